@@ -0,0 +1,169 @@
+//! Benchmarks for the hot paths of resolution: parsing versions and
+//! requirements, comparing versions, and solving dependency graphs.
+//!
+//! Run with `cargo bench`; compare against a baseline with
+//! `cargo bench -- --save-baseline main` and `--baseline main`.
+
+use std::{fs, hint::black_box, path::Path};
+
+use bundle::{RichReq, RubyVersion, parse_req};
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use pubgrub::{OfflineDependencyProvider, resolve};
+
+type Provider = OfflineDependencyProvider<String, RichReq>;
+
+// 1.2.3、4.0.0.rc1 のような、実際に見かけるバージョンを n 個
+fn version_strings(n: usize) -> Vec<String> {
+    (0..n)
+        .map(|i| match i % 10 {
+            0 => format!("{}.{}.{}.rc{}", i % 9, i % 31, i % 17, i % 3 + 1),
+            1 => format!("{}.{}.{}.{}", i % 9, i % 31, i % 17, i % 5),
+            2 => format!("{}.{}", i % 9, i % 31),
+            _ => format!("{}.{}.{}", i % 9, i % 31, i % 97),
+        })
+        .collect()
+}
+
+fn requirement_strings(n: usize) -> Vec<String> {
+    (0..n)
+        .map(|i| match i % 4 {
+            0 => format!("~> {}.{}", i % 9, i % 31),
+            1 => format!(">= {}.{}&< {}", i % 9, i % 31, i % 9 + 1),
+            2 => format!("= {}.{}.{}", i % 9, i % 31, i % 17),
+            _ => format!("!= {}.0&>= 0", i % 9),
+        })
+        .collect()
+}
+
+fn constraint(name: &str, requirement: &str) -> (String, RichReq) {
+    let (req, _) = parse_req(requirement, "&").expect("fixture requirements are valid");
+    (name.to_string(), req)
+}
+
+fn root() -> (String, RubyVersion) {
+    ("root".to_string(), RubyVersion::new(0, 0, 0))
+}
+
+/// The grpc gems from `tests/fixtures`, as the compact index serves them.
+fn fixture_provider() -> Provider {
+    let mut provider = Provider::new();
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/grpc/info");
+    for entry in fs::read_dir(dir).expect("the fixture exists") {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let info = fs::read_to_string(&path).unwrap();
+        for line in info.lines().skip(1) {
+            let (line, _) = line.split_once('|').unwrap_or((line, ""));
+            let (version, dependencies) = line.split_once(' ').unwrap_or((line, ""));
+            let version = RubyVersion::parse(version);
+            if version.is_platform() {
+                continue;
+            }
+            let dependencies = dependencies
+                .split(',')
+                .filter_map(|dependency| dependency.split_once(':'))
+                .map(|(dependency, requirement)| constraint(dependency, requirement));
+            provider.add_dependencies(name.clone(), version, dependencies);
+        }
+    }
+    let (root, root_version) = root();
+    provider.add_dependencies(
+        root,
+        root_version,
+        [
+            constraint("grpc", "~> 1.62"),
+            constraint("grpc-google-iam-v1", ">= 0"),
+        ],
+    );
+    provider
+}
+
+/// `gems` gems with 4 majors of 10 minors each. Every version of gem i needs
+/// up to three later gems at or above its own major, and the root caps the
+/// last gem below 3.0, so the solver has to walk most majors back.
+fn synthetic_provider(gems: usize) -> Provider {
+    let mut provider = Provider::new();
+    for i in 0..gems {
+        for major in 1..=4 {
+            for minor in 0..10 {
+                let dependencies = (0..3)
+                    .map(|k| i + 1 + (i * 7 + k * 13) % 20)
+                    .filter(|&j| j < gems)
+                    .map(|j| constraint(&format!("gem{}", j), &format!(">= {}.0", major)));
+                provider.add_dependencies(
+                    format!("gem{}", i),
+                    RubyVersion::new(major, minor, 0),
+                    dependencies,
+                );
+            }
+        }
+    }
+    let (root, root_version) = root();
+    provider.add_dependencies(
+        root,
+        root_version,
+        [
+            constraint("gem0", ">= 0"),
+            constraint(&format!("gem{}", gems - 1), "< 3"),
+        ],
+    );
+    provider
+}
+
+fn parsing(c: &mut Criterion) {
+    let versions = version_strings(10_000);
+    c.bench_function("parse 10k versions", |b| {
+        b.iter(|| {
+            versions
+                .iter()
+                .map(|v| RubyVersion::parse(black_box(v)))
+                .collect::<Vec<_>>()
+        })
+    });
+
+    let requirements = requirement_strings(10_000);
+    c.bench_function("parse 10k requirements", |b| {
+        b.iter(|| {
+            requirements
+                .iter()
+                .map(|r| parse_req(black_box(r), "&"))
+                .collect::<Vec<_>>()
+        })
+    });
+}
+
+fn comparing(c: &mut Criterion) {
+    let versions: Vec<RubyVersion> = version_strings(10_000)
+        .iter()
+        .map(|v| RubyVersion::parse(v))
+        .collect();
+    c.bench_function("sort 10k versions", |b| {
+        b.iter_batched(
+            || versions.clone(),
+            |mut versions| versions.sort(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn resolving(c: &mut Criterion) {
+    let solve = |provider: &Provider| {
+        let (root, root_version) = root();
+        resolve(provider, root, root_version).expect("the benchmark graphs resolve")
+    };
+
+    let fixture = fixture_provider();
+    c.bench_function("resolve grpc fixture", |b| b.iter(|| solve(&fixture)));
+
+    // 1 回に 0.2 秒ほどかかるので、標本を減らす
+    let synthetic = synthetic_provider(100);
+    let mut group = c.benchmark_group("slow");
+    group.sample_size(10);
+    group.bench_function("resolve 100 synthetic gems", |b| {
+        b.iter(|| solve(&synthetic))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parsing, comparing, resolving);
+criterion_main!(benches);
@@ -2,8 +2,30 @@
 require "bundler"
 require "json"
 
+# eval_gemfile で読み込み中のファイル (自分自身も含む) をもう一度読むと、
+# 再帰が止まらないので読み飛ばす。別々のファイルから同じファイルを読むのはかまわない
+module SkipRecursiveEvalGemfile
+  def eval_gemfile(gemfile, contents = nil)
+    path = Pathname.new(gemfile).expand_path(@gemfile&.parent)
+    @evaluating ||= []
+    if @evaluating.include?(path)
+      warn "Skipping eval_gemfile #{gemfile}: #{path} is already being evaluated"
+      return
+    end
+
+    @evaluating.push(path)
+    begin
+      super
+    ensure
+      @evaluating.pop
+    end
+  end
+end
+Bundler::Dsl.prepend(SkipRecursiveEvalGemfile)
+
 dsl = Bundler::Dsl.new
-dsl.eval_gemfile("Gemfile")
+# ruby eval_gemfile.rb [GEMFILE]。bundle は ruby -e で実行時に評価する
+dsl.eval_gemfile(ARGV.fetch(0, "Gemfile"))
 
 dependencies = dsl.dependencies.map do |dep|
   {
@@ -13,8 +35,23 @@ dependencies = dsl.dependencies.map do |dep|
     source: dep.source ? { type: dep.source.class.name, details: dep.source.to_s } : nil,
     git: dep.git,
     platforms: dep.platforms,
+    should_include: dep.instance_variable_get(:@should_include),
+    autorequire: dep.autorequire,
+    path: dep.source.is_a?(Bundler::Source::Path) && !dep.source.is_a?(Bundler::Source::Git) ? dep.source.options["path"] : nil,
     branch: dep.branch,
+    tag: dep.source.is_a?(Bundler::Source::Git) ? dep.source.options["tag"] : nil,
+    ref: dep.source.is_a?(Bundler::Source::Git) ? dep.source.options["ref"] : nil,
   }
 end
 
-puts JSON.pretty_generate({ dependencies: dependencies })
+ruby_version = dsl.instance_variable_get(:@ruby_version)
+ruby = ruby_version && {
+  versions: ruby_version.versions,
+  engine: ruby_version.engine,
+  engine_versions: ruby_version.engine_versions,
+}
+
+# Bundler prepends each new remote, so reverse to get declaration order.
+sources = dsl.instance_variable_get(:@sources).global_rubygems_source.remotes.reverse.map(&:to_s)
+
+puts JSON.pretty_generate({ dependencies: dependencies, ruby: ruby, sources: sources })
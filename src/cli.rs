@@ -1,3 +1,36 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tracing::Level;
+
+use crate::http::{HttpOptions, HttpVersion};
+
+/// The values of `--http-version`, see [`HttpVersion`].
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+enum HttpVersionArg {
+    /// HTTP/2 when the server offers it in the TLS handshake, HTTP/1.1 otherwise
+    #[default]
+    Auto,
+    /// Only HTTP/1.1, for servers that misbehave with HTTP/2
+    #[value(name = "1.1")]
+    Http1,
+    /// HTTP/2 without negotiating it, e.g. for a cleartext mirror known to speak it
+    #[value(name = "2")]
+    Http2,
+}
+
+impl From<HttpVersionArg> for HttpVersion {
+    fn from(arg: HttpVersionArg) -> Self {
+        match arg {
+            HttpVersionArg::Auto => HttpVersion::Auto,
+            HttpVersionArg::Http1 => HttpVersion::Http1,
+            HttpVersionArg::Http2 => HttpVersion::Http2,
+        }
+    }
+}
+
 #[derive(clap::Parser)]
 #[command(
     name = "Bundler",
@@ -9,20 +42,322 @@
 pub struct Cli {
     #[command(subcommand)]
     command: Option<Command>,
+    /// Seconds to wait for a connection or for more data from a network request before giving up
+    #[arg(long, global = true, default_value_t = 30)]
+    timeout: u64,
+    /// Retry failed network requests this many times
+    #[arg(long = "retry", global = true, default_value_t = 3, value_name = "N")]
+    retry: usize,
+    /// Show more log output (-v info, -vv debug, -vvv trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Only log warnings and errors, and print no status lines
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Never touch the network: resolve with the cached gem index only, and install only
+    /// gems found in vendor/cache or the gem cache
+    #[arg(long, global = true)]
+    local: bool,
+    /// Use a cached gem index younger than this many seconds without revalidating it
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        conflicts_with = "no_cache"
+    )]
+    index_max_age: Option<u64>,
+    /// Send every request through this proxy instead of HTTP_PROXY/HTTPS_PROXY
+    #[arg(long, global = true, value_name = "URL")]
+    proxy: Option<String>,
+    /// Lock for this platform (e.g. x86_64-linux) instead of ruby; repeat for several
+    #[arg(long = "platform", global = true, value_name = "PLATFORM")]
+    platforms: Vec<String>,
+    /// Fail instead of warning when the running Ruby does not match the Gemfile's ruby version
+    #[arg(long, global = true)]
+    strict_ruby: bool,
+    /// Consider only the newest N versions of each gem, to keep huge indexes fast
+    #[arg(long, global = true, value_name = "N")]
+    max_versions: Option<usize>,
+    /// Fetch at most N gems from each source at a time; 1 fetches them one by one in a
+    /// fixed order, e.g. to reproduce a failure. Defaults to BUNDLE_JOBS or the CPU count
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    jobs: Option<u64>,
+    /// Download the gem index in full instead of revalidating or reusing cached parts of it
+    #[arg(long, global = true, conflicts_with = "local")]
+    no_cache: bool,
+    /// HTTP version to use with gem sources
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        value_name = "VERSION"
+    )]
+    http_version: HttpVersionArg,
+    /// Close connections that have been idle for this many seconds instead of reusing them
+    #[arg(long, global = true, default_value_t = 90, value_name = "SECONDS")]
+    pool_idle_timeout: u64,
+    /// On failure, print every underlying cause and a backtrace, e.g. for a bug report
+    #[arg(long, global = true)]
+    trace: bool,
+    /// Read and write the lockfile at this path instead of Gemfile.lock next to the Gemfile
+    #[arg(
+        long,
+        global = true,
+        default_value = "Gemfile.lock",
+        value_name = "PATH"
+    )]
+    lockfile: PathBuf,
 }
 
 impl Cli {
     pub fn command(&self) -> Option<&Command> {
         self.command.as_ref()
     }
+
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub fn local(&self) -> bool {
+        self.local
+    }
+
+    pub fn strict_ruby(&self) -> bool {
+        self.strict_ruby
+    }
+
+    pub fn max_versions(&self) -> Option<usize> {
+        self.max_versions
+    }
+
+    pub fn jobs(&self) -> Option<usize> {
+        self.jobs.map(|jobs| jobs as usize)
+    }
+
+    pub fn no_cache(&self) -> bool {
+        self.no_cache
+    }
+
+    pub fn trace(&self) -> bool {
+        self.trace
+    }
+
+    pub fn lockfile(&self) -> &Path {
+        &self.lockfile
+    }
+
+    /// The log level used when `RUST_LOG` is not set. Warnings are shown by default.
+    pub fn log_level(&self) -> Level {
+        if self.quiet {
+            return Level::WARN;
+        }
+        match self.verbose {
+            0 => Level::WARN,
+            1 => Level::INFO,
+            2 => Level::DEBUG,
+            _ => Level::TRACE,
+        }
+    }
+
+    /// How long a cached `/versions` file is trusted without asking the server.
+    pub fn fresh_within(&self) -> Option<Duration> {
+        self.index_max_age.map(Duration::from_secs)
+    }
+
+    /// The platforms listed under `PLATFORMS` in the lockfile, sorted and
+    /// deduplicated. `ruby` when no `--platform` is given.
+    pub fn platforms(&self) -> Vec<String> {
+        if self.platforms.is_empty() {
+            return vec!["ruby".to_string()];
+        }
+        let platforms: std::collections::BTreeSet<_> = self.platforms.iter().cloned().collect();
+        platforms.into_iter().collect()
+    }
+
+    pub fn http_options(&self) -> HttpOptions {
+        HttpOptions {
+            connect_timeout: Duration::from_secs(self.timeout),
+            timeout: Duration::from_secs(self.timeout),
+            proxy: self.proxy.clone(),
+            retries: self.retry,
+            http_version: self.http_version.into(),
+            pool_idle_timeout: Duration::from_secs(self.pool_idle_timeout),
+            ..HttpOptions::default()
+        }
+    }
 }
 
 #[derive(clap::Subcommand)]
 pub enum Command {
-    Install,
+    Install {
+        /// Print what would be downloaded and built without touching the filesystem
+        #[arg(long)]
+        dry_run: bool,
+        /// Install gems under this directory instead of the system gem home; remembered in .bundle/config
+        #[arg(long)]
+        path: Option<PathBuf>,
+        /// Fail when the Gemfile no longer matches Gemfile.lock, or a resolved version is no longer listed by its source
+        #[arg(long)]
+        frozen: bool,
+        /// Install only ungrouped gems and those in these groups; the lockfile still lists every gem
+        #[arg(long, value_delimiter = ',', value_name = "GROUPS")]
+        only: Vec<String>,
+        /// Allow prerelease versions of every gem, unless a requirement pins a release
+        #[arg(long)]
+        pre: bool,
+        /// Also write bundler/setup.rb, which puts the gems on the load path without Bundler;
+        /// installs into ./bundle unless a path is set
+        #[arg(long)]
+        standalone: bool,
+        /// Keep installing the other gems when one fails, and list the failures at the end
+        #[arg(long)]
+        continue_on_error: bool,
+    },
     #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
     Exec {
         args: Vec<String>,
     },
-    Lock,
+    Lock {
+        /// Write the lockfile to stdout instead of to the lockfile path
+        #[arg(long)]
+        print: bool,
+        /// Also print a SHA-256 of the lockfile content, e.g. for a CI cache key
+        #[arg(long)]
+        hash: bool,
+        /// Allow prerelease versions of every gem, unless a requirement pins a release
+        #[arg(long)]
+        pre: bool,
+    },
+    /// List gems with newer versions available
+    Outdated,
+    /// Remove installed and cached gems that are not in Gemfile.lock
+    Clean {
+        /// Only list the gems that would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// Clean even when no bundle path is set, removing system gems not in the bundle
+        #[arg(long)]
+        force: bool,
+    },
+    /// Manage the cached gem index
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// List the versions of a gem that the sources offer
+    Versions {
+        gem: String,
+        /// Only list versions meeting this requirement, e.g. "~> 7.0" or "< 6 || >= 7.1"
+        #[arg(long)]
+        requirement: Option<String>,
+    },
+    /// Show the dependency chains that bring a gem into the bundle
+    Why { gem: String },
+    /// List settings, show one (`config path`), or change one (`config path vendor/bundle`)
+    Config {
+        key: Option<String>,
+        value: Option<String>,
+    },
+}
+
+#[derive(clap::Subcommand)]
+pub enum CacheCommand {
+    /// Remove the cached index entries of gems that Gemfile.lock does not need
+    Prune {
+        /// Only list the entries that would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use clap::Parser as _;
+    use tracing::Level;
+
+    use crate::cli::Cli;
+
+    #[test]
+    fn test_log_level() {
+        let level = |args: &[&str]| Cli::try_parse_from(args).unwrap().log_level();
+        assert_eq!(level(&["bundle", "lock"]), Level::WARN);
+        assert_eq!(level(&["bundle", "-v", "lock"]), Level::INFO);
+        assert_eq!(level(&["bundle", "lock", "-vv"]), Level::DEBUG);
+        assert_eq!(level(&["bundle", "-vvvv", "lock"]), Level::TRACE);
+        assert_eq!(level(&["bundle", "-q", "lock"]), Level::WARN);
+        assert!(Cli::try_parse_from(["bundle", "-q", "-v", "lock"]).is_err());
+        assert!(
+            Cli::try_parse_from(["bundle", "install", "--quiet"])
+                .unwrap()
+                .quiet()
+        );
+        assert!(!Cli::try_parse_from(["bundle", "install"]).unwrap().quiet());
+    }
+
+    #[test]
+    fn test_platforms() {
+        let platforms = |args: &[&str]| Cli::try_parse_from(args).unwrap().platforms();
+        assert_eq!(platforms(&["bundle", "lock"]), vec!["ruby"]);
+        assert_eq!(
+            platforms(&[
+                "bundle",
+                "lock",
+                "--platform",
+                "x86_64-linux",
+                "--platform",
+                "arm64-darwin",
+                "--platform",
+                "x86_64-linux",
+            ]),
+            vec!["arm64-darwin", "x86_64-linux"]
+        );
+    }
+
+    #[test]
+    fn test_lockfile() {
+        let lockfile = |args: &[&str]| Cli::try_parse_from(args).unwrap().lockfile().to_path_buf();
+        assert_eq!(lockfile(&["bundle", "lock"]), Path::new("Gemfile.lock"));
+        assert_eq!(
+            lockfile(&["bundle", "lock", "--lockfile", "locks/Gemfile.ci.lock"]),
+            Path::new("locks/Gemfile.ci.lock")
+        );
+        assert_eq!(
+            lockfile(&["bundle", "--lockfile", "/tmp/Gemfile.lock", "install"]),
+            Path::new("/tmp/Gemfile.lock")
+        );
+    }
+
+    #[test]
+    fn test_jobs() {
+        let jobs = |args: &[&str]| Cli::try_parse_from(args).map(|cli| cli.jobs());
+        assert_eq!(jobs(&["bundle", "install"]).unwrap(), None);
+        assert_eq!(
+            jobs(&["bundle", "install", "--jobs", "1"]).unwrap(),
+            Some(1)
+        );
+        assert!(jobs(&["bundle", "install", "--jobs", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_fresh_within() {
+        let cli = |args: &[&str]| Cli::try_parse_from(args).unwrap();
+        // --local はキャッシュの古さに関係なくネットワークを使わないので、TTL とは別
+        assert_eq!(cli(&["bundle", "install", "--local"]).fresh_within(), None);
+        assert_eq!(
+            cli(&["bundle", "install", "--index-max-age", "3600"]).fresh_within(),
+            Some(Duration::from_secs(3600))
+        );
+        assert!(
+            Cli::try_parse_from(["bundle", "install", "--index-max-age", "60", "--no-cache"])
+                .is_err()
+        );
+    }
 }
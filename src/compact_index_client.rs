@@ -1,24 +1,26 @@
-use futures::stream::FuturesUnordered;
+use base64::Engine;
 use futures::{Stream, StreamExt};
 use lazy_static::lazy_static;
 use md5::{Digest as Md5Digest, Md5};
 use pubgrub::Ranges;
 use regex::Regex;
-use reqwest::header::{ETAG, HeaderMap, HeaderValue, IF_NONE_MATCH, RANGE};
+use reqwest::header::{ETAG, HeaderMap, HeaderValue, IF_NONE_MATCH, LOCATION, RANGE, RETRY_AFTER};
 use reqwest::{Client, Response};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest as Sha2Digest, Sha256};
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::io::{self, BufRead, Cursor, Read, SeekFrom, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::fs::{self, File};
 use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, BufWriter};
-use tokio::sync::{OwnedSemaphorePermit, Semaphore};
-use tokio::task::JoinHandle;
-use tracing::{Level, debug, instrument};
+use tokio::sync::{OnceCell, Semaphore};
+use tracing::{Level, debug, instrument, warn};
 use url::Url;
 
+use crate::http::{HttpOptions, MAX_RETRY_AFTER, backoff, is_retryable};
 use crate::version::{RichReq, RubyVersion, Segment, parse_req};
 
 #[derive(Error, Debug)]
@@ -32,6 +34,15 @@ pub enum CompactIndexError {
     #[error("Checksum mismatch: expected {expected}, got {actual}")]
     ChecksumMismatch { expected: String, actual: String },
 
+    #[error("Not found: {0}")]
+    NotFound(Url),
+
+    #[error("Could not find gem '{0}' in any of the sources")]
+    GemNotFound(String),
+
+    #[error("{0} is not cached; run without --local to download it")]
+    NotCached(Url),
+
     #[error("URL parsing error: {0}")]
     UrlParse(#[from] url::ParseError),
 
@@ -41,36 +52,80 @@ pub enum CompactIndexError {
 
 pub type Result<T> = std::result::Result<T, CompactIndexError>;
 
-#[derive(Debug, Clone)]
+/// How many redirects one request follows before giving up, as reqwest does by default.
+const MAX_REDIRECTS: usize = 10;
+
+/// The body of an info file that lists no versions.
+const EMPTY_INFO: &[u8] = b"---";
+
+/// One line of a gem's info file: a version of the gem, what it depends on,
+/// and the metadata after the `|`, e.g.
+/// `3.0.9 webrick:>= 1.8|checksum:6e0c...,ruby:>= 2.4`.
+///
+/// Fields are only ever added, so code reading them keeps working.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GemVersion {
     pub name: String,
     pub version: RubyVersion,
+    /// The SHA-256 of the `.gem` file as hex, from `checksum:`.
     pub checksum: Option<String>,
+    /// The runtime dependencies, in the order the info line lists them.
     pub dependencies: Vec<GemDependency>,
+    /// The `ruby:` requirement after the `|` of the info line, if any.
+    pub required_ruby_version: Option<RichReq>,
 }
 
-#[derive(Debug, Clone)]
+/// A runtime dependency of a [`GemVersion`], e.g. `webrick:>= 1.8`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GemDependency {
     pub name: String,
+    /// The versions of `name` the requirement allows.
     pub requirement: RichReq,
+    /// The comparators as written, e.g. `["~> 2.0", ">= 2.2.4"]`.
     pub requirement_str: Vec<String>,
 }
 
+/// Bumped whenever the layout of [`ParsedInfo`] changes; older files are parsed again.
+const PARSED_INFO_FORMAT: u32 = 5;
+
+/// The parsed versions of an info file, stored in `info-parsed/` so that a
+/// run whose info file has not changed skips parsing it.
+#[derive(Serialize, Deserialize)]
+struct ParsedInfo {
+    format: u32,
+    /// The ETag of the info file these versions were parsed from.
+    etag: String,
+    versions: Vec<GemVersion>,
+}
+
 #[derive(Debug, Clone)]
 pub struct CompactIndexClient {
     base_url: Url,
+    // base_url が恒久的にリダイレクトされた先。このプロセスの間はこちらに直接リクエストする
+    redirected_url: Arc<RwLock<Option<Url>>>,
     cache_dir: PathBuf,
     http_client: Client,
+    // /versions に載っている info ファイルごとの MD5
+    info_checksums: Arc<OnceCell<HashMap<String, String>>>,
+    // MD5 が合わない info があったときに /versions を取り直して読んだもの。取り直すのは 1 回だけ
+    rechecked_checksums: Arc<OnceCell<HashMap<String, String>>>,
+    // /versions を取り直すのは 1 プロセスで 1 回だけ
+    versions_refreshed: Arc<OnceCell<()>>,
+    fresh_within: Option<Duration>,
+    no_cache: bool,
+    // --local のときはキャッシュだけを読み、リクエストは一切しない
+    offline: bool,
+    retries: usize,
+    // 同時に取りに行く info ファイルの数
     limiter: Arc<Semaphore>,
 }
 
 pub enum InfoSource {
-    File(File),                    // append された既存 or partial
-    Mem(std::io::Cursor<Vec<u8>>), // fresh full body
+    File(File), // 書き込み直後のキャッシュを先頭から読む
 }
 
 impl CompactIndexClient {
-    pub async fn new(base_url: &str, bundle_dir: &Path) -> Result<Self> {
+    pub async fn new(base_url: &str, bundle_dir: &Path, options: HttpOptions) -> Result<Self> {
         let url = Url::parse(base_url)?;
 
         let cache_slug = Self::cache_slug_for_url(&url)?;
@@ -82,12 +137,26 @@ impl CompactIndexClient {
         fs::create_dir_all(&cache_dir).await?;
         fs::create_dir_all(&cache_dir.join("info")).await?;
         fs::create_dir_all(&cache_dir.join("info-etags")).await?;
+        fs::create_dir_all(&cache_dir.join("info-parsed")).await?;
 
         Ok(Self {
             base_url: url,
+            redirected_url: Arc::new(RwLock::new(None)),
             cache_dir,
-            http_client: Client::builder().pool_max_idle_per_host(20).build()?,
-            limiter: Arc::new(Semaphore::new(num_cpus::get())),
+            // リダイレクトは update_cache で自分でたどり、恒久的な移転を覚えておく
+            http_client: options
+                .client_builder()?
+                .pool_max_idle_per_host(20)
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?,
+            info_checksums: Arc::new(OnceCell::new()),
+            rechecked_checksums: Arc::new(OnceCell::new()),
+            versions_refreshed: Arc::new(OnceCell::new()),
+            fresh_within: None,
+            no_cache: false,
+            offline: false,
+            retries: options.retries,
+            limiter: Arc::new(Semaphore::new(options.jobs.max(1))),
         })
     }
 
@@ -121,103 +190,101 @@ impl CompactIndexClient {
         Ok(slug)
     }
 
-    #[instrument(level = Level::INFO, skip_all)]
-    pub async fn resolve_dependencies(
-        &self,
-        root_gems: Vec<String>,
-    ) -> Result<HashMap<String, Vec<GemVersion>>> {
-        use futures::stream::StreamExt;
-        // Ensure we have a fresh `/versions` file – *serial* (only once).
-        self.ensure_versions_fresh().await?;
+    /// The URL requests are sent under: the base URL, or where it permanently
+    /// redirected to earlier in this process.
+    fn effective_base_url(&self) -> Url {
+        let redirected = self
+            .redirected_url
+            .read()
+            .unwrap_or_else(|err| err.into_inner());
+        redirected.clone().unwrap_or_else(|| self.base_url.clone())
+    }
 
-        // Work‑list algorithm, but each round is processed in parallel.
-        let mut graph: HashMap<String, Vec<GemVersion>> = HashMap::new();
-        let mut visited: HashSet<String> = HashSet::new();
-        let mut queue: VecDeque<String> = root_gems.iter().cloned().collect();
-        let mut tasks: FuturesUnordered<JoinHandle<Result<(String, Vec<GemVersion>)>>> =
-            FuturesUnordered::new();
-        // Shared Arc for all spawned tasks
-        let shared_client = Arc::new(self.clone());
-
-        // Function to spawn a fetch task; ONLY place where spawn happens
-        let spawn_fetch = |client: Arc<CompactIndexClient>,
-                           gem: String,
-                           permit: OwnedSemaphorePermit|
-         -> JoinHandle<_> {
-            tokio::spawn(async move {
-                let versions = client.info(&gem).await?;
-                drop(permit);
-                Ok::<_, CompactIndexError>((gem, versions))
-            })
-        };
+    /// `info/<gem>` under the base URL, with the gem name percent-encoded.
+    fn info_url(&self, gem_name: &str) -> Result<Url> {
+        let mut url = self.effective_base_url();
+        url.path_segments_mut()
+            .map_err(|_| {
+                CompactIndexError::Other(format!("{} cannot be a base URL", self.base_url))
+            })?
+            .pop_if_empty()
+            .push("info")
+            .push(gem_name);
+        Ok(url)
+    }
 
-        let sem = Arc::clone(&self.limiter);
+    /// Like Bundler, names that are not plain lowercase get a digest suffix so
+    /// they neither collide on case-insensitive filesystems nor escape the cache.
+    fn info_file_name(gem_name: &str) -> String {
+        lazy_static! {
+            static ref PLAIN_NAME: Regex = Regex::new(r"^[a-z0-9_-]+$").unwrap();
+            static ref UNSAFE_CHARS: Regex = Regex::new(r"[^A-Za-z0-9_-]").unwrap();
+        }
+        if PLAIN_NAME.is_match(gem_name) {
+            return gem_name.to_string();
+        }
+        let digest = format!("{:x}", Md5::digest(gem_name.as_bytes()));
+        format!("{}-{}", UNSAFE_CHARS.replace_all(gem_name, "-"), digest)
+    }
 
-        // initial fill (schedule unique downloads)
-        let mut scheduled = HashSet::<String>::new();
-        while let Some(name) = queue.pop_front() {
-            let permit = match sem.clone().try_acquire_owned() {
-                Ok(p) => p,
-                Err(_) => {
-                    queue.push_front(name);
-                    continue;
-                }
-            };
+    /// Bounds how many gems are fetched from this source at a time, to
+    /// [`HttpOptions::jobs`].
+    pub fn limiter(&self) -> &Arc<Semaphore> {
+        &self.limiter
+    }
 
-            if !visited.contains(&name) && !scheduled.contains(&name) {
-                scheduled.insert(name.clone());
-                tasks.push(spawn_fetch(Arc::clone(&shared_client), name, permit));
-            }
-            if tasks.len() >= shared_client.limiter.available_permits() {
-                break;
-            }
-        }
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
 
-        // main loop
-        while let Some(out) = tasks.next().await {
-            let (gem, versions) = out.unwrap().unwrap();
-            if visited.insert(gem.clone()) {
-                graph.insert(gem, versions.clone());
-            }
-            for v in &versions {
-                for d in &v.dependencies {
-                    if !visited.contains(&d.name) {
-                        queue.push_back(d.name.clone());
-                    }
-                }
-            }
+    /// Trusts a cached `/versions` file modified less than `ttl` ago instead of
+    /// revalidating it with the server.
+    pub fn set_fresh_within(&mut self, ttl: Option<Duration>) {
+        self.fresh_within = ttl;
+    }
 
-            // refill window
-            while let Some(n) = queue.pop_front() {
-                if !visited.contains(&n) && !scheduled.contains(&n) {
-                    let permit = match sem.clone().try_acquire_owned() {
-                        Ok(p) => p,
-                        Err(_) => {
-                            queue.push_front(n);
-                            break;
-                        }
-                    };
-                    scheduled.insert(n.clone());
-                    tasks.push(spawn_fetch(Arc::clone(&shared_client), n, permit));
-                }
-            }
-        }
-        Ok(graph)
+    /// Answers only from the cache and never sends a request. Gems whose info
+    /// file was never cached are not served.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    /// Downloads `/versions` and every info file in full, ignoring the ETags,
+    /// the cached bytes and the parsed info cache, and overwrites the cache.
+    pub fn set_no_cache(&mut self, no_cache: bool) {
+        self.no_cache = no_cache;
     }
 
-    async fn ensure_versions_fresh(&self) -> Result<()> {
-        let url = self.base_url.join("versions")?;
+    /// Revalidates the cached `/versions` file, at most once per client and
+    /// its clones.
+    pub async fn ensure_versions_fresh(&self) -> Result<()> {
+        if self.versions_refreshed.initialized() {
+            return Ok(());
+        }
+        let url = self.effective_base_url().join("versions")?;
         let path = self.cache_dir.join("versions");
-        self.update_cache(&url, &path, &path).await?;
+        if self.offline {
+            if !path.exists() {
+                return Err(CompactIndexError::NotCached(url));
+            }
+            return Ok(());
+        }
+        if let Some(ttl) = self.fresh_within
+            && let Ok(modified) = fs::metadata(&path).await.and_then(|m| m.modified())
+            && modified.elapsed().is_ok_and(|age| age < ttl)
+        {
+            debug!("Using cached {} without revalidating", url);
+            return Ok(());
+        }
+        self.versions_refreshed
+            .get_or_try_init(|| async { self.update_cache(&url, &path, &path).await.map(drop) })
+            .await?;
         Ok(())
     }
 
     pub async fn versions(&self, gems: Vec<String>) -> Result<HashMap<String, Vec<RubyVersion>>> {
         let versions_path = self.cache_dir.join("versions");
-        let versions_url = self.base_url.join("versions")?;
-
-        self.update_cache(&versions_url, &versions_path, &versions_path)
-            .await?;
+        self.ensure_versions_fresh().await?;
 
         // use futures::{StreamExt, TryStreamExt};
         use tokio_stream::wrappers::LinesStream;
@@ -225,23 +292,66 @@ impl CompactIndexClient {
         let mut content_lines =
             LinesStream::new(BufReader::new(File::open(versions_path).await?).lines())
                 .filter_map(|r| futures::future::ready(r.ok()))
-                .skip_while(|line| futures::future::ready(line != "---"))
+                .skip_while(|line| futures::future::ready(!is_header(line)))
                 .skip(1);
         let result = parse_version(&mut content_lines, gems).await?;
         Ok(result)
     }
 
+    /// Every version of `gem_name` the source serves, in the order of its info
+    /// file (oldest first), or nothing if the source does not serve the gem.
+    /// The info file is downloaded, or brought up to date, and cached.
+    ///
+    /// ```no_run
+    /// # use std::path::Path;
+    /// # use bundle::{HttpOptions, compact_index_client::CompactIndexClient};
+    /// # async fn run() -> bundle::compact_index_client::Result<()> {
+    /// let client = CompactIndexClient::new(
+    ///     "https://rubygems.org/",
+    ///     Path::new("/tmp/bundle-cache"),
+    ///     HttpOptions::default(),
+    /// )
+    /// .await?;
+    /// let newest = client.info("rack").await?.pop().expect("rack has versions");
+    /// for dependency in &newest.dependencies {
+    ///     println!("{} {}", dependency.name, dependency.requirement_str.join(", "));
+    /// }
+    /// println!("checksum: {:?}", newest.checksum);
+    /// # Ok(())
+    /// # }
+    /// ```
     #[instrument(level = Level::DEBUG, skip_all)]
     pub async fn info(&self, gem_name: &str) -> Result<Vec<GemVersion>> {
-        let info_path = self.cache_dir.join("info").join(gem_name);
-        let info_etag_path = self.cache_dir.join("info-etags").join(gem_name);
-        let info_url = self.base_url.join(&format!("info/{}", gem_name))?;
+        let file_name = Self::info_file_name(gem_name);
+        let info_path = self.cache_dir.join("info").join(&file_name);
+        let info_etag_path = self.cache_dir.join("info-etags").join(&file_name);
+        let info_url = self.info_url(gem_name)?;
 
         // TODO: It's possible to return bytes or File from this function and reuse it in `CompactIndexClient::info`.
         // It can reduce overlapped I/O.
-        let file = self
-            .update_cache(&info_url, &info_path, &info_etag_path)
-            .await?;
+        let file = if self.offline {
+            // キャッシュにない gem は、このソースにはないものとして扱う
+            match File::open(&info_path).await {
+                Ok(file) => Some(InfoSource::File(file)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+                Err(err) => return Err(err.into()),
+            }
+        } else {
+            match self
+                .update_cache(&info_url, &info_path, &info_etag_path)
+                .await
+            {
+                Ok(file) => file,
+                // The gem is not hosted by this source.
+                Err(CompactIndexError::NotFound(_)) => return Ok(vec![]),
+                Err(err) => return Err(err),
+            }
+        };
+
+        if file.is_some() && !self.offline {
+            self.verify_info_checksum(gem_name, &info_path, &info_etag_path)
+                .await?;
+        }
 
         // Check if the info file exists
         // info file is sometimes empty like https://rubygems.org/info/active_support.
@@ -251,6 +361,17 @@ impl CompactIndexClient {
             return Ok(vec![]);
         }
 
+        // ETag が変わっていなければ前回パースした結果をそのまま使う
+        let info_parsed_path = self.cache_dir.join("info-parsed").join(&file_name);
+        let etag = self.read_etag(&info_etag_path).await?;
+        if let Some(etag) = &etag
+            && !self.no_cache
+            && let Some(versions) = Self::read_parsed_info(&info_parsed_path, etag).await
+        {
+            debug!("Using parsed info cache for gem: {}", gem_name);
+            return Ok(versions);
+        }
+
         // This line would be unnecessary if update_cache returns a file or bytes, which info APi returns.
         // let Some(file) = file else {
         //     return Ok(vec![]);
@@ -261,56 +382,90 @@ impl CompactIndexClient {
 
         let file: Box<dyn AsyncBufRead + Unpin + Send> = match file {
             Some(InfoSource::File(f)) => Box::new(BufReader::new(f)),
-            Some(InfoSource::Mem(c)) => Box::new(BufReader::new(c)),
             None => {
                 return Ok(vec![]);
             }
         };
         let mut lines = file.lines();
 
+        // 先頭の --- だけを読み飛ばす。CRLF のミラーの \r や空行も無視する
+        let mut first = true;
         while let Some(raw) = lines.next_line().await? {
-            if raw.starts_with("---") {
+            let line = raw.trim_end();
+            if line.is_empty() || (std::mem::take(&mut first) && is_header(line)) {
                 continue;
             }
 
-            let line = raw.split('|').next().unwrap_or(&raw);
-
-            let mut parts = line.splitn(2, ' ');
-            let ver_str = parts.next().unwrap();
-            let deps_str = parts.next().unwrap_or("");
-            let rv = RubyVersion::parse(ver_str);
-
-            if rv.is_platform() {
-                continue;
+            if let Some(version) = parse_info_line(gem_name, line) {
+                result.push(version);
             }
+        }
+        if let Some(etag) = etag {
+            result = Self::write_parsed_info(&info_parsed_path, etag, result).await;
+        }
+        Ok(result)
+    }
 
-            let mut dependencies = Vec::new();
+    /// The versions of `gem` the source serves, oldest first, without their
+    /// dependencies. Empty if the source does not serve the gem.
+    ///
+    /// ```no_run
+    /// # use std::path::Path;
+    /// # use bundle::{HttpOptions, compact_index_client::CompactIndexClient};
+    /// use bundle::parse_req;
+    /// use pubgrub::VersionSet as _;
+    ///
+    /// # async fn run() -> bundle::compact_index_client::Result<()> {
+    /// let client = CompactIndexClient::new(
+    ///     "https://rubygems.org/",
+    ///     Path::new("/tmp/bundle-cache"),
+    ///     HttpOptions::default(),
+    /// )
+    /// .await?;
+    /// let (requirement, _) = parse_req("~> 3.0", ",").unwrap();
+    /// for version in client.versions_for("rack").await? {
+    ///     if requirement.contains(&version) {
+    ///         println!("rack {}", version);
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn versions_for(&self, gem: &str) -> Result<Vec<RubyVersion>> {
+        Ok(self
+            .info(gem)
+            .await?
+            .into_iter()
+            .map(|version| version.version)
+            .collect())
+    }
 
-            for dep_entry in deps_str.split(',') {
-                let dep_entry = dep_entry.trim();
-                if dep_entry.is_empty() {
-                    continue;
-                }
-                if let Some(idx) = dep_entry.find(':') {
-                    let name = dep_entry[..idx].to_string();
-
-                    let req_str = dep_entry[idx + 1..].trim();
-                    let (req, req_str) = parse_req(req_str, "&");
-                    dependencies.push(GemDependency {
-                        name: name.to_string(),
-                        requirement: req,
-                        requirement_str: req_str,
-                    });
+    async fn read_parsed_info(path: &Path, etag: &str) -> Option<Vec<GemVersion>> {
+        let bytes = fs::read(path).await.ok()?;
+        let parsed: ParsedInfo = bincode::deserialize(&bytes).ok()?;
+        (parsed.format == PARSED_INFO_FORMAT && parsed.etag == etag).then_some(parsed.versions)
+    }
+
+    // キャッシュは速くするためだけのものなので、書けなくてもエラーにしない
+    async fn write_parsed_info(
+        path: &Path,
+        etag: String,
+        versions: Vec<GemVersion>,
+    ) -> Vec<GemVersion> {
+        let parsed = ParsedInfo {
+            format: PARSED_INFO_FORMAT,
+            etag,
+            versions,
+        };
+        match bincode::serialize(&parsed) {
+            Ok(bytes) => {
+                if let Err(err) = fs::write(path, bytes).await {
+                    debug!("Could not write {}: {}", path.display(), err);
                 }
             }
-            result.push(GemVersion {
-                name: gem_name.to_string(),
-                version: rv,
-                checksum: None, // checksum is after the pipe; omitted here for brevity
-                dependencies,
-            });
+            Err(err) => debug!("Could not serialize {}: {}", path.display(), err),
         }
-        Ok(result)
+        parsed.versions
     }
 
     #[instrument(level = Level::DEBUG, skip_all)]
@@ -319,10 +474,34 @@ impl CompactIndexClient {
         url: &Url,
         cache_path: &Path,
         etag_path: &Path,
+    ) -> Result<Option<InfoSource>> {
+        if self.no_cache {
+            return self.download(url, cache_path, etag_path, true).await;
+        }
+        match self.download(url, cache_path, etag_path, false).await {
+            // 差分を足したものがサーバーの digest と合わないなら、キャッシュが壊れているので全体を取り直す
+            Err(CompactIndexError::ChecksumMismatch { .. }) if cache_path.exists() => {
+                warn!(
+                    "{} in the cache does not match the server; downloading it again",
+                    url
+                );
+                self.download(url, cache_path, etag_path, true).await
+            }
+            result => result,
+        }
+    }
+
+    // full のときは ETag も Range も送らずに全体を取る
+    async fn download(
+        &self,
+        url: &Url,
+        cache_path: &Path,
+        etag_path: &Path,
+        full: bool,
     ) -> Result<Option<InfoSource>> {
         let mut headers = HeaderMap::new();
 
-        if etag_path.exists() {
+        if !full && etag_path.exists() {
             if let Some(etag) = self.read_etag(etag_path).await? {
                 headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&etag).unwrap());
             }
@@ -335,71 +514,276 @@ impl CompactIndexClient {
             }
         }
 
-        let response = self
-            .http_client
-            .get(url.clone())
-            .headers(headers)
-            .send()
-            .await?;
+        let mut url = url.clone();
+        let mut redirects = 0;
+        let response = loop {
+            let response = self.send(&url, &headers).await?;
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok());
+            let (true, Some(location)) = (is_redirect(response.status()), location) else {
+                break response;
+            };
+            if redirects == MAX_REDIRECTS {
+                return Err(CompactIndexError::Other(format!(
+                    "Too many redirects for URL: {}",
+                    url
+                )));
+            }
+            let target = url.join(location)?;
+            if matches!(
+                response.status(),
+                reqwest::StatusCode::MOVED_PERMANENTLY | reqwest::StatusCode::PERMANENT_REDIRECT
+            ) {
+                self.remember_redirect(&url, &target);
+            }
+            debug!("{} redirected to {}", url, target);
+            url = target;
+            redirects += 1;
+        };
 
         if response.status() == reqwest::StatusCode::NOT_MODIFIED {
             return Ok(None);
         }
 
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(CompactIndexError::NotFound(url.clone()));
+        }
+
         if response.status().is_success() {
-            return Ok(self
-                .process_response(response, cache_path, etag_path)
-                .await?);
+            self.process_response(response, cache_path, etag_path).await
         } else {
-            return Err(CompactIndexError::Other(format!(
+            Err(CompactIndexError::Other(format!(
                 "HTTP error: {} for URL: {}",
                 response.status(),
                 url
-            )));
+            )))
+        }
+    }
+
+    // 失敗したリクエストを、429 や 5xx なら待ってから送り直す
+    async fn send(&self, url: &Url, headers: &HeaderMap) -> Result<Response> {
+        let mut retries = 0;
+        loop {
+            let result = self
+                .http_client
+                .get(url.clone())
+                .headers(headers.clone())
+                .send()
+                .await;
+            let delay = match &result {
+                Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    retry_after(response.headers())
+                        .unwrap_or(Duration::from_secs(1))
+                        .min(MAX_RETRY_AFTER)
+                }
+                Ok(response) if response.status().is_server_error() => backoff(retries),
+                Err(err) if is_retryable(err) => backoff(retries),
+                _ => return Ok(result?),
+            };
+            if retries == self.retries {
+                return Ok(result?);
+            }
+            retries += 1;
+            debug!("Request to {} failed, retrying in {:?}", url, delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Sends later requests straight to the new base URL when `from`, a URL
+    /// under the base, permanently moved to `to`, e.g. `/versions` of a mirror
+    /// that moved host. The lockfile and cache keep the configured URL.
+    fn remember_redirect(&self, from: &Url, to: &Url) {
+        let base = self.effective_base_url();
+        let Some(rest) = from.as_str().strip_prefix(base.as_str()) else {
+            return;
+        };
+        let Some(new_base) = to.as_str().strip_suffix(rest) else {
+            return;
+        };
+        if let Ok(new_base) = Url::parse(new_base) {
+            debug!("{} moved permanently to {}", base, new_base);
+            *self
+                .redirected_url
+                .write()
+                .unwrap_or_else(|err| err.into_inner()) = Some(new_base);
         }
     }
 
     async fn process_response(
         &self,
-        response: Response,
+        mut response: Response,
         cache_path: &Path,
         etag_path: &Path,
     ) -> Result<Option<InfoSource>> {
         let is_partial = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        // 本体を書き終えるまで ETag は保存しない。途中で止まったキャッシュを 304 で使い続けないように
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .map(|etag| etag.to_str().unwrap().to_string());
+        let digest = expected_sha256(response.headers());
+
+        use tokio::io::AsyncWriteExt;
 
-        if let Some(etag) = response.headers().get(ETAG) {
-            self.write_etag(etag_path, etag.to_str().unwrap()).await?;
+        // 空の info (--- だけ) かどうか分かるまで先頭だけ読んでおく
+        let mut head = Vec::new();
+        let mut finished = false;
+        while head.len() <= EMPTY_INFO.len() + 1 {
+            match response.chunk().await? {
+                Some(chunk) => head.extend_from_slice(&chunk),
+                None => {
+                    finished = true;
+                    break;
+                }
+            }
         }
 
-        let body = response.bytes().await?;
+        // 一時ファイルに書いてから置き換えるので、中断されても途中までのキャッシュは残らない
+        let dir = cache_path.parent().unwrap_or(Path::new("."));
+        let temp = tempfile::NamedTempFile::new_in(dir)?;
+        if is_partial && cache_path.exists() {
+            // 最初の 1 バイトはキャッシュの末尾と重なる
+            let skip = head.len().min(1);
+            head.drain(..skip);
+            fs::copy(cache_path, temp.path()).await?;
+        } else if finished && head.strip_suffix(b"\n").unwrap_or(&head) == EMPTY_INFO {
+            // If the body is empty, we don't create a new file.
+            if let Some(etag) = etag {
+                self.write_etag(etag_path, &etag).await?;
+            }
+            return Ok(None);
+        }
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(temp.path())
+            .await?;
 
-        use tokio::io::AsyncWriteExt;
+        let mut w = BufWriter::new(&mut file);
+        w.write_all(&head).await?;
+        while let Some(chunk) = response.chunk().await? {
+            w.write_all(&chunk).await?;
+        }
+        w.flush().await?;
+        drop(file);
+        // 差分のときも digest はファイル全体のもの
+        if let Some(expected) = digest {
+            let actual = base64::engine::general_purpose::STANDARD
+                .encode(Sha256::digest(fs::read(temp.path()).await?));
+            if actual != expected {
+                return Err(CompactIndexError::ChecksumMismatch { expected, actual });
+            }
+        }
+        temp.persist(cache_path).map_err(|err| err.error)?;
+        if let Some(etag) = etag {
+            self.write_etag(etag_path, &etag).await?;
+        }
 
-        let file = if is_partial && cache_path.exists() {
-            let mut file = fs::OpenOptions::new()
-                .append(true)
-                .read(true)
-                .open(cache_path)
-                .await?;
+        let file = InfoSource::File(File::open(cache_path).await?);
+        Ok(Some(file))
+    }
 
-            file.write_all(&body[1..]).await?;
-            InfoSource::File(file)
-        } else {
-            // If the body is empty, we don't create a new file.
-            if let Ok(text) = std::str::from_utf8(&body) {
-                let mut lines = text.lines();
-                if lines.next() == Some("---") && lines.next().is_none() {
-                    return Ok(None);
+    /// Removes the cached info files of gems not in `keep`, with their ETags
+    /// and parsed copies, and returns the names of the files removed. With
+    /// `dry_run` nothing is removed.
+    pub async fn prune_info(&self, keep: &HashSet<String>, dry_run: bool) -> Result<Vec<String>> {
+        let keep: HashSet<String> = keep.iter().map(|gem| Self::info_file_name(gem)).collect();
+        let mut removed = Vec::new();
+        for dir in ["info", "info-etags", "info-parsed"] {
+            let mut entries = fs::read_dir(self.cache_dir.join(dir)).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if keep.contains(&file_name) {
+                    continue;
+                }
+                if !dry_run {
+                    fs::remove_file(entry.path()).await?;
                 }
+                removed.push(file_name);
             }
-            let file = File::create(cache_path).await?;
-            let mut w = BufWriter::new(file);
-            w.write_all(&body).await?;
-            w.flush().await?;
-            InfoSource::Mem(Cursor::new(body.to_vec()))
+        }
+        removed.sort();
+        removed.dedup();
+        Ok(removed)
+    }
+
+    /// The MD5 of the info file of `gem_name` as listed in the cached `/versions`.
+    /// rubygems.org serves the same digest as the info file's ETag.
+    pub async fn info_checksum(&self, gem_name: &str) -> Result<Option<String>> {
+        let checksums = self
+            .info_checksums
+            .get_or_try_init(|| self.read_info_checksums())
+            .await?;
+        Ok(checksums.get(gem_name).cloned())
+    }
+
+    /// Checks a freshly downloaded info file against the MD5 listed for it in
+    /// `/versions`. On a mismatch `/versions` may just be older than the info
+    /// file, so it is downloaded again, once per client, and checked again;
+    /// the info file is removed from the cache when they still differ.
+    async fn verify_info_checksum(
+        &self,
+        gem_name: &str,
+        info_path: &Path,
+        etag_path: &Path,
+    ) -> Result<()> {
+        let checksums = self
+            .info_checksums
+            .get_or_try_init(|| self.read_info_checksums())
+            .await?;
+        let Some(expected) = checksums.get(gem_name) else {
+            return Ok(());
         };
+        if !info_path.exists() {
+            return Ok(());
+        }
+        let actual = self.md5_checksum(info_path).await?;
+        if &actual == expected {
+            return Ok(());
+        }
+        let rechecked = self
+            .rechecked_checksums
+            .get_or_try_init(|| self.refetch_info_checksums())
+            .await?;
+        match rechecked.get(gem_name) {
+            Some(expected) if expected != &actual => {
+                let _ = fs::remove_file(info_path).await;
+                let _ = fs::remove_file(etag_path.with_extension("etag")).await;
+                Err(CompactIndexError::ChecksumMismatch {
+                    expected: expected.clone(),
+                    actual,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
 
-        Ok(Some(file))
+    // --index-max-age で再検証しなかったときや、取得の合間に gem が公開されたときは /versions が古い
+    async fn refetch_info_checksums(&self) -> Result<HashMap<String, String>> {
+        let url = self.effective_base_url().join("versions")?;
+        let path = self.cache_dir.join("versions");
+        debug!("Downloading {} again to recheck info checksums", url);
+        self.update_cache(&url, &path, &path).await?;
+        self.read_info_checksums().await
+    }
+
+    /// Reads the `name versions md5` lines of the cached `/versions` file.
+    /// Later lines are appended updates and override earlier ones.
+    async fn read_info_checksums(&self) -> Result<HashMap<String, String>> {
+        let versions_path = self.cache_dir.join("versions");
+        let mut checksums = HashMap::new();
+        if !versions_path.exists() {
+            return Ok(checksums);
+        }
+        let content = fs::read_to_string(&versions_path).await?;
+        for line in content.lines().skip_while(|line| !is_header(line)).skip(1) {
+            let mut parts = line.split_whitespace();
+            if let (Some(name), Some(_), Some(md5)) = (parts.next(), parts.next(), parts.next()) {
+                checksums.insert(name.to_string(), md5.to_string());
+            }
+        }
+        Ok(checksums)
     }
 
     async fn read_etag(&self, file_path: &Path) -> Result<Option<String>> {
@@ -433,6 +817,131 @@ impl CompactIndexClient {
     }
 }
 
+// 304 Not Modified も 3xx だが、たどる先はない
+fn is_redirect(status: reqwest::StatusCode) -> bool {
+    use reqwest::StatusCode;
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+/// The base64 SHA-256 of the whole file a response carries all or part of,
+/// from its `Repr-Digest` (`sha-256=:...:`) or older `Digest` header.
+fn expected_sha256(headers: &HeaderMap) -> Option<String> {
+    ["repr-digest", "digest"].into_iter().find_map(|name| {
+        headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .split(',')
+            .find_map(|entry| {
+                let (algorithm, value) = entry.trim().split_once('=')?;
+                algorithm
+                    .eq_ignore_ascii_case("sha-256")
+                    .then(|| value.trim_matches(':').to_string())
+            })
+    })
+}
+
+/// Reads a `Retry-After` header given either in seconds or as an HTTP-date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(std::time::SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
+
+/// Whether `line` is the `---` that ends the header of `/versions` and info
+/// files. Trailing whitespace, such as the `\r` of a CRLF line, is ignored.
+fn is_header(line: &str) -> bool {
+    line.trim_end() == "---"
+}
+
+/// Parses one line of an info file, e.g. `1.2.0 rack:>= 2.0|checksum:abc,ruby:>= 2.7`.
+/// Platform-specific versions, e.g. `1.16.0-x86_64-linux`, are kept; the
+/// resolver picks among them by the lockfile platforms.
+pub fn parse_info_line(gem_name: &str, line: &str) -> Option<GemVersion> {
+    let (line, metadata) = line.split_once('|').unwrap_or((line, ""));
+
+    let mut parts = line.splitn(2, ' ');
+    let ver_str = parts.next().unwrap();
+    let deps_str = parts.next().unwrap_or("");
+
+    Some(GemVersion {
+        name: gem_name.to_string(),
+        version: RubyVersion::parse(ver_str),
+        checksum: metadata_value(metadata, "checksum").map(str::to_string),
+        dependencies: parse_dependencies(deps_str),
+        required_ruby_version: parse_required_ruby_version(gem_name, metadata),
+    })
+}
+
+// checksum:...,ruby:>= 2.7&< 4,rubygems:>= 3.0 から key の値を読む
+fn metadata_value<'a>(metadata: &'a str, key: &str) -> Option<&'a str> {
+    metadata
+        .split(',')
+        .filter_map(|entry| entry.split_once(':'))
+        .find(|(name, _)| name.trim().trim_start_matches('@') == key)
+        .map(|(_, value)| value.trim())
+}
+
+fn parse_required_ruby_version(gem_name: &str, metadata: &str) -> Option<RichReq> {
+    let req_str = metadata_value(metadata, "ruby")?;
+    match parse_req(req_str, "&") {
+        Ok((req, _)) => Some(req),
+        Err(err) => {
+            warn!(
+                "Ignoring the required Ruby version of {}: {}",
+                gem_name, err
+            );
+            None
+        }
+    }
+}
+
+/// Parses the dependency list of an info line, e.g. `rack:>= 2.0&< 3,thor:~> 1.2`.
+pub fn parse_dependencies(deps_str: &str) -> Vec<GemDependency> {
+    let mut dependencies = Vec::new();
+
+    for dep_entry in deps_str.split(',') {
+        let dep_entry = dep_entry.trim();
+        if dep_entry.is_empty() {
+            continue;
+        }
+        if let Some(idx) = dep_entry.find(':') {
+            let name = dep_entry[..idx].to_string();
+
+            let req_str = dep_entry[idx + 1..].trim();
+            let (req, req_str) = match parse_req(req_str, "&") {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    warn!("Skipping dependency {} of unknown form: {}", name, err);
+                    continue;
+                }
+            };
+            dependencies.push(GemDependency {
+                name: name.to_string(),
+                requirement: req,
+                requirement_str: req_str,
+            });
+        }
+    }
+    dependencies
+}
+
+/// The versions of `gems` listed in a `/versions` file, keyed by the name
+/// as the index spells it. A gem the index has only in another casing, such
+/// as `Rails` for `rails`, is matched case-insensitively with a warning.
 #[instrument(skip_all)]
 async fn parse_version<S>(
     mut lines: S,
@@ -442,18 +951,47 @@ where
     S: Stream<Item = String> + Unpin,
 {
     let mut map: HashMap<String, Vec<RubyVersion>> = HashMap::new();
+    // 小文字にした名前から、要求された名前へ
+    let folded: HashMap<String, String> = gems
+        .iter()
+        .map(|gem| (gem.to_lowercase(), gem.clone()))
+        .collect();
     let gems_set: HashSet<String> = gems.into_iter().collect();
     while let Some(line) = lines.next().await {
         let parts: Vec<&str> = line.split_whitespace().collect();
         if parts.len() < 2 {
             continue;
         }
-        if !gems_set.contains(parts[0]) {
+        if !gems_set.contains(parts[0]) && !folded.contains_key(&parts[0].to_lowercase()) {
             continue;
         }
+        let versions = map.entry(parts[0].to_string()).or_default();
         for ver_str in parts[1].split(',') {
-            let rv = RubyVersion::parse(ver_str.trim());
-            map.entry(parts[0].to_string()).or_default().push(rv);
+            // 後から追記された行の -1.0.0 は yank されたバージョン
+            match ver_str.trim().strip_prefix('-') {
+                Some(yanked) => {
+                    let yanked = RubyVersion::parse(yanked);
+                    versions.retain(|v| v != &yanked);
+                }
+                None => versions.push(RubyVersion::parse(ver_str.trim())),
+            }
+        }
+    }
+    // 大文字小文字が違うだけの名前は、正確に一致する名前がないときだけ使う
+    let inexact: Vec<String> = map
+        .keys()
+        .filter(|name| !gems_set.contains(*name))
+        .cloned()
+        .collect();
+    for name in inexact {
+        let requested = &folded[&name.to_lowercase()];
+        if map.contains_key(requested) {
+            map.remove(&name);
+        } else {
+            warn!(
+                "Could not find gem '{}' in the index, using '{}' instead; gem names are case-sensitive",
+                requested, name
+            );
         }
     }
     Ok(map)
@@ -462,9 +1000,31 @@ where
 #[cfg(test)]
 mod tests {
     use std::{
+        collections::HashMap,
         fs::File,
         io::{BufRead, BufReader},
         path::PathBuf,
+        time::Duration,
+    };
+
+    use base64::Engine;
+    use futures::stream;
+    use md5::{Digest, Md5};
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+    use sha2::Sha256;
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{header_exists, method, path},
+    };
+
+    use crate::{
+        compact_index_client::{
+            CompactIndexClient, CompactIndexError, ParsedInfo, parse_info_line, parse_version,
+            retry_after,
+        },
+        http::HttpOptions,
+        test_support::{flaky_server, mock_compact_index},
+        version::{RubyVersion, parse_req},
     };
 
     #[test]
@@ -481,8 +1041,674 @@ mod tests {
         // let line = lines.next().unwrap();
     }
 
-    // #[test]
-    // fn test_parse_version() {
-    //     let mut file = fs::OpenOptions::new().append(true).open(cache_path)?;
-    // }
+    #[tokio::test]
+    async fn test_retry_after_too_many_requests() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("---\n1.0.0 |checksum:00\n"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        let started = std::time::Instant::now();
+        let versions = client.info("a").await?;
+
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(versions.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retries_server_errors() -> anyhow::Result<()> {
+        let options = |retries| HttpOptions {
+            retries,
+            ..HttpOptions::default()
+        };
+        let dir = tempfile::tempdir()?;
+
+        let server = flaky_server("/info/a", b"---\n1.0.0 |checksum:00\n").await;
+        let client = CompactIndexClient::new(&server.uri(), dir.path(), options(1)).await?;
+        assert!(client.info("a").await.is_err());
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+
+        let server = flaky_server("/info/a", b"---\n1.0.0 |checksum:00\n").await;
+        let client = CompactIndexClient::new(&server.uri(), dir.path(), options(2)).await?;
+        assert_eq!(client.info("a").await?.len(), 1);
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_info_checksum() -> anyhow::Result<()> {
+        let info = "---\n1.0.0 |checksum:00\n";
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "created_at: 2024-01-01\n---\na 1.0.0 0123456789abcdef0123456789abcdef\nb 1.0.0 b4115e44e0e9aa3b39bccf335d91472e\n",
+            ))
+            .mount(&server)
+            .await;
+        for name in ["a", "b"] {
+            Mock::given(method("GET"))
+                .and(path(format!("/info/{}", name)))
+                .respond_with(ResponseTemplate::new(200).set_body_string(info))
+                .mount(&server)
+                .await;
+        }
+
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        client.ensure_versions_fresh().await?;
+
+        assert_eq!(client.info("b").await?.len(), 1);
+        match client.info("a").await {
+            Err(CompactIndexError::ChecksumMismatch { expected, .. }) => {
+                assert_eq!(expected, "0123456789abcdef0123456789abcdef")
+            }
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+        assert!(!client.cache_dir.join("info").join("a").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_info_checksum_with_stale_versions() -> anyhow::Result<()> {
+        let info = "---\n1.0.0 |checksum:00\n";
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                "created_at: 2024-01-01\n---\na 1.0.0 {:x}\n",
+                Md5::digest(info)
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(info))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let mut client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        // a が公開される前の /versions を、再検証せずに使う
+        std::fs::write(
+            client.cache_dir.join("versions"),
+            "created_at: 2024-01-01\n---\na 0.9.0 0123456789abcdef0123456789abcdef\n",
+        )?;
+        client.set_fresh_within(Some(Duration::from_secs(3600)));
+        client.ensure_versions_fresh().await?;
+
+        assert_eq!(client.info("a").await?.len(), 1);
+        assert!(client.cache_dir.join("info").join("a").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_versions_digest() -> anyhow::Result<()> {
+        let versions = "created_at: 2024-01-01\n---\na 1.0.0 01\nb 1.0.0 02\n";
+        let digest = |body: &str| {
+            format!(
+                "sha-256=:{}:",
+                base64::engine::general_purpose::STANDARD.encode(Sha256::digest(body))
+            )
+        };
+
+        // 本体が digest と合わなければキャッシュしない
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("repr-digest", digest("created_at: 2024-01-01\n---\n"))
+                    .set_body_string(versions),
+            )
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        assert!(matches!(
+            client.ensure_versions_fresh().await,
+            Err(CompactIndexError::ChecksumMismatch { .. })
+        ));
+        assert!(!client.cache_dir.join("versions").exists());
+
+        // 壊れたキャッシュに差分を足すと合わないので、全体を取り直す
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .and(header_exists("range"))
+            .respond_with(
+                ResponseTemplate::new(206)
+                    .insert_header("repr-digest", digest(versions))
+                    .set_body_string("\nb 1.0.0 02\n"),
+            )
+            .with_priority(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("digest", digest(versions).replace(':', ""))
+                    .insert_header("etag", "\"v2\"")
+                    .set_body_string(versions),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        std::fs::write(
+            client.cache_dir.join("versions"),
+            "created_at: 2024-01-01\n---\na 1.0.0 ff\n",
+        )?;
+        std::fs::write(client.cache_dir.join("versions.etag"), "\"v1\"")?;
+        client.ensure_versions_fresh().await?;
+        assert_eq!(
+            std::fs::read_to_string(client.cache_dir.join("versions"))?,
+            versions
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partial_content_appends() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .and(header_exists("if-none-match"))
+            .respond_with(ResponseTemplate::new(206).set_body_string("\nb 1.0.0 02\n"))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("etag", "\"v1\"")
+                    .set_body_string("created_at: 2024-01-01\n---\na 1.0.0 01\n"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/empty"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("---\n"))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        client.ensure_versions_fresh().await?;
+        // 次のプロセスでは差分だけを取りに行く
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        client.ensure_versions_fresh().await?;
+        assert_eq!(
+            std::fs::read_to_string(client.cache_dir.join("versions"))?,
+            "created_at: 2024-01-01\n---\na 1.0.0 01\nb 1.0.0 02\n"
+        );
+
+        // 中身のない info はキャッシュしない
+        assert!(client.info("empty").await?.is_empty());
+        assert!(!client.cache_dir.join("info").join("empty").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_cache() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        for name in ["if-none-match", "range"] {
+            Mock::given(method("GET"))
+                .and(header_exists(name))
+                .respond_with(ResponseTemplate::new(304))
+                .with_priority(1)
+                .expect(0)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("etag", "\"v2\"")
+                    .set_body_string("created_at: 2024-01-01\n---\na 1.0.0 01\n"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let mut client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        // 壊れたキャッシュ
+        std::fs::write(client.cache_dir.join("versions"), "garbage")?;
+        client.set_no_cache(true);
+        client.ensure_versions_fresh().await?;
+        assert_eq!(
+            std::fs::read_to_string(client.cache_dir.join("versions"))?,
+            "created_at: 2024-01-01\n---\na 1.0.0 01\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follows_permanent_redirect() -> anyhow::Result<()> {
+        let info = "---\n1.0.0 |checksum:00\n";
+        let new_host = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/mirror/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                "created_at: 2024-01-01\n---\na 1.0.0 {:x}\n",
+                Md5::digest(info)
+            )))
+            .expect(1)
+            .mount(&new_host)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/mirror/info/a"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(info))
+            .expect(1)
+            .mount(&new_host)
+            .await;
+
+        // /versions だけが移転を知らせ、info はもう古いホストには来ない
+        let old_host = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(301)
+                    .insert_header("Location", format!("{}/mirror/versions", new_host.uri())),
+            )
+            .expect(1)
+            .mount(&old_host)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(0)
+            .mount(&old_host)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&old_host.uri(), dir.path(), HttpOptions::default()).await?;
+        assert_eq!(client.versions(vec!["a".to_string()]).await?["a"].len(), 1);
+        assert_eq!(client.info("a").await?.len(), 1);
+        // lockfile には設定された remote を書く
+        assert_eq!(client.base_url().as_str(), format!("{}/", old_host.uri()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follows_temporary_redirect_every_time() -> anyhow::Result<()> {
+        let new_host = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("---\n1.0.0 |checksum:00\n"))
+            .expect(2)
+            .mount(&new_host)
+            .await;
+        let old_host = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(
+                ResponseTemplate::new(302)
+                    .insert_header("Location", format!("{}/info/a", new_host.uri())),
+            )
+            .expect(2)
+            .mount(&old_host)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&old_host.uri(), dir.path(), HttpOptions::default()).await?;
+        for _ in 0..2 {
+            assert_eq!(client.info("a").await?.len(), 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_versions_for() -> anyhow::Result<()> {
+        let server = mock_compact_index("grpc").await;
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+
+        assert_eq!(
+            client.versions_for("grpc-google-iam-v1").await?,
+            vec![RubyVersion::parse("1.10.0"), RubyVersion::parse("1.11.0")]
+        );
+        let newest = client.info("grpc-google-iam-v1").await?.pop().unwrap();
+        assert_eq!(
+            newest.checksum.as_deref(),
+            Some("b60121cc508c4ab376cdbd3841fe8f3d5dcca8c1e68de1f633548cff427713a6")
+        );
+        assert!(client.versions_for("no-such-gem").await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_versions_refreshed_once() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("created_at: 2024-01-01\n---\na 1.0.0,1.1.0 01\n"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        client.ensure_versions_fresh().await?;
+        let versions = client.clone().versions(vec!["a".to_string()]).await?;
+        assert_eq!(versions["a"].len(), 2);
+        client.ensure_versions_fresh().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crlf_line_endings() -> anyhow::Result<()> {
+        let info = "\r\n---\r\n1.0.0 b:>= 1.0|checksum:00\r\n1.1.0 |checksum:01,ruby:>= 3.0 \r\n";
+        let versions = format!(
+            "created_at: 2024-01-01\r\n--- \r\na 1.0.0,1.1.0 {:x}\r\n",
+            Md5::digest(info)
+        );
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(versions))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(info))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        assert_eq!(
+            client.versions(vec!["a".to_string()]).await?["a"],
+            vec![RubyVersion::parse("1.0.0"), RubyVersion::parse("1.1.0")]
+        );
+
+        let versions = client.info("a").await?;
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].dependencies[0].requirement_str, vec![">= 1.0"]);
+        assert!(versions[1].dependencies.is_empty());
+        let ruby = versions[1].required_ruby_version.as_ref().unwrap();
+        assert!(!ruby.range.contains(&RubyVersion::parse("2.7.8")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parsed_info_cache() -> anyhow::Result<()> {
+        let info = "---\n1.0.0 b:>= 1.0|checksum:00\n";
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v1\"")
+                    .set_body_string(info),
+            )
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        let versions = client.info("a").await?;
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].dependencies[0].requirement_str, vec![">= 1.0"]);
+
+        // 同じ ETag なら info ファイルではなくパース済みのキャッシュが使われる
+        let parsed_path = client.cache_dir.join("info-parsed").join("a");
+        let mut parsed: ParsedInfo = bincode::deserialize(&std::fs::read(&parsed_path)?)?;
+        assert_eq!(parsed.etag, "\"v1\"");
+        parsed.versions[0].version = RubyVersion::parse("9.9.9");
+        std::fs::write(&parsed_path, bincode::serialize(&parsed)?)?;
+        let versions = client.info("a").await?;
+        assert_eq!(versions[0].version, RubyVersion::parse("9.9.9"));
+        assert_eq!(
+            versions[0].dependencies[0].requirement,
+            parse_req(">= 1.0", "&").unwrap().0
+        );
+
+        // ETag が変われば info ファイルをパースし直す
+        server.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v2\"")
+                    .set_body_string(info),
+            )
+            .mount(&server)
+            .await;
+        let versions = client.info("a").await?;
+        assert_eq!(versions[0].version, RubyVersion::parse("1.0.0"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_info_encodes_gem_name() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/info/Odd%20gem%2F..%3F"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("---\n1.0.0 |checksum:00\n"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        assert_eq!(client.info("Odd gem/..?").await?.len(), 1);
+
+        let file_name = CompactIndexClient::info_file_name("Odd gem/..?");
+        assert!(file_name.starts_with("Odd-gem----"));
+        assert!(client.cache_dir.join("info").join(&file_name).exists());
+        assert_eq!(CompactIndexClient::info_file_name("rack-test"), "rack-test");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_versions_fresh_within() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string("created_at: 2024-01-01\n---\n"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let mut client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        std::fs::write(
+            client.cache_dir.join("versions"),
+            "created_at: 2024-01-01\n---\n",
+        )?;
+
+        client.set_fresh_within(Some(Duration::from_secs(3600)));
+        client.ensure_versions_fresh().await?;
+        client.set_fresh_within(None);
+        client.ensure_versions_fresh().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_offline() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string("created_at: 2024-01-01\n---\n"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("---\n1.0.0 |checksum:00\n"))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir()?;
+        let mut client =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        client.set_offline(true);
+        assert!(matches!(
+            client.ensure_versions_fresh().await,
+            Err(CompactIndexError::NotCached(_))
+        ));
+
+        // 一度取ってキャッシュしたものは、サーバーに聞かずに読める
+        let online =
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?;
+        online.ensure_versions_fresh().await?;
+        assert_eq!(online.info("a").await?.len(), 1);
+        let requests = server.received_requests().await.unwrap().len();
+
+        client.ensure_versions_fresh().await?;
+        assert_eq!(client.info("a").await?.len(), 1);
+        assert!(client.info("b").await?.is_empty());
+        assert_eq!(server.received_requests().await.unwrap().len(), requests);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_info_line_required_ruby_version() {
+        let version = parse_info_line(
+            "rack",
+            "3.0.9 webrick:>= 1.8|checksum:1234,ruby:>= 2.4.0&< 3.5,rubygems:>= 3.0",
+        )
+        .unwrap();
+        assert_eq!(version.version, RubyVersion::parse("3.0.9"));
+        assert_eq!(version.dependencies[0].name, "webrick");
+        let ruby = version.required_ruby_version.unwrap();
+        assert!(ruby.range.contains(&RubyVersion::parse("3.3.0")));
+        assert!(!ruby.range.contains(&RubyVersion::parse("2.3.8")));
+        assert!(!ruby.range.contains(&RubyVersion::parse("3.5.0")));
+
+        let version = parse_info_line("rack", "3.0.9 |@ruby:>= 2.7").unwrap();
+        assert!(version.required_ruby_version.is_some());
+        let version = parse_info_line("rack", "2.2.8 |checksum:1234").unwrap();
+        assert!(version.required_ruby_version.is_none());
+        assert_eq!(version.checksum.as_deref(), Some("1234"));
+        // 読めない要求は無視する
+        let version = parse_info_line("rack", "2.2.8 |ruby:~~ 2").unwrap();
+        assert!(version.required_ruby_version.is_none());
+        let version = parse_info_line("nokogiri", "1.16.0-x86_64-linux |ruby:>= 3.0").unwrap();
+        assert_eq!(version.version.platform(), Some("x86_64-linux"));
+        assert!(version.required_ruby_version.is_some());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/info/slow"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let options = HttpOptions {
+            timeout: Duration::from_millis(200),
+            retries: 0,
+            ..HttpOptions::default()
+        };
+        let client = CompactIndexClient::new(&server.uri(), dir.path(), options).await?;
+
+        match client.info("slow").await {
+            Err(CompactIndexError::Http(err)) => assert!(err.is_timeout()),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parse_version_matches_case_insensitively() -> anyhow::Result<()> {
+        let parse = |gems: &[&str]| {
+            let lines = [
+                "rails 7.0.0,7.1.0 0123",
+                "Rack 1.0.0 4567",
+                "rack 3.0.0 89ab",
+                "rails -7.0.0 cdef",
+            ];
+            parse_version(
+                stream::iter(lines.map(String::from)),
+                gems.iter().map(|gem| gem.to_string()).collect(),
+            )
+        };
+        let sorted = |map: HashMap<String, Vec<RubyVersion>>| {
+            let mut entries: Vec<_> = map
+                .into_iter()
+                .map(|(name, versions)| {
+                    let versions: Vec<_> = versions.iter().map(|v| v.to_string()).collect();
+                    (name, versions.join(","))
+                })
+                .collect();
+            entries.sort();
+            entries
+        };
+
+        assert_eq!(
+            sorted(parse(&["Rails", "rack"]).await?),
+            vec![
+                ("rack".to_string(), "3.0.0".to_string()),
+                ("rails".to_string(), "7.1.0".to_string()),
+            ]
+        );
+        assert_eq!(
+            sorted(parse(&["RACK"]).await?).len(),
+            2,
+            "every casing is kept when none matches exactly"
+        );
+        assert!(parse(&["thor"]).await?.is_empty());
+        Ok(())
+    }
 }
@@ -0,0 +1,298 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use url::Url;
+
+/// Settings stored in `.bundle/config`, the file `bundle config set` writes,
+/// and in `BUNDLE_*` environment variables.
+///
+/// Only the flat `KEY: "value"` form Bundler itself emits is understood.
+#[derive(Debug, Default, PartialEq)]
+pub struct BundleConfig {
+    values: BTreeMap<String, String>,
+    // 環境変数は .bundle/config より優先度が低く、保存もしない
+    env: BTreeMap<String, String>,
+}
+
+impl BundleConfig {
+    /// Reads `<dir>/.bundle/config`. A missing file is an empty config.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let config = match fs::read_to_string(Self::file(dir)) {
+            Ok(text) => Self::parse(&text),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(err),
+        };
+        Ok(config.with_env(std::env::vars()))
+    }
+
+    /// Adds the `BUNDLE_*` variables of `vars` as fallbacks for keys the file
+    /// does not set.
+    pub fn with_env(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.env = vars
+            .into_iter()
+            .filter(|(key, _)| key.starts_with("BUNDLE_"))
+            .collect();
+        self
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let file = Self::file(dir);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(file, self.to_string())
+    }
+
+    fn file(dir: &Path) -> PathBuf {
+        dir.join(".bundle").join("config")
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut values = BTreeMap::new();
+        for line in text.lines() {
+            // キーにも : が入りうる (BUNDLE_MIRROR__HTTPS://RUBYGEMS__ORG/)
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+            let key = key.trim();
+            if !key.starts_with("BUNDLE_") {
+                continue;
+            }
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            values.insert(key.to_string(), value.to_string());
+        }
+        Self {
+            values,
+            env: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .get(key)
+            .or_else(|| self.env.get(key))
+            .map(String::as_str)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    /// Every setting in effect. Values from `.bundle/config` win over the environment.
+    pub fn settings(&self) -> BTreeMap<&str, &str> {
+        self.env
+            .iter()
+            .chain(&self.values)
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect()
+    }
+
+    /// The key a setting named as in `bundle config <name>` is stored under,
+    /// e.g. `path` is `BUNDLE_PATH`. Keys already starting with `BUNDLE_` are kept.
+    pub fn key(name: &str) -> String {
+        let key = Self::key_for(name);
+        if key.starts_with("BUNDLE_") {
+            key
+        } else {
+            format!("BUNDLE_{}", key)
+        }
+    }
+
+    /// `BUNDLE_FROZEN`, the sticky form of `bundle install --frozen`.
+    pub fn frozen(&self) -> bool {
+        self.get("BUNDLE_FROZEN") == Some("true")
+    }
+
+    /// `BUNDLE_JOBS`, how many gems to fetch at a time. Values that are not
+    /// a positive number are ignored.
+    pub fn jobs(&self) -> Option<usize> {
+        self.get("BUNDLE_JOBS")?
+            .parse()
+            .ok()
+            .filter(|&jobs| jobs > 0)
+    }
+
+    /// `BUNDLE_ONLY`, the sticky form of `bundle install --only`. Bundler
+    /// separates the groups with `:` or spaces.
+    pub fn only(&self) -> Vec<String> {
+        self.get("BUNDLE_ONLY")
+            .unwrap_or_default()
+            .split([':', ' '])
+            .filter(|group| !group.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// `BUNDLE_PATH`, where `bundle install --path` puts gems.
+    /// `BUNDLE_USER_AGENT`, sent instead of the default `User-Agent`.
+    pub fn user_agent(&self) -> Option<&str> {
+        self.get("BUNDLE_USER_AGENT")
+    }
+
+    pub fn path(&self) -> Option<PathBuf> {
+        self.get("BUNDLE_PATH").map(PathBuf::from)
+    }
+
+    /// The mirror set with `bundle config set mirror.<source> <mirror>`, or
+    /// `mirror.all`, for the gem source `source`.
+    pub fn mirror_for(&self, source: &Url) -> Option<Url> {
+        let source = with_trailing_slash(source.clone());
+        [source.as_str(), "all"]
+            .into_iter()
+            .find_map(|key| self.get(&format!("BUNDLE_MIRROR__{}", Self::key_for(key))))
+            .and_then(|mirror| Url::parse(mirror).ok())
+            .map(with_trailing_slash)
+    }
+
+    // Bundler::Settings#key_for と同じ変換
+    fn key_for(key: &str) -> String {
+        key.replace('.', "__").replace('-', "___").to_uppercase()
+    }
+}
+
+// Bundler と同じく source の URI は / で終わる形に揃える
+fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
+
+impl std::fmt::Display for BundleConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "---")?;
+        for (key, value) in &self.values {
+            writeln!(f, "{}: \"{}\"", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use url::Url;
+
+    use crate::config::BundleConfig;
+
+    #[test]
+    fn test_config_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert_eq!(BundleConfig::load(dir.path())?.to_string(), "---\n");
+
+        let mut config = BundleConfig::default();
+        config.set("BUNDLE_PATH", "vendor/bundle");
+        config.save(dir.path())?;
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join(".bundle/config"))?,
+            "---\nBUNDLE_PATH: \"vendor/bundle\"\n"
+        );
+
+        let config = BundleConfig::load(dir.path())?;
+        assert_eq!(config.path(), Some(PathBuf::from("vendor/bundle")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_set_get_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut config = BundleConfig::load(dir.path())?;
+        config.set(&BundleConfig::key("frozen"), "true");
+        config.set(&BundleConfig::key("only"), "default:test");
+        config.set(
+            &BundleConfig::key("mirror.https://rubygems.org/"),
+            "https://gems.example.com/",
+        );
+        config.save(dir.path())?;
+
+        let config = BundleConfig::load(dir.path())?;
+        assert_eq!(config.get("BUNDLE_FROZEN"), Some("true"));
+        assert!(config.frozen());
+        assert_eq!(config.only(), vec!["default", "test"]);
+        assert_eq!(
+            config.get(&BundleConfig::key("mirror.https://rubygems.org/")),
+            Some("https://gems.example.com/")
+        );
+        assert_eq!(BundleConfig::key("BUNDLE_PATH"), "BUNDLE_PATH");
+        assert_eq!(
+            BundleConfig::key("gem.test-framework"),
+            "BUNDLE_GEM__TEST___FRAMEWORK"
+        );
+
+        let config = config.with_env([
+            ("BUNDLE_JOBS".to_string(), "4".to_string()),
+            ("BUNDLE_FROZEN".to_string(), "false".to_string()),
+        ]);
+        assert_eq!(
+            config.settings().into_iter().collect::<Vec<_>>(),
+            vec![
+                ("BUNDLE_FROZEN", "true"),
+                ("BUNDLE_JOBS", "4"),
+                (
+                    "BUNDLE_MIRROR__HTTPS://RUBYGEMS__ORG/",
+                    "https://gems.example.com/"
+                ),
+                ("BUNDLE_ONLY", "default:test"),
+            ]
+        );
+        assert_eq!(config.jobs(), Some(4));
+        assert_eq!(
+            BundleConfig::parse("---\nBUNDLE_JOBS: \"0\"\n").jobs(),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mirror_for() {
+        let rubygems = Url::parse("https://rubygems.org").unwrap();
+        let config = BundleConfig::parse(
+            "---\nBUNDLE_MIRROR__HTTPS://RUBYGEMS__ORG/: \"https://gems.example.com/rubygems\"\n",
+        );
+        assert_eq!(
+            config.mirror_for(&rubygems).map(String::from),
+            Some("https://gems.example.com/rubygems/".to_string())
+        );
+        assert_eq!(
+            config.mirror_for(&Url::parse("https://gems.my-company.dev/").unwrap()),
+            None
+        );
+
+        let env = |key: &str, value: &str| (key.to_string(), value.to_string());
+        let config = BundleConfig::default().with_env([
+            env(
+                "BUNDLE_MIRROR__HTTPS://GEMS__MY___COMPANY__DEV/",
+                "https://mirror.local/",
+            ),
+            env("BUNDLE_MIRROR__ALL", "https://all.local/"),
+            env("PATH", "/usr/bin"),
+        ]);
+        assert_eq!(
+            config
+                .mirror_for(&Url::parse("https://gems.my-company.dev").unwrap())
+                .map(String::from),
+            Some("https://mirror.local/".to_string())
+        );
+        assert_eq!(
+            config.mirror_for(&rubygems).map(String::from),
+            Some("https://all.local/".to_string())
+        );
+        assert_eq!(config.get("PATH"), None);
+
+        // .bundle/config が環境変数より優先される
+        let config = BundleConfig::parse("---\nBUNDLE_MIRROR__ALL: \"https://file.local/\"\n")
+            .with_env([env("BUNDLE_MIRROR__ALL", "https://all.local/")]);
+        assert_eq!(
+            config.mirror_for(&rubygems).map(String::from),
+            Some("https://file.local/".to_string())
+        );
+    }
+}
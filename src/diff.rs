@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use crate::version::RubyVersion;
+
+/// How one gem changed between two resolutions.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added {
+        name: String,
+        version: RubyVersion,
+    },
+    Removed {
+        name: String,
+        version: RubyVersion,
+    },
+    Upgraded {
+        name: String,
+        from: RubyVersion,
+        to: RubyVersion,
+    },
+    Downgraded {
+        name: String,
+        from: RubyVersion,
+        to: RubyVersion,
+    },
+}
+
+impl Change {
+    pub fn name(&self) -> &str {
+        match self {
+            Change::Added { name, .. }
+            | Change::Removed { name, .. }
+            | Change::Upgraded { name, .. }
+            | Change::Downgraded { name, .. } => name,
+        }
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added { name, version } => write!(f, "Installing {} {}", name, version),
+            Change::Removed { name, version } => write!(f, "Removing {} ({})", name, version),
+            Change::Upgraded { name, from, to } | Change::Downgraded { name, from, to } => {
+                write!(f, "Installing {} {} (was {})", name, to, from)
+            }
+        }
+    }
+}
+
+// 名前ごとに 1 つのバージョンにまとめる。プラットフォーム付きよりも素の gem を優先する
+// bundler 自身はロックされないので比べない
+fn by_name(solution: &[(String, RubyVersion)]) -> BTreeMap<&str, &RubyVersion> {
+    let mut gems: BTreeMap<&str, &RubyVersion> = BTreeMap::new();
+    for (name, version) in solution {
+        if name == "root" || name == "bundler" {
+            continue;
+        }
+        gems.entry(name)
+            .and_modify(|current| {
+                if current.is_platform() && !version.is_platform() {
+                    *current = version;
+                }
+            })
+            .or_insert(version);
+    }
+    gems
+}
+
+/// Lists what changed from `old` to `new`, sorted by gem name.
+pub fn diff_solutions(old: &[(String, RubyVersion)], new: &[(String, RubyVersion)]) -> Vec<Change> {
+    let old = by_name(old);
+    let new = by_name(new);
+    let mut changes = Vec::new();
+    for (name, version) in &new {
+        let name = name.to_string();
+        let to = (*version).clone();
+        match old.get(name.as_str()) {
+            None => changes.push(Change::Added { name, version: to }),
+            Some(from) if *from < *version => changes.push(Change::Upgraded {
+                name,
+                from: (*from).clone(),
+                to,
+            }),
+            Some(from) if *from > *version => changes.push(Change::Downgraded {
+                name,
+                from: (*from).clone(),
+                to,
+            }),
+            Some(_) => {}
+        }
+    }
+    for (name, version) in &old {
+        if !new.contains_key(name) {
+            changes.push(Change::Removed {
+                name: name.to_string(),
+                version: (*version).clone(),
+            });
+        }
+    }
+    changes.sort_by(|a, b| a.name().cmp(b.name()));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        diff::{Change, diff_solutions},
+        version::RubyVersion,
+    };
+
+    fn solution(gems: &[(&str, &str)]) -> Vec<(String, RubyVersion)> {
+        gems.iter()
+            .map(|(name, version)| (name.to_string(), RubyVersion::parse(version)))
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_solutions() {
+        let old = solution(&[
+            ("rails", "6.1.7"),
+            ("rack", "2.2.8"),
+            ("nokogiri", "1.16.0-x86_64-linux"),
+            ("nokogiri", "1.16.0"),
+            ("thor", "1.3.2"),
+            ("puma", "6.4.0"),
+        ]);
+        let new = solution(&[
+            ("root", "0.0.0"),
+            ("bundler", "2.6.8"),
+            ("rails", "7.0.0"),
+            ("rack", "2.2.4"),
+            ("nokogiri", "1.16.0"),
+            ("puma", "6.4.0"),
+            ("zeitwerk", "2.7.2"),
+        ]);
+
+        let changes = diff_solutions(&old, &new);
+        assert_eq!(
+            changes,
+            vec![
+                Change::Downgraded {
+                    name: "rack".to_string(),
+                    from: RubyVersion::parse("2.2.8"),
+                    to: RubyVersion::parse("2.2.4"),
+                },
+                Change::Upgraded {
+                    name: "rails".to_string(),
+                    from: RubyVersion::parse("6.1.7"),
+                    to: RubyVersion::parse("7.0.0"),
+                },
+                Change::Removed {
+                    name: "thor".to_string(),
+                    version: RubyVersion::parse("1.3.2"),
+                },
+                Change::Added {
+                    name: "zeitwerk".to_string(),
+                    version: RubyVersion::parse("2.7.2"),
+                },
+            ]
+        );
+        let lines: Vec<_> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Installing rack 2.2.4 (was 2.2.8)",
+                "Installing rails 7.0.0 (was 6.1.7)",
+                "Removing thor (1.3.2)",
+                "Installing zeitwerk 2.7.2",
+            ]
+        );
+    }
+}
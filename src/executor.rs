@@ -1,19 +1,22 @@
 use std::{env, path::PathBuf, process::Command};
 
 pub struct Executor {
-    args: Vec<String>
+    args: Vec<String>,
+    vendor_root: PathBuf,
 }
 
 impl Executor {
-    pub fn new(args: Vec<String>) -> Self {
+    /// `vendor_root` is the gem home the bundle was installed into,
+    /// e.g. `vendor/bundle/ruby/3.3.0`.
+    pub fn new(args: Vec<String>, vendor_root: PathBuf) -> Self {
         Self {
-            args
+            args,
+            vendor_root,
         }
     }
 
     pub fn exec(&self) -> anyhow::Result<()> {
-        let ruby_ver = "3.3.0";
-        let vendor_root = PathBuf::from("vendor").join("bundle").join("ruby").join(&ruby_ver);
+        let vendor_root = &self.vendor_root;
         let bin_path = vendor_root.join("bin");
     
         // Environment --------------------------------------------------------------
@@ -29,8 +32,8 @@ impl Executor {
         let status = Command::new(&self.args[0])
             .args(&self.args[1..])
             .env("BUNDLE_GEMFILE", "Gemfile")
-            .env("GEM_HOME", &vendor_root)
-            .env("GEM_PATH", &vendor_root)
+            .env("GEM_HOME", vendor_root)
+            .env("GEM_PATH", vendor_root)
             .env("RUBYOPT", rubyopt)
             .env("PATH", path_val)
             .status()?;
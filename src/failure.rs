@@ -0,0 +1,176 @@
+use std::{backtrace::Backtrace, error::Error, fmt::Write};
+
+use thiserror::Error;
+
+use crate::{compact_index_client::CompactIndexError, installer::InstallerError};
+
+/// Exit status for a failed resolution, and for errors that fit no other category.
+pub const EXIT_RESOLUTION: i32 = 1;
+/// Exit status when a gem source or download could not be reached.
+pub const EXIT_NETWORK: i32 = 2;
+/// Exit status when a resolved gem could not be installed.
+pub const EXIT_INSTALL: i32 = 3;
+/// Exit status for a Gemfile (or path gemspec) that cannot be used.
+pub const EXIT_GEMFILE: i32 = 4;
+/// Exit status when `--frozen` finds a resolved gem its source no longer lists.
+pub const EXIT_YANKED: i32 = 5;
+
+/// Failures `run` reports itself, as opposed to errors passed up from a module.
+#[derive(Debug, Error)]
+pub enum Failure {
+    #[error("{0}")]
+    Gemfile(String),
+
+    #[error("{0}")]
+    Resolution(String),
+
+    #[error("{0}")]
+    Yanked(String),
+}
+
+/// The exit status `bundle` ends with for `err`:
+///
+/// | status | failure |
+/// |--------|---------|
+/// | 1 | dependencies could not be resolved, or anything not listed below |
+/// | 2 | network or HTTP failure while fetching the index or a gem |
+/// | 3 | a gem could not be installed |
+/// | 4 | the Gemfile is malformed or inconsistent |
+/// | 5 | with `--frozen`, a resolved gem is no longer listed by its source |
+pub fn exit_code(err: &(dyn Error + 'static)) -> i32 {
+    if let Some(failure) = err.downcast_ref::<Failure>() {
+        return match failure {
+            Failure::Gemfile(_) => EXIT_GEMFILE,
+            Failure::Resolution(_) => EXIT_RESOLUTION,
+            Failure::Yanked(_) => EXIT_YANKED,
+        };
+    }
+    if let Some(err) = err.downcast_ref::<CompactIndexError>() {
+        // キャッシュの読み書きや URL の間違いはネットワークのせいではない
+        return match err {
+            CompactIndexError::Http(_)
+            | CompactIndexError::NotFound(_)
+            | CompactIndexError::NotCached(_)
+            | CompactIndexError::Other(_) => EXIT_NETWORK,
+            CompactIndexError::Io(_)
+            | CompactIndexError::ChecksumMismatch { .. }
+            | CompactIndexError::GemNotFound(_)
+            | CompactIndexError::UrlParse(_) => EXIT_RESOLUTION,
+        };
+    }
+    if err.is::<reqwest::Error>() {
+        return EXIT_NETWORK;
+    }
+    match err.downcast_ref::<InstallerError>() {
+        Some(InstallerError::Http(_)) => EXIT_NETWORK,
+        Some(_) => EXIT_INSTALL,
+        None => EXIT_RESOLUTION,
+    }
+}
+
+/// What `bundle` prints for `err`: its message, and with `trace` (`--trace`)
+/// also every error beneath it and a backtrace of where it was reported.
+pub fn report(err: &(dyn Error + 'static), trace: bool) -> String {
+    let mut report = err.to_string();
+    if !trace {
+        return report;
+    }
+    let mut source = err.source();
+    if source.is_some() {
+        report.push_str("\n\nCaused by:");
+    }
+    let mut depth = 0;
+    while let Some(cause) = source {
+        let _ = write!(report, "\n    {}: {}", depth, cause);
+        source = cause.source();
+        depth += 1;
+    }
+    // エラーの型は backtrace を持たないので、報告した場所のものを出す
+    let _ = write!(report, "\n\nBacktrace:\n{}", Backtrace::force_capture());
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::{
+        compact_index_client::CompactIndexError,
+        failure::{
+            EXIT_GEMFILE, EXIT_INSTALL, EXIT_NETWORK, EXIT_RESOLUTION, EXIT_YANKED, Failure,
+            exit_code, report,
+        },
+        installer::InstallerError,
+        resolver::Resolver,
+        version::{RubyVersion, parse_req},
+    };
+
+    #[test]
+    fn test_resolution_conflict_exit_code() {
+        // root は a 2 系を要求するが、a は 1.0 しかない
+        let mut resolver = Resolver::new();
+        resolver.add_dependencies("a".into(), RubyVersion::parse("1.0"), vec![]);
+        let (req, req_str) = parse_req(">= 2.0", ",").unwrap();
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![("a".into(), req, req_str)],
+        );
+
+        let err: Box<dyn Error> = resolver
+            .resolve()
+            .map_err(|err| Failure::Resolution(err.to_string()))
+            .unwrap_err()
+            .into();
+        assert_eq!(exit_code(err.as_ref()), EXIT_RESOLUTION);
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let code = |err: Box<dyn Error>| exit_code(err.as_ref());
+        assert_eq!(code(Failure::Gemfile("bad".into()).into()), EXIT_GEMFILE);
+        assert_eq!(
+            code(Failure::Yanked("rack (3.0.0)".into()).into()),
+            EXIT_YANKED
+        );
+        assert_eq!(
+            code(CompactIndexError::Other("HTTP error: 503".into()).into()),
+            EXIT_NETWORK
+        );
+        assert_eq!(
+            code(CompactIndexError::Io(std::io::Error::other("disk full")).into()),
+            EXIT_RESOLUTION
+        );
+        assert_eq!(
+            code(
+                CompactIndexError::ChecksumMismatch {
+                    expected: "a".into(),
+                    actual: "b".into()
+                }
+                .into()
+            ),
+            EXIT_RESOLUTION
+        );
+        assert_eq!(
+            code(InstallerError::Extraction("bad tar".into()).into()),
+            EXIT_INSTALL
+        );
+        assert_eq!(code("something else".into()), EXIT_RESOLUTION);
+    }
+
+    #[test]
+    fn test_report() {
+        let io = std::io::Error::other("disk full");
+        let err: Box<dyn Error> = InstallerError::Io(io).into();
+
+        assert_eq!(report(err.as_ref(), false), "IO error: disk full");
+        let trace = report(err.as_ref(), true);
+        assert!(
+            trace
+                .starts_with("IO error: disk full\n\nCaused by:\n    0: disk full\n\nBacktrace:\n"),
+            "{}",
+            trace
+        );
+        assert!(trace.contains("test_report"), "{}", trace);
+    }
+}
@@ -1,56 +1,369 @@
-use std::{io, path::Path};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    path::Path,
+};
 
+use sha2::{Digest, Sha256};
 use tokio::{
     fs::File,
-    io::{AsyncWriteExt, BufWriter},
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+};
+use tracing::warn;
+
+use crate::{
+    git::GitReference,
+    resolver::Resolver,
+    version::{RichReq, RubyVersion, parse_req},
 };
 
-use crate::{resolver::Resolver, version::RubyVersion};
+/// Orders the comparators of a requirement the way Bundler does
+/// (`Gem::Dependency#to_lock` sorts them and reverses), e.g. `>= 1.0, < 2.0`.
+pub fn sort_requirements(reqs: &[String]) -> Vec<String> {
+    let mut reqs = reqs.to_vec();
+    reqs.sort();
+    reqs.reverse();
+    reqs
+}
+
+/// Whether a requirement allows every release, so that Bundler writes the gem
+/// without one: no comparators, `*`, or `>= 0` (also spelled `>= 0.0`).
+pub fn is_unconstrained(reqs: &[String]) -> bool {
+    reqs.iter().all(|req| {
+        let req = req.trim();
+        req.is_empty()
+            || req == "*"
+            || req.strip_prefix(">=").is_some_and(|version| {
+                RubyVersion::parse(version.trim()) == RubyVersion::new(0, 0, 0)
+            })
+    })
+}
+
+/// The parts of a `Gemfile.lock` needed by commands that inspect an existing bundle.
+#[derive(Debug, Default)]
+pub struct Lockfile {
+    pub specs: Vec<(String, RubyVersion)>,
+    /// The names of the specs under `GEM`, i.e. those served by a gem index.
+    pub gem_specs: Vec<String>,
+    /// The Gemfile's own dependencies, from `DEPENDENCIES`.
+    pub dependencies: Vec<LockedDependency>,
+}
+
+/// One line of `DEPENDENCIES`, e.g. `rails (~> 6.1.3)` or `mygem!`.
+#[derive(Debug, PartialEq)]
+pub struct LockedDependency {
+    pub name: String,
+    pub requirement: RichReq,
+    /// The comparators as written, empty when the gem is unconstrained.
+    pub requirement_str: Vec<String>,
+    /// Marked with `!`: the gem comes from a path or git source, not a gem server.
+    pub pinned: bool,
+}
+
+impl Lockfile {
+    /// How the Gemfile's `dependencies` differ from the ones this lockfile was
+    /// written for, one description per added, removed or changed gem.
+    /// Requirements are compared by the versions they allow, so `~> 6.1` and
+    /// `>= 6.1, < 7` do not count as a change.
+    pub fn dependency_changes(
+        &self,
+        dependencies: &[(String, RichReq, Vec<String>)],
+    ) -> Vec<String> {
+        let written = |reqs: &[String]| {
+            if is_unconstrained(reqs) {
+                return ">= 0".to_string();
+            }
+            sort_requirements(reqs).join(", ")
+        };
+        let mut changes = Vec::new();
+        for (name, requirement, requirement_str) in dependencies {
+            match self.dependencies.iter().find(|dep| &dep.name == name) {
+                None => changes.push(format!("{} was added to the Gemfile", name)),
+                Some(locked) if locked.requirement.range != requirement.range => {
+                    changes.push(format!(
+                        "{} is now {}, locked as {}",
+                        name,
+                        written(requirement_str),
+                        written(&locked.requirement_str)
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        for locked in &self.dependencies {
+            if !dependencies.iter().any(|(name, _, _)| name == &locked.name) {
+                changes.push(format!("{} was removed from the Gemfile", locked.name));
+            }
+        }
+        changes
+    }
+}
 
+// "  rails (~> 6.1.3, >= 6.1.3.1)" や "  mygem!" の 1 行
+fn parse_dependency(line: &str) -> Option<LockedDependency> {
+    let line = line.trim();
+    let (line, pinned) = match line.strip_suffix('!') {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+    let (name, requirement) = match line.split_once(" (") {
+        Some((name, requirement)) => (name, requirement.strip_suffix(')')?),
+        None => (line, ">= 0"),
+    };
+    let (requirement, requirement_str) = match parse_req(requirement, ",") {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            warn!("Ignoring {} in DEPENDENCIES of Gemfile.lock: {}", name, err);
+            return None;
+        }
+    };
+    Some(LockedDependency {
+        name: name.to_string(),
+        requirement,
+        requirement_str: requirement_str
+            .into_iter()
+            .filter(|req| req != ">= 0")
+            .collect(),
+        pinned,
+    })
+}
+
+pub fn parse_lockfile(content: &str) -> Lockfile {
+    let mut lockfile = Lockfile::default();
+    let mut in_specs = false;
+    let mut in_gem = false;
+    let mut in_dependencies = false;
+    for line in content.lines() {
+        if !line.starts_with(' ') {
+            in_specs = false;
+            in_gem = line == "GEM";
+            in_dependencies = line == "DEPENDENCIES";
+            continue;
+        }
+        if in_dependencies {
+            lockfile.dependencies.extend(parse_dependency(line));
+            continue;
+        }
+        if line == "  specs:" {
+            in_specs = true;
+            continue;
+        }
+        // Specs are indented by four spaces, their dependencies by six.
+        let Some(spec) = line.strip_prefix("    ") else {
+            continue;
+        };
+        if !in_specs || spec.starts_with(' ') {
+            continue;
+        }
+        if let Some((name, version)) = spec.split_once(" (") {
+            let version = version.trim_end_matches(')');
+            if in_gem {
+                lockfile.gem_specs.push(name.to_string());
+            }
+            lockfile
+                .specs
+                .push((name.to_string(), RubyVersion::parse(version)));
+        }
+    }
+    lockfile
+}
+
+pub async fn read_lockfile(path: &Path) -> io::Result<Lockfile> {
+    let content = tokio::fs::read_to_string(path).await?;
+    Ok(parse_lockfile(&content))
+}
+
+/// Where the specs of one lockfile section come from.
+///
+/// The variant order is the section order Bundler writes: `GIT`, `PATH`, then `GEM`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockSource {
+    /// A `git:` gem, pinned to the commit that was checked out.
+    Git {
+        remote: String,
+        revision: String,
+        reference: Option<GitReference>,
+    },
+    /// A `path:` gem, keyed by the path as written in the Gemfile.
+    Path(String),
+    /// A rubygems remote URL.
+    Gem(String),
+}
+
+impl LockSource {
+    fn header(&self) -> &'static str {
+        match self {
+            LockSource::Git { .. } => "GIT",
+            LockSource::Path(_) => "PATH",
+            LockSource::Gem(_) => "GEM",
+        }
+    }
+
+    fn remote(&self) -> &str {
+        match self {
+            LockSource::Git { remote, .. } | LockSource::Path(remote) | LockSource::Gem(remote) => {
+                remote
+            }
+        }
+    }
+
+    // remote: の後、specs: の前に書く行
+    fn options(&self) -> Vec<String> {
+        match self {
+            LockSource::Git {
+                revision,
+                reference,
+                ..
+            } => std::iter::once(format!("revision: {}", revision))
+                .chain(reference.as_ref().map(GitReference::lock_line))
+                .collect(),
+            LockSource::Path(_) | LockSource::Gem(_) => vec![],
+        }
+    }
+}
+
+/// Writes a lockfile with one section per entry in `sources`, which maps each
+/// source to the resolved specs it serves.
+///
+/// Sections are ordered by source type, then by remote, as Bundler does.
+/// `platforms` are written to `PLATFORMS` in the given order.
+///
+/// An existing lockfile keeps its `RUBY VERSION` and `BUNDLED WITH` sections,
+/// and is not touched at all when nothing else changed. Returns whether the
+/// file was written.
 pub async fn write_lockfile(
-    solutions: Vec<(String, RubyVersion)>,
-    resolver: Resolver,
+    sources: BTreeMap<LockSource, Vec<(String, RubyVersion)>>,
+    platforms: &[String],
+    resolver: &Resolver,
     path: &Path,
+) -> io::Result<bool> {
+    let mut content = Vec::new();
+    write_lockfile_to(sources, platforms, resolver, &mut content).await?;
+    let content = String::from_utf8(content).expect("lockfile content is UTF-8");
+
+    let previous = tokio::fs::read_to_string(path).await.ok();
+    let content = match &previous {
+        Some(previous) => carry_over_sections(previous, &content),
+        None => content,
+    };
+    if previous.as_deref() == Some(content.as_str()) {
+        return Ok(false);
+    }
+    // --lockfile で指定した、まだないディレクトリにも書けるようにする
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut file = File::create(path).await?;
+    file.write_all(content.as_bytes()).await?;
+    file.flush().await?;
+    Ok(true)
+}
+
+// 生成しないセクション (RUBY VERSION) と BUNDLED WITH は前の lockfile のものを使う
+fn carry_over_sections(previous: &str, content: &str) -> String {
+    let header = |section: &str| section.lines().next().unwrap_or("").to_string();
+    let kept: Vec<&str> = previous
+        .split("\n\n")
+        .filter(|section| matches!(header(section).as_str(), "RUBY VERSION" | "BUNDLED WITH"))
+        .collect();
+    let mut sections: Vec<&str> = content
+        .split("\n\n")
+        .filter(|section| header(section) != "BUNDLED WITH")
+        .collect();
+    sections.extend(kept);
+    if sections
+        .last()
+        .is_none_or(|section| header(section) != "BUNDLED WITH")
+    {
+        // 前の lockfile に BUNDLED WITH がなければ、生成したものを使う
+        sections.extend(
+            content
+                .split("\n\n")
+                .filter(|section| header(section) == "BUNDLED WITH"),
+        );
+    }
+    let mut joined = sections.join("\n\n");
+    if !joined.ends_with('\n') {
+        joined.push('\n');
+    }
+    joined
+}
+
+/// SHA-256 (hex) of the lockfile [`write_lockfile`] would write, for use as a
+/// CI cache key. Equal resolutions hash the same however they were collected.
+pub async fn lockfile_hash(
+    sources: BTreeMap<LockSource, Vec<(String, RubyVersion)>>,
+    platforms: &[String],
+    resolver: &Resolver,
+) -> io::Result<String> {
+    let mut content = Vec::new();
+    write_lockfile_to(sources, platforms, resolver, &mut content).await?;
+    Ok(format!("{:x}", Sha256::digest(&content)))
+}
+
+/// Like [`write_lockfile`], but writes to `writer`, e.g. stdout for `bundle lock --print`.
+pub async fn write_lockfile_to(
+    sources: BTreeMap<LockSource, Vec<(String, RubyVersion)>>,
+    platforms: &[String],
+    resolver: &Resolver,
+    writer: impl AsyncWrite + Unpin,
 ) -> io::Result<()> {
-    let file = File::create(path).await?;
-    let mut w = BufWriter::new(file);
-
-    w.write_all(b"GEM\n").await?;
-    w.write_all(b"  remote: https://rubygems.org/\n").await?;
-    w.write_all(b"  specs:\n").await?;
-    let mut solutions = solutions;
-    solutions.sort_by(|a, b| a.0.cmp(&b.0));
-    for (pkg, ver) in &solutions {
-        if pkg == "root" {
-            continue;
-        }
-        w.write_all(format!("    {} ({})\n", pkg, ver).as_bytes())
+    let mut w = BufWriter::new(writer);
+
+    // Gemfile では rubygems 以外の source の gem に ! が付く
+    let pinned: HashSet<String> = sources
+        .iter()
+        .filter(|(source, _)| !matches!(source, LockSource::Gem(_)))
+        .flat_map(|(_, specs)| specs.iter().map(|(name, _)| name.clone()))
+        .collect();
+    for (source, specs) in sources {
+        w.write_all(format!("{}\n", source.header()).as_bytes())
             .await?;
-        if let Some(deps) = resolver.get_dependencies_str(pkg, ver) {
-            let mut deps = deps.clone();
-            deps.sort_by(|a, b| a.0.cmp(&b.0));
-            for (dg, dr) in deps {
-                let mut dr = dr.clone();
-                dr.reverse();
-                w.write_all(
-                    format!(
-                        "      {}{}\n",
-                        dg,
-                        if dr.iter().all(|r| r != ">= 0") {
-                            format!(" ({})", dr.join(", "))
-                        } else {
-                            String::new()
-                        }
-                    )
-                    .as_bytes(),
-                )
+        w.write_all(format!("  remote: {}\n", source.remote()).as_bytes())
+            .await?;
+        for option in source.options() {
+            w.write_all(format!("  {}\n", option).as_bytes()).await?;
+        }
+        w.write_all(b"  specs:\n").await?;
+        let mut specs = specs;
+        specs.sort_by(|a, b| a.0.cmp(&b.0));
+        for (pkg, ver) in &specs {
+            // Bundler never locks itself
+            if pkg == "bundler" {
+                continue;
+            }
+            w.write_all(format!("    {} ({})\n", pkg, ver).as_bytes())
                 .await?;
+            if let Some(deps) = resolver.get_dependencies_str(pkg, ver) {
+                let mut deps = deps.clone();
+                deps.sort_by(|a, b| a.0.cmp(&b.0));
+                for (dg, dr) in deps {
+                    let dr = sort_requirements(&dr);
+                    w.write_all(
+                        format!(
+                            "      {}{}\n",
+                            dg,
+                            if !is_unconstrained(&dr) {
+                                format!(" ({})", dr.join(", "))
+                            } else {
+                                String::new()
+                            }
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                }
             }
         }
+        w.write_all(b"\n").await?;
     }
-    w.write_all(b"\n").await?;
     w.write_all(b"PLATFORMS\n").await?;
-    w.write_all(b"  ruby\n").await?;
+    for platform in platforms {
+        w.write_all(format!("  {}\n", platform).as_bytes()).await?;
+    }
     w.write_all(b"\n").await?;
     w.write_all(b"DEPENDENCIES\n").await?;
     if let Some(deps) =
@@ -59,17 +372,17 @@ pub async fn write_lockfile(
         let mut deps = deps.clone();
         deps.sort_by(|a, b| a.0.cmp(&b.0));
         for (dg, dr) in deps {
-            let mut dr = dr.clone();
-            // dr.reverse();
+            let dr = sort_requirements(&dr);
             w.write_all(
                 format!(
-                    "  {}{}\n",
+                    "  {}{}{}\n",
                     dg,
-                    if dr.iter().all(|r| r != ">= 0") {
+                    if !is_unconstrained(&dr) {
                         format!(" ({})", dr.join(", "))
                     } else {
                         String::new()
-                    }
+                    },
+                    if pinned.contains(&dg) { "!" } else { "" }
                 )
                 .as_bytes(),
             )
@@ -83,3 +396,655 @@ pub async fn write_lockfile(
     w.flush().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        gemfilelock::{
+            LockSource, LockedDependency, is_unconstrained, lockfile_hash, parse_lockfile,
+            read_lockfile, sort_requirements, write_lockfile, write_lockfile_to,
+        },
+        git::GitReference,
+        resolver::Resolver,
+        version::{RubyVersion, parse_req},
+    };
+
+    type Sections = BTreeMap<LockSource, Vec<(String, RubyVersion)>>;
+
+    fn rubygems() -> LockSource {
+        LockSource::Gem("https://rubygems.org/".to_string())
+    }
+
+    /// Everything `resolver` resolves besides `root`, in one rubygems.org section.
+    fn resolved_on_rubygems(resolver: &Resolver) -> anyhow::Result<Sections> {
+        let specs = resolver
+            .resolve()?
+            .into_iter()
+            .filter(|(name, _)| name != "root")
+            .collect();
+        Ok(BTreeMap::from([(rubygems(), specs)]))
+    }
+
+    /// A resolver whose root needs every gem of `specs`, none of which has
+    /// dependencies, and those gems in one rubygems.org section.
+    fn lock_fixture(specs: &[(&str, &str)]) -> (Sections, Resolver) {
+        let mut resolver = Resolver::new();
+        let mut root = vec![];
+        for (name, version) in specs {
+            resolver.add_dependencies(name.to_string(), RubyVersion::parse(version), vec![]);
+            let (req, req_str) = parse_req(">= 0", ",").unwrap();
+            root.push((name.to_string(), req, req_str));
+        }
+        resolver.add_dependencies("root".into(), RubyVersion::new(0, 0, 0), root);
+        let sources = BTreeMap::from([(
+            rubygems(),
+            specs
+                .iter()
+                .map(|(name, version)| (name.to_string(), RubyVersion::parse(version)))
+                .collect(),
+        )]);
+        (sources, resolver)
+    }
+
+    #[test]
+    fn test_sort_requirements() {
+        let reqs = vec!["< 2.0".to_string(), ">= 1.0".to_string()];
+        assert_eq!(sort_requirements(&reqs), vec![">= 1.0", "< 2.0"]);
+
+        let reqs = vec![">= 2.5.4".to_string(), "~> 2.5".to_string()];
+        assert_eq!(sort_requirements(&reqs), vec!["~> 2.5", ">= 2.5.4"]);
+    }
+
+    #[test]
+    fn test_is_unconstrained() {
+        let reqs = |reqs: &[&str]| reqs.iter().map(|req| req.to_string()).collect::<Vec<_>>();
+        assert!(is_unconstrained(&reqs(&[])));
+        assert!(is_unconstrained(&reqs(&[">= 0"])));
+        assert!(is_unconstrained(&reqs(&["*"])));
+        assert!(is_unconstrained(&reqs(&[">= 0.0", "*"])));
+        assert!(!is_unconstrained(&reqs(&[">= 0", "< 2"])));
+        assert!(!is_unconstrained(&reqs(&[">= 0.1"])));
+        assert!(!is_unconstrained(&reqs(&["> 0"])));
+    }
+
+    #[test]
+    fn test_parse_lockfile() {
+        let lockfile = parse_lockfile(
+            "GEM
+  remote: https://rubygems.org/
+  specs:
+    actioncable (7.0.8.7)
+      actionpack (= 7.0.8.7)
+      nio4r (~> 2.0)
+    nokogiri (1.16.0-x86_64-linux)
+      racc (~> 1.4)
+
+PLATFORMS
+  ruby
+
+DEPENDENCIES
+  actioncable
+
+BUNDLED WITH
+   2.5.22
+",
+        );
+        assert_eq!(
+            lockfile.specs,
+            vec![
+                ("actioncable".to_string(), RubyVersion::parse("7.0.8.7")),
+                (
+                    "nokogiri".to_string(),
+                    RubyVersion::parse("1.16.0-x86_64-linux")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_lockfile_gem_specs() {
+        let lockfile = parse_lockfile(
+            "PATH
+  remote: vendor/mygem
+  specs:
+    mygem (0.1.0)
+      rack
+
+GEM
+  remote: https://rubygems.org/
+  specs:
+    rack (3.0.9)
+
+DEPENDENCIES
+  mygem!
+",
+        );
+        assert_eq!(lockfile.specs.len(), 2);
+        assert_eq!(lockfile.gem_specs, vec!["rack".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_dependencies_round_trip() -> anyhow::Result<()> {
+        let mut resolver = Resolver::new();
+        resolver.add_dependencies("rails".into(), RubyVersion::parse("6.1.7"), vec![]);
+        resolver.add_dependencies("rack".into(), RubyVersion::parse("2.2.8"), vec![]);
+        resolver.add_dependencies("mygem".into(), RubyVersion::parse("0.1.0"), vec![]);
+        let dep = |name: &str, req: &str| {
+            let (req, req_str) = parse_req(req, ",").unwrap();
+            (name.to_string(), req, req_str)
+        };
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![
+                dep("rails", "~> 6.1.3, >= 6.1.3.1"),
+                dep("rack", ">= 0"),
+                dep("mygem", ">= 0"),
+            ],
+        );
+        let solution = resolver.resolve()?;
+        let version = |name: &str| (name.to_string(), solution[name].clone());
+        let sources = BTreeMap::from([
+            (rubygems(), vec![version("rails"), version("rack")]),
+            (
+                LockSource::Path("mygem".to_string()),
+                vec![version("mygem")],
+            ),
+        ]);
+        let mut content = Vec::new();
+        write_lockfile_to(sources, &["ruby".to_string()], &resolver, &mut content).await?;
+
+        let lockfile = parse_lockfile(&String::from_utf8(content)?);
+        let locked = |name: &str, req: &str, pinned: bool| {
+            let (requirement, requirement_str) = parse_req(req, ",").unwrap();
+            LockedDependency {
+                name: name.to_string(),
+                requirement,
+                requirement_str: requirement_str
+                    .into_iter()
+                    .filter(|req| req != ">= 0")
+                    .collect(),
+                pinned,
+            }
+        };
+        assert_eq!(
+            lockfile.dependencies,
+            vec![
+                locked("mygem", ">= 0", true),
+                locked("rack", ">= 0", false),
+                locked("rails", "~> 6.1.3, >= 6.1.3.1", false),
+            ]
+        );
+
+        let gemfile = |deps: &[(&str, &str)]| -> Vec<_> {
+            deps.iter()
+                .map(|(name, req)| {
+                    let (req, req_str) = parse_req(req, ",").unwrap();
+                    (name.to_string(), req, req_str)
+                })
+                .collect()
+        };
+        assert!(
+            lockfile
+                .dependency_changes(&gemfile(&[
+                    ("rails", ">= 6.1.3.1, ~> 6.1.3"),
+                    ("rack", ">= 0"),
+                    ("mygem", ">= 0"),
+                ]))
+                .is_empty()
+        );
+        assert_eq!(
+            lockfile.dependency_changes(&gemfile(&[
+                ("rails", "~> 7.0"),
+                ("rack", ">= 0"),
+                ("thor", ">= 0"),
+            ])),
+            vec![
+                "rails is now ~> 7.0, locked as ~> 6.1.3, >= 6.1.3.1".to_string(),
+                "thor was added to the Gemfile".to_string(),
+                "mygem was removed from the Gemfile".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_sorted_requirements() -> anyhow::Result<()> {
+        let mut resolver = Resolver::new();
+        let (b_req, b_req_str) = parse_req("< 2.0, >= 1.0", ",").unwrap();
+        resolver.add_dependencies(
+            "a".into(),
+            RubyVersion::parse("1.0.0"),
+            vec![("b".into(), b_req, b_req_str)],
+        );
+        resolver.add_dependencies("b".into(), RubyVersion::parse("1.5.0"), vec![]);
+        let (a_req, a_req_str) = parse_req(">= 1.0, < 2.0", ",").unwrap();
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![("a".into(), a_req, a_req_str)],
+        );
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Gemfile.lock");
+        let sources = resolved_on_rubygems(&resolver)?;
+        write_lockfile(sources, &["ruby".to_string()], &resolver, &path).await?;
+
+        let lockfile = std::fs::read_to_string(&path)?;
+        assert!(lockfile.contains("    a (1.0.0)\n      b (>= 1.0, < 2.0)\n"));
+        assert!(lockfile.contains("DEPENDENCIES\n  a (>= 1.0, < 2.0)\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_omits_unconstrained_requirements() -> anyhow::Result<()> {
+        let mut resolver = Resolver::new();
+        let constraint = |name: &str, req: &str| {
+            let (req, req_str) = parse_req(req, ",").unwrap();
+            (name.to_string(), req, req_str)
+        };
+        let forms = [
+            ("a", "*"),
+            ("b", ">= 0"),
+            ("c", ">=0.0"),
+            ("d", "*, >= 0"),
+            ("e", ">= 0, < 2"),
+        ];
+        resolver.add_dependencies(
+            "a".into(),
+            RubyVersion::parse("1.0.0"),
+            forms[1..]
+                .iter()
+                .map(|(name, req)| constraint(name, req))
+                .collect(),
+        );
+        for (name, _) in &forms[1..] {
+            resolver.add_dependencies(name.to_string(), RubyVersion::parse("1.0.0"), vec![]);
+        }
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            forms
+                .iter()
+                .map(|(name, req)| constraint(name, req))
+                .collect(),
+        );
+
+        let mut out = Vec::new();
+        let sources = resolved_on_rubygems(&resolver)?;
+        write_lockfile_to(sources, &["ruby".to_string()], &resolver, &mut out).await?;
+
+        let lockfile = String::from_utf8(out)?;
+        assert!(
+            lockfile.contains("    a (1.0.0)\n      b\n      c\n      d\n      e (>= 0, < 2)\n")
+        );
+        assert!(lockfile.contains("DEPENDENCIES\n  a\n  b\n  c\n  d\n  e (>= 0, < 2)\n"));
+        Ok(())
+    }
+
+    /// `bundle lock` output for the fixture in `test_write_lockfile_golden`.
+    const GOLDEN_LOCKFILE: &str = "GEM
+  remote: https://rubygems.org/
+  specs:
+    base64 (0.2.0)
+    license_finder (7.2.1)
+      bundler
+      rubyzip (>= 1, < 3)
+      thor (~> 1.2)
+    rack (2.2.13)
+    rack-protection (3.2.0)
+      base64 (>= 0.1.0)
+      rack (~> 2.2, >= 2.2.4)
+    rubyzip (2.4.1)
+    thor (1.3.2)
+
+PLATFORMS
+  ruby
+
+DEPENDENCIES
+  license_finder (~> 7.2)
+  rack-protection
+
+BUNDLED WITH
+   2.5.22
+";
+
+    #[tokio::test]
+    async fn test_write_lockfile_golden() -> anyhow::Result<()> {
+        // compact index の info 行と同じ書式
+        let index = [
+            (
+                "root",
+                "0.0.0",
+                "license_finder:~> 7.2,rack-protection:>= 0",
+            ),
+            ("base64", "0.2.0", ""),
+            ("bundler", "2.6.8", ""),
+            (
+                "license_finder",
+                "7.2.1",
+                "bundler:>= 0,rubyzip:< 3&>= 1,thor:~> 1.2",
+            ),
+            ("rack", "2.2.13", ""),
+            (
+                "rack-protection",
+                "3.2.0",
+                "base64:>= 0.1.0,rack:>= 2.2.4&~> 2.2",
+            ),
+            ("rubyzip", "2.4.1", ""),
+            ("thor", "1.3.2", ""),
+        ];
+        let mut resolver = Resolver::new();
+        for (name, version, deps) in index {
+            let deps = deps
+                .split(',')
+                .filter(|dep| !dep.is_empty())
+                .map(|dep| {
+                    let (dep_name, req) = dep.split_once(':').unwrap();
+                    let (req, req_str) = parse_req(req, "&").unwrap();
+                    (dep_name.to_string(), req, req_str)
+                })
+                .collect();
+            resolver.add_dependencies(name.into(), RubyVersion::parse(version), deps);
+        }
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Gemfile.lock");
+        let sources = resolved_on_rubygems(&resolver)?;
+        write_lockfile(sources, &["ruby".to_string()], &resolver, &path).await?;
+
+        assert_eq!(std::fs::read_to_string(&path)?, GOLDEN_LOCKFILE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_matches_bundler() -> anyhow::Result<()> {
+        // compact index の info 行と同じ書式で、順序や空白を崩して渡す
+        let mut resolver = Resolver::new();
+        let deps = [
+            ("rack", ">=2.2.4&~> 2.0"),
+            ("rails-html-sanitizer", ">= 1.2.0&~>1.0"),
+            ("activesupport", "7.0.8.7"),
+            ("rack-test", ">= 0.6.3"),
+        ];
+        resolver.add_dependencies(
+            "actionpack".into(),
+            RubyVersion::parse("7.0.8.7"),
+            deps.iter()
+                .map(|(name, req)| {
+                    let (req, req_str) = parse_req(req, "&").unwrap();
+                    (name.to_string(), req, req_str)
+                })
+                .collect(),
+        );
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Gemfile.lock");
+        let sources = BTreeMap::from([(
+            rubygems(),
+            vec![("actionpack".to_string(), RubyVersion::parse("7.0.8.7"))],
+        )]);
+        write_lockfile(sources, &["ruby".to_string()], &resolver, &path).await?;
+
+        // Gemfile.lock generated by Bundler 2.5.22 for rails 7.0.8.7
+        let lockfile = std::fs::read_to_string(&path)?;
+        assert!(lockfile.contains(
+            "    actionpack (7.0.8.7)
+      activesupport (= 7.0.8.7)
+      rack (~> 2.0, >= 2.2.4)
+      rack-test (>= 0.6.3)
+      rails-html-sanitizer (~> 1.0, >= 1.2.0)
+"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_to_custom_path() -> anyhow::Result<()> {
+        let (sources, resolver) = lock_fixture(&[("rack", "3.0.0")]);
+        let sources = || sources.clone();
+
+        // まだないディレクトリの下にも書ける
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("locks").join("Gemfile.ci.lock");
+        assert!(write_lockfile(sources(), &["ruby".to_string()], &resolver, &path).await?);
+        assert!(!write_lockfile(sources(), &["ruby".to_string()], &resolver, &path).await?);
+
+        let lockfile = read_lockfile(&path).await?;
+        assert_eq!(
+            lockfile.specs,
+            vec![("rack".to_string(), RubyVersion::parse("3.0.0"))]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_multiple_sources() -> anyhow::Result<()> {
+        let mut resolver = Resolver::new();
+        let (b_req, b_req_str) = parse_req(">= 1.0", ",").unwrap();
+        resolver.add_dependencies(
+            "a".into(),
+            RubyVersion::parse("1.0.0"),
+            vec![("b".into(), b_req, b_req_str)],
+        );
+        resolver.add_dependencies("b".into(), RubyVersion::parse("1.5.0"), vec![]);
+        resolver.add_dependencies("c".into(), RubyVersion::parse("2.0.0"), vec![]);
+
+        let sources = BTreeMap::from([
+            (
+                rubygems(),
+                vec![
+                    ("c".to_string(), RubyVersion::parse("2.0.0")),
+                    ("a".to_string(), RubyVersion::parse("1.0.0")),
+                ],
+            ),
+            (
+                LockSource::Gem("https://gems.example.com/".to_string()),
+                vec![("b".to_string(), RubyVersion::parse("1.5.0"))],
+            ),
+        ]);
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Gemfile.lock");
+        write_lockfile(sources, &["ruby".to_string()], &resolver, &path).await?;
+
+        let lockfile = std::fs::read_to_string(&path)?;
+        assert!(lockfile.starts_with(
+            "GEM
+  remote: https://gems.example.com/
+  specs:
+    b (1.5.0)
+
+GEM
+  remote: https://rubygems.org/
+  specs:
+    a (1.0.0)
+      b (>= 1.0)
+    c (2.0.0)
+
+PLATFORMS
+"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_leaves_unchanged_file_alone() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Gemfile.lock");
+        let platforms = ["ruby".to_string()];
+
+        let (sources, resolver) = lock_fixture(&[("a", "1.0.0"), ("b", "2.0.0")]);
+        assert!(write_lockfile(sources, &platforms, &resolver, &path).await?);
+        // Bundler が書いた RUBY VERSION と BUNDLED WITH は残す
+        let original = std::fs::read_to_string(&path)?.replace(
+            "BUNDLED WITH\n   2.5.22\n",
+            "RUBY VERSION\n   ruby 3.3.0p0\n\nBUNDLED WITH\n   2.4.10\n",
+        );
+        std::fs::write(&path, &original)?;
+
+        let (sources, resolver) = lock_fixture(&[("b", "2.0.0"), ("a", "1.0.0")]);
+        assert!(!write_lockfile(sources, &platforms, &resolver, &path).await?);
+        assert_eq!(std::fs::read_to_string(&path)?, original);
+
+        let (sources, resolver) = lock_fixture(&[("a", "1.0.0"), ("b", "2.1.0")]);
+        assert!(write_lockfile(sources, &platforms, &resolver, &path).await?);
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            original.replace("    b (2.0.0)", "    b (2.1.0)")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lockfile_hash() -> anyhow::Result<()> {
+        let hash = |specs: &[(&str, &str)]| {
+            let (sources, resolver) = lock_fixture(specs);
+            async move { lockfile_hash(sources, &["ruby".to_string()], &resolver).await }
+        };
+
+        let a = hash(&[("a", "1.0.0"), ("b", "2.0.0")]).await?;
+        assert_eq!(a.len(), 64);
+        // 順序が違っても同じ解決なら同じハッシュ
+        assert_eq!(a, hash(&[("b", "2.0.0"), ("a", "1.0.0")]).await?);
+        assert_ne!(a, hash(&[("a", "1.0.1"), ("b", "2.0.0")]).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_to_writer() -> anyhow::Result<()> {
+        let (sources, resolver) = lock_fixture(&[("a", "1.0.0")]);
+
+        let mut stdout = Vec::new();
+        write_lockfile_to(sources, &["ruby".to_string()], &resolver, &mut stdout).await?;
+
+        assert_eq!(
+            String::from_utf8(stdout)?,
+            "GEM
+  remote: https://rubygems.org/
+  specs:
+    a (1.0.0)
+
+PLATFORMS
+  ruby
+
+DEPENDENCIES
+  a
+
+BUNDLED WITH
+   2.5.22
+"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_platforms() -> anyhow::Result<()> {
+        let (sources, resolver) = lock_fixture(&[("a", "1.0.0")]);
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Gemfile.lock");
+        let platforms = ["arm64-darwin".to_string(), "x86_64-linux".to_string()];
+        write_lockfile(sources, &platforms, &resolver, &path).await?;
+
+        let lockfile = std::fs::read_to_string(&path)?;
+        assert!(lockfile.contains("PLATFORMS\n  arm64-darwin\n  x86_64-linux\n\nDEPENDENCIES\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_git_source() -> anyhow::Result<()> {
+        let mut resolver = Resolver::new();
+        resolver.add_dependencies("mygem".into(), RubyVersion::parse("0.1.0"), vec![]);
+        let (req, req_str) = parse_req(">= 0", ",").unwrap();
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![("mygem".into(), req, req_str)],
+        );
+        let sources = BTreeMap::from([(
+            LockSource::Git {
+                remote: "https://github.com/me/mygem.git".to_string(),
+                revision: "0123456789abcdef0123456789abcdef01234567".to_string(),
+                reference: Some(GitReference::Branch("main".to_string())),
+            },
+            vec![("mygem".to_string(), RubyVersion::parse("0.1.0"))],
+        )]);
+
+        let mut content = Vec::new();
+        write_lockfile_to(sources, &["ruby".to_string()], &resolver, &mut content).await?;
+        assert_eq!(
+            String::from_utf8(content)?,
+            "GIT
+  remote: https://github.com/me/mygem.git
+  revision: 0123456789abcdef0123456789abcdef01234567
+  branch: main
+  specs:
+    mygem (0.1.0)
+
+PLATFORMS
+  ruby
+
+DEPENDENCIES
+  mygem!
+
+BUNDLED WITH
+   2.5.22
+"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lockfile_path_source() -> anyhow::Result<()> {
+        let mut resolver = Resolver::new();
+        let (rack_req, rack_req_str) = parse_req("~> 2.0", ",").unwrap();
+        resolver.add_dependencies(
+            "mylib".into(),
+            RubyVersion::parse("0.3.1"),
+            vec![("rack".into(), rack_req, rack_req_str)],
+        );
+        resolver.add_dependencies("rack".into(), RubyVersion::parse("2.2.8"), vec![]);
+        let (req, req_str) = parse_req(">= 0", ",").unwrap();
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![("mylib".into(), req, req_str)],
+        );
+
+        let sources = BTreeMap::from([
+            (
+                rubygems(),
+                vec![("rack".to_string(), RubyVersion::parse("2.2.8"))],
+            ),
+            (
+                LockSource::Path("../mylib".to_string()),
+                vec![("mylib".to_string(), RubyVersion::parse("0.3.1"))],
+            ),
+        ]);
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Gemfile.lock");
+        write_lockfile(sources, &["ruby".to_string()], &resolver, &path).await?;
+
+        let lockfile = std::fs::read_to_string(&path)?;
+        assert!(lockfile.starts_with(
+            "PATH
+  remote: ../mylib
+  specs:
+    mylib (0.3.1)
+      rack (~> 2.0)
+
+GEM
+  remote: https://rubygems.org/
+  specs:
+    rack (2.2.8)
+
+"
+        ));
+        assert!(lockfile.contains("DEPENDENCIES\n  mylib!\n"));
+        Ok(())
+    }
+}
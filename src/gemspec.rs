@@ -0,0 +1,261 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::version::RubyVersion;
+
+lazy_static! {
+    static ref NAME_RE: Regex = Regex::new(r#"(?m)^\s*\w+\.name\s*=\s*["']([^"']+)["']"#).unwrap();
+    static ref VERSION_RE: Regex =
+        Regex::new(r#"(?m)^\s*\w+\.version\s*=\s*["']([^"']+)["']"#).unwrap();
+    static ref VERSION_CONST_RE: Regex =
+        Regex::new(r#"(?m)^\s*VERSION\s*=\s*["']([^"']+)["']"#).unwrap();
+    static ref DEPENDENCY_RE: Regex = Regex::new(
+        r#"(?m)^\s*\w+\.add_(?:runtime_)?dependency[\s(]+["']([^"']+)["']((?:\s*,\s*["'][^"']+["'])*)"#
+    )
+    .unwrap();
+    static ref REQUIREMENT_RE: Regex = Regex::new(r#"["']([^"']+)["']"#).unwrap();
+}
+
+/// What a `path:` gem's `.gemspec` declares, read without evaluating Ruby.
+///
+/// Only literal strings are understood. A version taken from a constant is
+/// looked up as `VERSION = "..."` in `lib/<name>/version.rb`.
+#[derive(Debug, PartialEq)]
+pub struct Gemspec {
+    pub name: String,
+    pub version: RubyVersion,
+    /// Runtime dependencies with their requirement comparators.
+    pub dependencies: Vec<(String, Vec<String>)>,
+}
+
+impl Gemspec {
+    /// Loads `<dir>/<name>.gemspec`, or the only `*.gemspec` in `dir`.
+    pub fn load(dir: &Path, name: &str) -> anyhow::Result<Self> {
+        let file = Self::find(dir, name)?;
+        let text = fs::read_to_string(&file)
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        let name = match NAME_RE.captures(&text) {
+            Some(caps) => caps[1].to_string(),
+            None => bail!("{} does not set a literal name", file.display()),
+        };
+        let version = match VERSION_RE.captures(&text) {
+            Some(caps) => caps[1].to_string(),
+            None => {
+                let version_rb = dir.join("lib").join(&name).join("version.rb");
+                fs::read_to_string(&version_rb)
+                    .ok()
+                    .and_then(|text| Some(VERSION_CONST_RE.captures(&text)?[1].to_string()))
+                    .with_context(|| {
+                        format!("Could not determine the version of {}", file.display())
+                    })?
+            }
+        };
+        let dependencies = DEPENDENCY_RE
+            .captures_iter(&text)
+            .map(|caps| {
+                let mut requirements: Vec<String> = REQUIREMENT_RE
+                    .captures_iter(&caps[2])
+                    .map(|req| req[1].to_string())
+                    .collect();
+                if requirements.is_empty() {
+                    requirements.push(">= 0".to_string());
+                }
+                (caps[1].to_string(), requirements)
+            })
+            .collect();
+        Ok(Self {
+            name,
+            version: RubyVersion::parse(&version),
+            dependencies,
+        })
+    }
+
+    fn find(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+        let file = dir.join(format!("{}.gemspec", name));
+        if file.exists() {
+            return Ok(file);
+        }
+        let mut gemspecs = Vec::new();
+        for entry in
+            fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "gemspec") {
+                gemspecs.push(path);
+            }
+        }
+        match gemspecs.len() {
+            1 => Ok(gemspecs.remove(0)),
+            0 => bail!("Could not find a gemspec for {} in {}", name, dir.display()),
+            _ => bail!("{} has more than one gemspec", dir.display()),
+        }
+    }
+}
+
+/// The parts of a gem's YAML `metadata` that `metadata_dependencies` reads.
+/// The `!ruby/object:...` tags `Gem::Specification#to_yaml` writes are ignored.
+#[derive(Deserialize)]
+struct Metadata {
+    #[serde(default)]
+    dependencies: Vec<MetadataDependency>,
+}
+
+#[derive(Deserialize)]
+struct MetadataDependency {
+    name: String,
+    // requirement: と version_requirements: は同じ内容なので、前者だけを読む
+    requirement: MetadataRequirement,
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MetadataRequirement {
+    requirements: Vec<(String, MetadataVersion)>,
+}
+
+#[derive(Deserialize)]
+struct MetadataVersion {
+    // '2.0' は文字列でも、7 のように引用符なしだと数値になる
+    version: serde_yaml::Value,
+}
+
+/// Runtime dependencies in the YAML `metadata` of a packaged gem (its
+/// decompressed `metadata.gz`), with their requirement comparators.
+pub fn metadata_dependencies(yaml: &str) -> serde_yaml::Result<Vec<(String, Vec<String>)>> {
+    let metadata: Metadata = serde_yaml::from_str(yaml)?;
+    Ok(metadata
+        .dependencies
+        .into_iter()
+        // type: のない古い gem の依存は runtime
+        .filter(|dependency| {
+            dependency
+                .kind
+                .as_deref()
+                .is_none_or(|kind| kind == ":runtime")
+        })
+        .map(|dependency| {
+            let requirements = dependency
+                .requirement
+                .requirements
+                .into_iter()
+                .filter_map(|(op, version)| {
+                    let version = match version.version {
+                        serde_yaml::Value::String(version) => version,
+                        serde_yaml::Value::Number(version) => version.to_string(),
+                        _ => return None,
+                    };
+                    Some(format!("{} {}", op, version))
+                })
+                .collect();
+            (dependency.name, requirements)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gemspec::{Gemspec, metadata_dependencies},
+        version::RubyVersion,
+    };
+
+    #[test]
+    fn test_metadata_dependencies() {
+        let yaml = r#"--- !ruby/object:Gem::Specification
+name: actionpack
+version: !ruby/object:Gem::Version
+  version: 7.0.8.7
+dependencies:
+- !ruby/object:Gem::Dependency
+  name: rack
+  requirement: !ruby/object:Gem::Requirement
+    requirements:
+    - - "~>"
+      - !ruby/object:Gem::Version
+        version: '2.0'
+    - - ">="
+      - !ruby/object:Gem::Version
+        version: 2.2.4
+  type: :runtime
+  prerelease: false
+  version_requirements: !ruby/object:Gem::Requirement
+    requirements:
+    - - "~>"
+      - !ruby/object:Gem::Version
+        version: '2.0'
+    - - ">="
+      - !ruby/object:Gem::Version
+        version: 2.2.4
+- !ruby/object:Gem::Dependency
+  name: rspec
+  requirement: !ruby/object:Gem::Requirement
+    requirements:
+    - - ">="
+      - !ruby/object:Gem::Version
+        version: '0'
+  type: :development
+  prerelease: false
+description:
+email:
+"#;
+        assert_eq!(
+            metadata_dependencies(yaml).unwrap(),
+            vec![(
+                "rack".to_string(),
+                vec!["~> 2.0".to_string(), ">= 2.2.4".to_string()]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_load_gemspec() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("mylib.gemspec"),
+            r#"require_relative "lib/mylib/version"
+
+Gem::Specification.new do |spec|
+  spec.name          = "mylib"
+  spec.version       = Mylib::VERSION
+  spec.summary       = "A library"
+
+  spec.add_dependency "rack", "~> 2.0", ">= 2.0.1"
+  spec.add_runtime_dependency('thor')
+  spec.add_development_dependency "rspec", "~> 3.0"
+end
+"#,
+        )?;
+        std::fs::create_dir_all(dir.path().join("lib/mylib"))?;
+        std::fs::write(
+            dir.path().join("lib/mylib/version.rb"),
+            "module Mylib\n  VERSION = \"0.3.1\"\nend\n",
+        )?;
+
+        let spec = Gemspec::load(dir.path(), "mylib")?;
+        assert_eq!(
+            spec,
+            Gemspec {
+                name: "mylib".to_string(),
+                version: RubyVersion::parse("0.3.1"),
+                dependencies: vec![
+                    (
+                        "rack".to_string(),
+                        vec!["~> 2.0".to_string(), ">= 2.0.1".to_string()]
+                    ),
+                    ("thor".to_string(), vec![">= 0".to_string()]),
+                ],
+            }
+        );
+
+        assert!(Gemspec::load(&dir.path().join("lib"), "mylib").is_err());
+        Ok(())
+    }
+}
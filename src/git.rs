@@ -0,0 +1,164 @@
+use std::{
+    io,
+    path::Path,
+    process::{Command, Output},
+};
+
+use thiserror::Error;
+use tracing::debug;
+
+/// Which commit of a `git:` gem to use, from the `branch:`, `tag:` or `ref:`
+/// option. Without one, the remote's default branch is used.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GitReference {
+    Branch(String),
+    Tag(String),
+    Ref(String),
+}
+
+impl GitReference {
+    /// The line Bundler writes under `revision:` in a `GIT` section, e.g. `branch: main`.
+    pub fn lock_line(&self) -> String {
+        match self {
+            GitReference::Branch(branch) => format!("branch: {}", branch),
+            GitReference::Tag(tag) => format!("tag: {}", tag),
+            GitReference::Ref(reference) => format!("ref: {}", reference),
+        }
+    }
+
+    // checkout に渡すリビジョン。ブランチは fetch したリモートのものを使う
+    fn revision(&self) -> String {
+        match self {
+            GitReference::Branch(branch) => format!("origin/{}", branch),
+            GitReference::Tag(tag) => format!("refs/tags/{}^{{commit}}", tag),
+            GitReference::Ref(reference) => format!("{}^{{commit}}", reference),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum GitError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("git {args} failed: {stderr}")]
+    Command { args: String, stderr: String },
+}
+
+/// Clones `remote` into `dest`, or fetches into an earlier clone there, and
+/// checks out `reference`. Returns the SHA of the commit checked out, which
+/// the lockfile records as the `revision:`.
+pub fn checkout(
+    remote: &str,
+    reference: Option<&GitReference>,
+    dest: &Path,
+) -> Result<String, GitError> {
+    if dest.join(".git").exists() {
+        git(dest, &["fetch", "--quiet", "--tags", "--force", "origin"])?;
+    } else {
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let dest = dest.to_string_lossy();
+        git(
+            Path::new("."),
+            &["clone", "--quiet", "--no-checkout", remote, &dest],
+        )?;
+    }
+    let revision = reference
+        .map(GitReference::revision)
+        .unwrap_or_else(|| "origin/HEAD".to_string());
+    git(dest, &["checkout", "--quiet", "--detach", &revision])?;
+    let output = git(dest, &["rev-parse", "HEAD"])?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<Output, GitError> {
+    debug!("Running git {} in {}", args.join(" "), dir.display());
+    let output = Command::new("git").current_dir(dir).args(args).output()?;
+    if !output.status.success() {
+        return Err(GitError::Command {
+            args: args.join(" "),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::git::{GitReference, checkout, git};
+
+    // コミットを作って、そのハッシュを返す
+    fn commit(repo: &Path, message: &str) -> String {
+        git(
+            repo,
+            &[
+                "-c",
+                "user.name=bundle",
+                "-c",
+                "user.email=bundle@example.com",
+                "commit",
+                "--quiet",
+                "--allow-empty",
+                "-m",
+                message,
+            ],
+        )
+        .unwrap();
+        let output = git(repo, &["rev-parse", "HEAD"]).unwrap();
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    #[test]
+    fn test_checkout() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let remote = dir.path().join("mygem.git");
+        let work = dir.path().join("work");
+        std::fs::create_dir(&remote)?;
+        git(
+            &remote,
+            &["init", "--quiet", "--bare", "--initial-branch=main"],
+        )?;
+        git(dir.path(), &["clone", "--quiet", "mygem.git", "work"])?;
+        git(&work, &["checkout", "--quiet", "-b", "main"])?;
+
+        let tagged = commit(&work, "v1.0");
+        git(&work, &["tag", "v1.0"])?;
+        let main = commit(&work, "main");
+        git(&work, &["checkout", "--quiet", "-b", "feature"])?;
+        let feature = commit(&work, "feature");
+        git(
+            &work,
+            &["push", "--quiet", "--tags", "origin", "main", "feature"],
+        )?;
+
+        let remote = remote.to_string_lossy();
+        let dest = dir.path().join("checkout");
+        assert_eq!(checkout(&remote, None, &dest)?, main);
+        assert_eq!(
+            checkout(&remote, Some(&GitReference::Tag("v1.0".into())), &dest)?,
+            tagged
+        );
+        assert_eq!(
+            checkout(
+                &remote,
+                Some(&GitReference::Branch("feature".into())),
+                &dest
+            )?,
+            feature
+        );
+        assert_eq!(
+            checkout(
+                &remote,
+                Some(&GitReference::Ref(tagged[..10].into())),
+                &dest
+            )?,
+            tagged
+        );
+        assert!(checkout(&remote, Some(&GitReference::Tag("v2.0".into())), &dest).is_err());
+        Ok(())
+    }
+}
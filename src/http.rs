@@ -0,0 +1,288 @@
+use std::time::Duration;
+
+/// Network settings shared by the compact index client and the gem downloader.
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    pub connect_timeout: Duration,
+    /// How long to wait for the response, or for the next bytes of its body.
+    /// A slow but steady transfer is not cut off.
+    pub timeout: Duration,
+    /// Proxy for every request. Without it `HTTP_PROXY`, `HTTPS_PROXY` and
+    /// `NO_PROXY` from the environment are used.
+    pub proxy: Option<String>,
+    /// How many times a request that failed with a timeout, a connection error
+    /// or a 5xx/429 response is retried.
+    pub retries: usize,
+    /// Sent as `User-Agent` with every request.
+    pub user_agent: String,
+    pub http_version: HttpVersion,
+    /// How long an idle connection is kept open for reuse.
+    pub pool_idle_timeout: Duration,
+    /// How many gems are fetched from a source at a time, one per CPU by
+    /// default. With 1 they are fetched one by one in the order they were
+    /// found, so logs and failures are the same on every run.
+    pub jobs: usize,
+}
+
+/// Which HTTP version to speak with gem sources.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/2 when the server offers it in the TLS handshake, HTTP/1.1 otherwise.
+    #[default]
+    Auto,
+    /// Only HTTP/1.1, for servers that misbehave with HTTP/2.
+    Http1,
+    /// HTTP/2 without negotiating it, e.g. for a cleartext mirror known to speak it.
+    Http2,
+}
+
+/// `bundle-rs/<version> (<arch>-<os>)`, the `User-Agent` used unless
+/// `BUNDLE_USER_AGENT` sets another.
+pub fn default_user_agent() -> String {
+    format!(
+        "bundle-rs/{} ({}-{})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    )
+}
+
+/// The wait before the first retry; it doubles with every further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+
+/// Upper bound on the wait before a retry, whether backing off or asked to by
+/// a server's `Retry-After`.
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// How long to wait before retry number `attempt` (starting at 0).
+pub fn backoff(attempt: usize) -> Duration {
+    RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt as u32))
+        .min(MAX_RETRY_AFTER)
+}
+
+/// Whether a failed request is worth sending again.
+pub fn is_retryable(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect()
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(30),
+            timeout: Duration::from_secs(30),
+            proxy: None,
+            retries: 3,
+            user_agent: default_user_agent(),
+            http_version: HttpVersion::Auto,
+            pool_idle_timeout: Duration::from_secs(90),
+            jobs: num_cpus::get(),
+        }
+    }
+}
+
+impl HttpOptions {
+    pub fn client_builder(&self) -> reqwest::Result<reqwest::ClientBuilder> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.timeout)
+            .user_agent(&self.user_agent)
+            .pool_idle_timeout(self.pool_idle_timeout);
+        // HTTP/2 では数百の info を 1 本の接続に多重化するので、ウィンドウを帯域に合わせて広げる
+        builder = match self.http_version {
+            HttpVersion::Auto => builder.http2_adaptive_window(true),
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge().http2_adaptive_window(true),
+        };
+        // reqwest は環境変数のプロキシを既定で使うので、明示されたときだけ上書きする
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        Ok(builder)
+    }
+
+    pub fn blocking_client_builder(&self) -> reqwest::Result<reqwest::blocking::ClientBuilder> {
+        // blocking の timeout はレスポンス待ちと本文の 1 回ごとの read に掛かるので、
+        // 転送全体の上限にはならない
+        let mut builder = reqwest::blocking::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout)
+            .user_agent(&self.user_agent)
+            .pool_idle_timeout(self.pool_idle_timeout);
+        builder = match self.http_version {
+            HttpVersion::Auto => builder,
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+        };
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{header, method, path},
+    };
+
+    use std::time::Duration;
+
+    use crate::http::{HttpOptions, HttpVersion, MAX_RETRY_AFTER, backoff, default_user_agent};
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_millis(200));
+        assert_eq!(backoff(2), Duration::from_millis(800));
+        assert_eq!(backoff(10), MAX_RETRY_AFTER);
+        assert_eq!(backoff(usize::MAX), MAX_RETRY_AFTER);
+    }
+
+    #[tokio::test]
+    async fn test_user_agent() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("user-agent", default_user_agent().as_str()))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(header("user-agent", "my-mirror-client/1.0"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        assert!(default_user_agent().starts_with("bundle-rs/0."));
+
+        let client = HttpOptions::default().client_builder()?.build()?;
+        let status = client.get(server.uri()).send().await?.status();
+        assert!(status.is_success());
+
+        let options = HttpOptions {
+            user_agent: "my-mirror-client/1.0".to_string(),
+            ..HttpOptions::default()
+        };
+        let url = server.uri();
+        let status = tokio::task::spawn_blocking(move || {
+            let client = options.blocking_client_builder()?.build()?;
+            Ok::<_, reqwest::Error>(client.get(url).send()?.status())
+        })
+        .await??;
+        assert!(status.is_success());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_explicit_proxy_is_used() -> anyhow::Result<()> {
+        // プロキシ役のサーバーに、存在しないホスト宛てのリクエストが届くこと
+        let proxy = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("via proxy"))
+            .expect(2)
+            .mount(&proxy)
+            .await;
+        let options = HttpOptions {
+            proxy: Some(proxy.uri()),
+            ..HttpOptions::default()
+        };
+
+        let client = options.client_builder()?.build()?;
+        let body = client
+            .get("http://gems.invalid/versions")
+            .send()
+            .await?
+            .text()
+            .await?;
+        assert_eq!(body, "via proxy");
+
+        let body = tokio::task::spawn_blocking(move || {
+            let client = options.blocking_client_builder()?.build()?;
+            client.get("http://gems.invalid/versions").send()?.text()
+        })
+        .await??;
+        assert_eq!(body, "via proxy");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_version() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let version = |http_version| {
+            let url = server.uri();
+            async move {
+                let options = HttpOptions {
+                    http_version,
+                    ..HttpOptions::default()
+                };
+                let client = options.client_builder()?.build()?;
+                anyhow::Ok(client.get(url).send().await?.version())
+            }
+        };
+
+        // 平文では ALPN がないので、Auto は HTTP/1.1 のまま
+        assert_eq!(version(HttpVersion::Auto).await?, reqwest::Version::HTTP_11);
+        assert_eq!(
+            version(HttpVersion::Http1).await?,
+            reqwest::Version::HTTP_11
+        );
+        assert_eq!(version(HttpVersion::Http2).await?, reqwest::Version::HTTP_2);
+        Ok(())
+    }
+
+    /// 本文を `chunks` 回に分けて `interval` おきに送るだけのサーバー
+    fn trickle_server(chunks: usize, interval: Duration) -> std::io::Result<String> {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/", listener.local_addr()?);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {chunks}\r\nconnection: close\r\n\r\n"
+                );
+                let _ = stream.write_all(head.as_bytes());
+                for _ in 0..chunks {
+                    std::thread::sleep(interval);
+                    let _ = stream.write_all(b"x");
+                }
+            }
+        });
+        Ok(url)
+    }
+
+    #[tokio::test]
+    async fn test_timeout_is_not_a_total_cap() -> anyhow::Result<()> {
+        // 全体では timeout を超えても、データが流れ続けている限り打ち切らない
+        let url = trickle_server(6, Duration::from_millis(100))?;
+        let options = HttpOptions {
+            timeout: Duration::from_millis(300),
+            ..HttpOptions::default()
+        };
+
+        let client = options.client_builder()?.build()?;
+        let body = client.get(&url).send().await?.text().await?;
+        assert_eq!(body, "xxxxxx");
+
+        // gem のダウンロードと同じく、本文は Read で少しずつ読む
+        let body = tokio::task::spawn_blocking(move || {
+            let client = options.blocking_client_builder()?.build()?;
+            let mut body = Vec::new();
+            client.get(url).send()?.copy_to(&mut body)?;
+            anyhow::Ok(body)
+        })
+        .await??;
+        assert_eq!(body, b"xxxxxx");
+        Ok(())
+    }
+}
@@ -1,11 +1,20 @@
 // src/installer.rs
-use crate::compact_index_client::GemVersion;
-use std::collections::HashMap;
+use crate::compact_index_client::{GemDependency, GemVersion};
+use crate::gemfilelock::sort_requirements;
+use crate::gemspec::metadata_dependencies;
+use crate::http::{HttpOptions, backoff, is_retryable};
+use crate::output::status;
+use crate::platform::platform_matches;
+use crate::version::parse_req;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::OnceLock;
 use thiserror::Error;
+use tracing::{debug, warn};
+use url::Url;
 
 #[derive(Error, Debug)]
 pub enum InstallerError {
@@ -21,86 +30,386 @@ pub enum InstallerError {
     #[error("Gem extraction error: {0}")]
     Extraction(String),
 
+    #[error("{0}.gem is not in vendor/cache or the gem cache; run without --local to download it")]
+    NotCached(String),
+
+    #[error("Could not install these gems:\n  {}", .0.join("\n  "))]
+    Failed(Vec<String>),
+
     #[error("Other error: {0}")]
     Other(String),
 }
 
 pub type Result<T> = std::result::Result<T, InstallerError>;
 
+/// What [`GemInstaller::install_gems`] did with one gem.
+#[derive(Debug, PartialEq)]
+pub enum InstallOutcome {
+    /// Installed now, or in a dry run, would be.
+    Installed,
+    AlreadyInstalled,
+}
+
+/// What [`GemInstaller::install_gems`] did. In a dry run, `installed` counts
+/// the gems that would be installed.
+#[derive(Debug, Default, PartialEq)]
+pub struct InstallSummary {
+    pub installed: usize,
+    pub already_installed: usize,
+}
+
+impl InstallSummary {
+    /// Counts the gems of `outcomes` that were installed or already present;
+    /// failed gems are not counted.
+    pub fn new(outcomes: &[(String, Result<InstallOutcome>)]) -> Self {
+        let mut summary = Self::default();
+        for (_, outcome) in outcomes {
+            match outcome {
+                Ok(InstallOutcome::Installed) => summary.installed += 1,
+                Ok(InstallOutcome::AlreadyInstalled) => summary.already_installed += 1,
+                Err(_) => {}
+            }
+        }
+        summary
+    }
+}
+
+/// The running Ruby's `RUBY_VERSION` and `RUBY_PLATFORM`.
+#[derive(Debug, PartialEq)]
+struct RubyInfo {
+    version: String,
+    platform: String,
+}
+
+impl RubyInfo {
+    // 起動が遅いので、両方を 1 回の ruby で聞く
+    fn detect(ruby: &Path) -> Result<Self> {
+        let output = Command::new(ruby)
+            .args(["-e", "puts RUBY_VERSION; puts RUBY_PLATFORM"])
+            .output()?;
+
+        if !output.status.success() {
+            return Err(InstallerError::Command(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines().map(str::trim);
+        match (lines.next(), lines.next()) {
+            (Some(version), Some(platform)) => Ok(Self {
+                version: version.to_string(),
+                platform: platform.to_string(),
+            }),
+            _ => Err(InstallerError::Command(format!(
+                "unexpected output from {}: {}",
+                ruby.display(),
+                stdout.trim()
+            ))),
+        }
+    }
+
+    /// Runs `ruby` only the first time; later calls return what it said then.
+    /// A failure is not remembered, so the next call tries again.
+    fn cached<'a>(cell: &'a OnceLock<Self>, ruby: &Path) -> Result<&'a Self> {
+        if let Some(info) = cell.get() {
+            return Ok(info);
+        }
+        let info = Self::detect(ruby)?;
+        Ok(cell.get_or_init(|| info))
+    }
+}
+
+fn running_ruby() -> Result<&'static RubyInfo> {
+    static RUBY: OnceLock<RubyInfo> = OnceLock::new();
+    RubyInfo::cached(&RUBY, Path::new("ruby"))
+}
+
 pub struct GemInstaller {
     install_base_dir: PathBuf,
     cache_dir: PathBuf,
-    base_url: String,
+    http_options: HttpOptions,
     // Ruby version for paths
     ruby_version: String,
+    // 拡張のビルド先と、ビルド済み gem の選択に使う RUBY_PLATFORM
+    platform: String,
+    // --path で指定されたときは Bundler と同じ <path>/ruby/<version> に入れる
+    bundle_path: Option<PathBuf>,
+    // ダウンロードする前に探す、プロジェクトの vendor/cache
+    vendor_cache: Option<PathBuf>,
+    // --local のときはダウンロードせず、キャッシュにない gem はエラーにする
+    local: bool,
+    // --continue-on-error のときは、失敗した gem を飛ばして残りを入れる
+    continue_on_error: bool,
 }
 
 impl GemInstaller {
-    pub fn new(install_base_dir: &Path, cache_dir: &Path, base_url: &str) -> Result<Self> {
-        // Ruby のバージョンを取得
-        let ruby_version = Self::get_ruby_version()?;
-
-        // ディレクトリ構造を作成
-        let full_install_dir = install_base_dir.join("gems").join(&ruby_version);
-        fs::create_dir_all(&full_install_dir.join("gems"))?;
-        fs::create_dir_all(&full_install_dir.join("specifications"))?;
-        fs::create_dir_all(&full_install_dir.join("extensions"))?;
-        fs::create_dir_all(&full_install_dir.join("bin"))?;
-
-        fs::create_dir_all(cache_dir)?;
+    pub fn new(
+        install_base_dir: &Path,
+        cache_dir: &Path,
+        http_options: HttpOptions,
+    ) -> Result<Self> {
+        // Ruby のバージョンとプラットフォームを取得
+        let ruby = running_ruby()?;
 
         Ok(Self {
             install_base_dir: install_base_dir.to_path_buf(),
             cache_dir: cache_dir.to_path_buf(),
-            base_url: base_url.to_string(),
-            ruby_version,
+            http_options,
+            ruby_version: ruby.version.clone(),
+            platform: ruby.platform.clone(),
+            bundle_path: None,
+            vendor_cache: None,
+            local: false,
+            continue_on_error: false,
         })
     }
 
+    /// Installs into `<path>/ruby/<ruby version>` like `bundle install --path`.
+    pub fn set_bundle_path(&mut self, path: &Path) {
+        self.bundle_path = Some(path.to_path_buf());
+    }
+
+    /// Looks for `<name>-<version>.gem` in `dir` (usually `vendor/cache`) before downloading it.
+    pub fn set_vendor_cache(&mut self, dir: &Path) {
+        self.vendor_cache = Some(dir.to_path_buf());
+    }
+
+    /// Fails instead of downloading a gem that is in neither cache.
+    pub fn set_local(&mut self, local: bool) {
+        self.local = local;
+    }
+
+    /// Makes [`install_gems`](Self::install_gems) go on past a gem that fails
+    /// to install instead of stopping there.
+    pub fn set_continue_on_error(&mut self, continue_on_error: bool) {
+        self.continue_on_error = continue_on_error;
+    }
+
     // Rubyのバージョンを取得
-    fn get_ruby_version() -> Result<String> {
-        let output = Command::new("ruby")
-            .args(&["-e", "puts RUBY_VERSION"])
-            .output()?;
+    pub fn get_ruby_version() -> Result<String> {
+        Ok(running_ruby()?.version.clone())
+    }
 
-        if !output.status.success() {
-            return Err(InstallerError::Command(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+    // ディレクトリ構造を作成
+    fn create_dirs(&self) -> Result<()> {
+        let full_install_dir = self.get_gems_base_dir();
+        fs::create_dir_all(full_install_dir.join("gems"))?;
+        fs::create_dir_all(full_install_dir.join("specifications"))?;
+        fs::create_dir_all(full_install_dir.join("extensions"))?;
+        fs::create_dir_all(full_install_dir.join("bin"))?;
+
+        fs::create_dir_all(&self.cache_dir)?;
+        Ok(())
+    }
+
+    /// Installs every resolved gem, downloading it from the source it was
+    /// resolved from, and returns what happened to each, in name order. The first failure ends the run and is the last entry, unless
+    /// [`set_continue_on_error`](Self::set_continue_on_error) is set. With
+    /// `dry_run` nothing is downloaded, extracted or built; the planned
+    /// actions are printed instead.
+    pub fn install_gems(
+        &self,
+        resolved_gems: HashMap<String, (Url, GemVersion)>,
+        dry_run: bool,
+    ) -> Result<Vec<(String, Result<InstallOutcome>)>> {
+        if !dry_run {
+            self.create_dirs()?;
+        }
+
+        let mut outcomes = Vec::new();
+        let mut resolved_gems: Vec<_> = resolved_gems.into_iter().collect();
+        resolved_gems.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, (source, version)) in resolved_gems {
+            let outcome = self
+                .install_gem(
+                    &source,
+                    &name,
+                    &version.version.to_string(),
+                    &version.dependencies,
+                    dry_run,
+                )
+                .map(|installed| {
+                    if installed {
+                        InstallOutcome::Installed
+                    } else {
+                        InstallOutcome::AlreadyInstalled
+                    }
+                });
+            let failed = outcome.is_err();
+            outcomes.push((name, outcome));
+            // 続けないときは、失敗した gem を最後の要素にして返す
+            if failed && !self.continue_on_error {
+                break;
+            }
         }
 
-        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        Ok(version)
+        Ok(outcomes)
     }
 
-    pub fn install_gems(&self, resolved_gems: HashMap<String, GemVersion>) -> Result<()> {
-        for (name, version) in resolved_gems {
-            self.install_gem(&name, &version.version.to_string())?;
+    /// Writes `bundler/setup.rb` next to the `ruby` directory of the bundle
+    /// path, like `bundle install --standalone`. Requiring it puts the `lib`
+    /// directory and built extensions of every gem in `gems` on `$LOAD_PATH`,
+    /// so the app runs without Bundler. Returns the path written.
+    pub fn write_standalone_setup(&self, gems: &HashMap<String, GemVersion>) -> Result<PathBuf> {
+        let base_dir = self.get_gems_base_dir();
+        // bundle/ruby/3.3.0 なら bundle/bundler/setup.rb に書き、そこからの相対パスで指す
+        let root = base_dir
+            .parent()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new("."));
+        let relative = |path: &Path| {
+            let path = path.strip_prefix(root).unwrap_or(path);
+            format!("../{}", path.to_string_lossy())
+        };
+
+        let mut gems: Vec<_> = gems.iter().collect();
+        gems.sort_by(|a, b| a.0.cmp(b.0));
+        let mut content = String::from("# Generated by bundle install --standalone\n");
+        for (name, version) in gems {
+            let full_name = format!("{}-{}", name, version.version);
+            let mut load_paths = vec![self.get_gems_dir().join(&full_name).join("lib")];
+            // extensions/<platform>/<name-version> はビルドした gem にだけある
+            if let Ok(platforms) = fs::read_dir(self.get_extensions_dir()) {
+                load_paths.extend(
+                    platforms
+                        .filter_map(|entry| entry.ok().map(|entry| entry.path().join(&full_name)))
+                        .filter(|path| path.is_dir()),
+                );
+            }
+            for path in load_paths {
+                content.push_str(&format!(
+                    "$:.unshift File.expand_path({:?}, __dir__)\n",
+                    relative(&path)
+                ));
+            }
         }
 
-        Ok(())
+        let path = root.join("bundler").join("setup.rb");
+        fs::create_dir_all(root.join("bundler"))?;
+        fs::write(&path, content)?;
+        Ok(path)
     }
 
-    fn install_gem(&self, name: &str, version: &str) -> Result<()> {
-        let gem_filename = format!("{}-{}.gem", name, version);
-        let cache_path = self.cache_dir.join(&gem_filename);
+    /// Removes installed gems whose `name-version` is not in `keep`, returning
+    /// the removed names sorted. With `dry_run` nothing is deleted. Downloaded
+    /// `.gem` files are left alone, since the gem cache is shared by every bundle.
+    pub fn clean(&self, keep: &HashSet<String>, dry_run: bool) -> Result<Vec<String>> {
+        let dirs = [
+            (self.get_gems_dir(), ""),
+            (self.get_specifications_dir(), ".gemspec"),
+        ];
+        let mut removed = BTreeSet::new();
+        for (dir, suffix) in dirs {
+            if !dir.exists() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                let Some(full_name) = file_name.strip_suffix(suffix) else {
+                    continue;
+                };
+                // ロックされている gem は新しいバージョンがあっても残す
+                if is_kept(keep, full_name) {
+                    continue;
+                }
+                removed.insert(full_name.to_string());
+                if dry_run {
+                    continue;
+                }
+                if path.is_dir() {
+                    fs::remove_dir_all(&path)?;
+                } else {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(removed.into_iter().collect())
+    }
 
+    fn install_gem(
+        &self,
+        source: &Url,
+        name: &str,
+        version: &str,
+        dependencies: &[GemDependency],
+        dry_run: bool,
+    ) -> Result<bool> {
         // すでにインストールされているかチェック
         if self.is_gem_installed(name, version)? {
-            println!("Gem {} ({}) is already installed", name, version);
-            return Ok(());
+            status!("Gem {} ({}) is already installed", name, version);
+            return Ok(false);
         }
 
-        // キャッシュになければダウンロード
-        if !cache_path.exists() {
-            self.download_gem(name, version, &cache_path)?;
+        let cached = self.cached_gem(name, version);
+        if cached.is_none() && self.local {
+            return Err(InstallerError::NotCached(format!("{}-{}", name, version)));
         }
 
+        if dry_run {
+            // Whether an uncached gem has native extensions is only known after downloading it.
+            match &cached {
+                None => status!("Would download {} ({})", name, version),
+                Some(path) if Self::has_extensions(path)? => {
+                    status!("Would build native extensions for {} ({})", name, version)
+                }
+                Some(_) => status!("Would install {} ({}) from cache", name, version),
+            }
+            return Ok(true);
+        }
+
+        // キャッシュになければダウンロード
+        let cache_path = match cached {
+            Some(path) => path,
+            None => {
+                let path = self.cache_dir.join(format!("{}-{}.gem", name, version));
+                self.download_gem(source, name, version, &path)?;
+                path
+            }
+        };
+
         // gemを解凍してインストール
-        self.extract_and_install_gem(name, version, &cache_path)?;
+        self.extract_and_install_gem(name, version, dependencies, &cache_path)?;
 
-        println!("Installed {} ({})", name, version);
-        Ok(())
+        status!("Installed {} ({})", name, version);
+        Ok(true)
+    }
+
+    // vendor/cache を先に、次に gem のキャッシュを探す。
+    // どちらにもなければ、この環境で使えるビルド済みの gem を探す
+    fn cached_gem(&self, name: &str, version: &str) -> Option<PathBuf> {
+        let gem_filename = format!("{}-{}.gem", name, version);
+        self.vendor_cache
+            .iter()
+            .chain([&self.cache_dir])
+            .map(|dir| dir.join(&gem_filename))
+            .find(|path| path.exists())
+            .or_else(|| self.cached_platform_gem(name, version, &self.platform))
+    }
+
+    /// Finds a precompiled `name-version-platform.gem` in the caches whose
+    /// platform runs on `platform`, e.g. `nokogiri-1.16.0-arm64-darwin.gem`
+    /// on `arm64-darwin23`.
+    fn cached_platform_gem(&self, name: &str, version: &str, platform: &str) -> Option<PathBuf> {
+        let prefix = format!("{}-{}-", name, version);
+        self.vendor_cache
+            .iter()
+            .chain([&self.cache_dir])
+            .find_map(|dir| {
+                let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+                    .ok()?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .collect();
+                paths.sort();
+                paths.into_iter().find(|path| {
+                    path.file_name()
+                        .and_then(|file_name| file_name.to_str())
+                        .and_then(|file_name| file_name.strip_prefix(&prefix))
+                        .and_then(|file_name| file_name.strip_suffix(".gem"))
+                        .is_some_and(|gem_platform| platform_matches(gem_platform, platform))
+                })
+            })
     }
 
     fn is_gem_installed(&self, name: &str, version: &str) -> Result<bool> {
@@ -112,11 +421,54 @@ impl GemInstaller {
         Ok(gem_dir.exists() && gemspec_path.exists())
     }
 
-    fn download_gem(&self, name: &str, version: &str, output_path: &Path) -> Result<()> {
-        let url = format!("{}/gems/{}-{}.gem", self.base_url, name, version);
+    // .gem の data.tar.gz に ext/ が含まれているかチェック
+    fn has_extensions(gem_path: &Path) -> Result<bool> {
+        let mut gem = tar::Archive::new(File::open(gem_path)?);
+        for entry in gem.entries()? {
+            let entry = entry?;
+            if entry.path()?.as_os_str() != "data.tar.gz" {
+                continue;
+            }
+            let mut data = tar::Archive::new(flate2::read::GzDecoder::new(entry));
+            for file in data.entries()? {
+                if file?.path()?.starts_with("ext") {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
 
-        let client = reqwest::blocking::Client::new();
-        let mut response = client.get(&url).send()?;
+    // 解決に使ったソースから取る。同名の公開 gem を入れてしまわないよう rubygems.org には頼らない
+    fn download_gem(
+        &self,
+        source: &Url,
+        name: &str,
+        version: &str,
+        output_path: &Path,
+    ) -> Result<()> {
+        let url = format!(
+            "{}/gems/{}-{}.gem",
+            source.as_str().trim_end_matches('/'),
+            name,
+            version
+        );
+
+        let client = self.http_options.blocking_client_builder()?.build()?;
+        let mut retries = 0;
+        let mut response = loop {
+            let result = client.get(&url).send();
+            let retryable = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(err) => is_retryable(err),
+            };
+            if !retryable || retries == self.http_options.retries {
+                break result?;
+            }
+            debug!("Downloading {} failed, retrying", url);
+            std::thread::sleep(backoff(retries));
+            retries += 1;
+        };
 
         if !response.status().is_success() {
             return Err(InstallerError::Other(format!(
@@ -125,15 +477,19 @@ impl GemInstaller {
             )));
         }
 
-        let mut file = File::create(output_path)?;
-        let mut content = Vec::new();
-        response.copy_to(&mut content)?;
-        file.write_all(&content)?;
-
-        Ok(())
+        write_atomically(output_path, |file| {
+            response.copy_to(file)?;
+            Ok(())
+        })
     }
 
-    fn extract_and_install_gem(&self, name: &str, version: &str, gem_path: &Path) -> Result<()> {
+    fn extract_and_install_gem(
+        &self,
+        name: &str,
+        version: &str,
+        dependencies: &[GemDependency],
+        gem_path: &Path,
+    ) -> Result<()> {
         let gem_full_name = format!("{}-{}", name, version);
         let gem_dir = self.get_gems_dir().join(&gem_full_name);
         let spec_dir = self.get_specifications_dir();
@@ -153,6 +509,27 @@ impl GemInstaller {
         let mut compressed_data = Vec::new();
         source_file.read_to_end(&mut compressed_data)?;
 
+        // index の依存関係と gem 自身の metadata が食い違うなら、作り直された gem かもしれない
+        let declared = Self::read_metadata(&gemspec_source).and_then(|metadata| {
+            metadata_dependencies(&metadata)
+                .map_err(|err| InstallerError::Extraction(format!("invalid metadata: {}", err)))
+        });
+        match declared {
+            Ok(declared) => {
+                for difference in dependency_differences(&declared, dependencies) {
+                    warn!(
+                        "{} ({}) does not declare the dependencies the index listed ({}); \
+                         the gem may have been tampered with or repackaged",
+                        name, version, difference
+                    );
+                }
+            }
+            Err(err) => warn!(
+                "Could not read the metadata of {} ({}) to verify its dependencies: {}",
+                name, version, err
+            ),
+        }
+
         // gem自体の実行ファイルをbinディレクトリに作成
         self.setup_bin_files(name, version, &gem_dir)?;
 
@@ -162,6 +539,18 @@ impl GemInstaller {
         Ok(())
     }
 
+    fn read_metadata(metadata_gz: &Path) -> Result<String> {
+        let output = Command::new("gzip")
+            .args(["-dc", metadata_gz.to_str().unwrap()])
+            .output()?;
+        if !output.status.success() {
+            return Err(InstallerError::Extraction(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
     fn extract_gem(&self, gem_path: &Path, output_dir: &Path) -> Result<()> {
         // tar コマンドを使って.gemファイルを解凍
         // gemファイルはtar.gzファイルの一種です
@@ -240,9 +629,8 @@ impl GemInstaller {
         }
 
         let extensions_dir = self.get_extensions_dir();
-        let platform = Self::get_platform()?;
         let target_ext_dir = extensions_dir
-            .join(&platform)
+            .join(&self.platform)
             .join(format!("{}-{}", name, version));
 
         fs::create_dir_all(&target_ext_dir)?;
@@ -261,8 +649,8 @@ impl GemInstaller {
                     .output()?;
 
                 if !output.status.success() {
-                    println!(
-                        "Warning: Failed to run extconf.rb for {}: {}",
+                    warn!(
+                        "Failed to run extconf.rb for {}: {}",
                         ext_name.to_string_lossy(),
                         String::from_utf8_lossy(&output.stderr)
                     );
@@ -273,8 +661,8 @@ impl GemInstaller {
                 let output = Command::new("make").current_dir(&ext_source_dir).output()?;
 
                 if !output.status.success() {
-                    println!(
-                        "Warning: Failed to build extension {} for {}-{}: {}",
+                    warn!(
+                        "Failed to build extension {} for {}-{}: {}",
                         ext_name.to_string_lossy(),
                         name,
                         version,
@@ -300,24 +688,14 @@ impl GemInstaller {
         Ok(())
     }
 
-    fn get_platform() -> Result<String> {
-        let output = Command::new("ruby")
-            .args(&["-e", "puts RUBY_PLATFORM"])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(InstallerError::Command(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
-        }
-
-        let platform = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        Ok(platform)
-    }
-
     // ディレクトリ構造のヘルパーメソッド
-    fn get_gems_base_dir(&self) -> PathBuf {
-        self.install_base_dir.join("gems").join(&self.ruby_version)
+    /// The gem home gems are installed into, `<path>/ruby/<ruby version>` when
+    /// a bundle path is set. `bundle exec` runs against the same directory.
+    pub fn get_gems_base_dir(&self) -> PathBuf {
+        match &self.bundle_path {
+            Some(path) => path.join("ruby").join(&self.ruby_version),
+            None => self.install_base_dir.join("gems").join(&self.ruby_version),
+        }
     }
 
     fn get_gems_dir(&self) -> PathBuf {
@@ -336,3 +714,556 @@ impl GemInstaller {
         self.get_gems_base_dir().join("bin")
     }
 }
+
+/// How the runtime dependencies `declared` in a gem's metadata differ from the
+/// ones the index listed, e.g. `rack: ~> 2.0 in the gem, ~> 3.0 in the index`.
+fn dependency_differences(
+    declared: &[(String, Vec<String>)],
+    indexed: &[GemDependency],
+) -> Vec<String> {
+    // "= 1.0" と "1.0" のような書き方の違いは parse_req で揃える
+    let normalize = |requirements: &[String]| {
+        let mut requirements = parse_req(&requirements.join(","), ",")
+            .map(|(_, normalized)| normalized)
+            .unwrap_or_else(|_| requirements.to_vec());
+        requirements.retain(|req| req != ">= 0");
+        sort_requirements(&requirements)
+    };
+    let declared: BTreeMap<&str, Vec<String>> = declared
+        .iter()
+        .map(|(name, reqs)| (name.as_str(), normalize(reqs)))
+        .collect();
+    let indexed: BTreeMap<&str, Vec<String>> = indexed
+        .iter()
+        .map(|dep| (dep.name.as_str(), normalize(&dep.requirement_str)))
+        .collect();
+    let describe = |reqs: Option<&Vec<String>>| match reqs {
+        None => "missing".to_string(),
+        Some(reqs) if reqs.is_empty() => ">= 0".to_string(),
+        Some(reqs) => reqs.join(", "),
+    };
+    let names: BTreeSet<&str> = declared.keys().chain(indexed.keys()).copied().collect();
+    names
+        .into_iter()
+        .filter(|name| declared.get(name) != indexed.get(name))
+        .map(|name| {
+            format!(
+                "{}: {} in the gem, {} in the index",
+                name,
+                describe(declared.get(name)),
+                describe(indexed.get(name))
+            )
+        })
+        .collect()
+}
+
+/// Whether `clean` leaves the installed `full_name` alone: it is in `keep`,
+/// it is a platform build of a gem in `keep` (`nokogiri-1.16.0-x86_64-linux`
+/// for `nokogiri-1.16.0`), or it is Bundler itself.
+fn is_kept(keep: &HashSet<String>, full_name: &str) -> bool {
+    if keep.contains(full_name) || full_name.starts_with("bundler-") {
+        return true;
+    }
+    // プラットフォームは数字で始まらないので、別のバージョンとは区別できる
+    full_name.match_indices('-').any(|(i, _)| {
+        keep.contains(&full_name[..i])
+            && !full_name[i + 1..].starts_with(|c: char| c.is_ascii_digit())
+    })
+}
+
+/// Writes `path` through a temporary file in the same directory that replaces
+/// it only once `write` succeeds, so an interrupted download never leaves a
+/// truncated file that looks complete.
+fn write_atomically(path: &Path, write: impl FnOnce(&mut File) -> Result<()>) -> Result<()> {
+    let mut temp = tempfile::NamedTempFile::new_in(path.parent().unwrap_or(Path::new(".")))?;
+    write(temp.as_file_mut())?;
+    temp.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::io::Write;
+    use std::time::Duration;
+
+    use url::Url;
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{method, path},
+    };
+
+    use crate::{
+        compact_index_client::{GemVersion, parse_dependencies},
+        http::HttpOptions,
+        installer::{
+            GemInstaller, InstallOutcome, InstallSummary, InstallerError, RubyInfo,
+            dependency_differences, write_atomically,
+        },
+        test_support::flaky_server,
+        version::RubyVersion,
+    };
+
+    fn resolved(name: &str, version: &str) -> (String, (Url, GemVersion)) {
+        resolved_from("https://rubygems.org/", name, version)
+    }
+
+    fn resolved_from(source: &str, name: &str, version: &str) -> (String, (Url, GemVersion)) {
+        (
+            name.to_string(),
+            (
+                Url::parse(source).unwrap(),
+                GemVersion {
+                    name: name.to_string(),
+                    version: RubyVersion::parse(version),
+                    checksum: None,
+                    dependencies: vec![],
+                    required_ruby_version: None,
+                },
+            ),
+        )
+    }
+
+    #[test]
+    fn test_install_summary() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let installer = GemInstaller {
+            install_base_dir: dir.path().join("install"),
+            cache_dir: dir.path().join("cache"),
+            http_options: HttpOptions::default(),
+            ruby_version: "3.3.0".to_string(),
+            platform: "x86_64-linux".to_string(),
+            bundle_path: None,
+            vendor_cache: None,
+            local: false,
+            continue_on_error: false,
+        };
+        installer.create_dirs()?;
+        std::fs::create_dir(installer.get_gems_dir().join("rack-2.2.8"))?;
+        std::fs::write(
+            installer
+                .get_specifications_dir()
+                .join("rack-2.2.8.gemspec"),
+            "",
+        )?;
+
+        let outcomes = installer.install_gems(
+            HashMap::from([resolved("rack", "2.2.8"), resolved("thor", "1.3.2")]),
+            true,
+        )?;
+        assert_eq!(
+            InstallSummary::new(&outcomes),
+            InstallSummary {
+                installed: 1,
+                already_installed: 1,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_standalone_setup() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut installer = GemInstaller {
+            install_base_dir: dir.path().join("install"),
+            cache_dir: dir.path().join("cache"),
+            http_options: HttpOptions::default(),
+            ruby_version: "3.3.0".to_string(),
+            platform: "x86_64-linux".to_string(),
+            bundle_path: None,
+            vendor_cache: None,
+            local: false,
+            continue_on_error: false,
+        };
+        installer.set_bundle_path(&dir.path().join("bundle"));
+        std::fs::create_dir_all(
+            installer
+                .get_extensions_dir()
+                .join("x86_64-linux")
+                .join("nio4r-2.7.0"),
+        )?;
+
+        let path = installer.write_standalone_setup(&HashMap::from(
+            [resolved("rack", "3.0.9"), resolved("nio4r", "2.7.0")]
+                .map(|(name, (_, version))| (name, version)),
+        ))?;
+        assert_eq!(path, dir.path().join("bundle/bundler/setup.rb"));
+        assert_eq!(
+            std::fs::read_to_string(path)?,
+            "# Generated by bundle install --standalone\n\
+             $:.unshift File.expand_path(\"../ruby/3.3.0/gems/nio4r-2.7.0/lib\", __dir__)\n\
+             $:.unshift File.expand_path(\"../ruby/3.3.0/extensions/x86_64-linux/nio4r-2.7.0\", __dir__)\n\
+             $:.unshift File.expand_path(\"../ruby/3.3.0/gems/rack-3.0.9/lib\", __dir__)\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_vendor_cache() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let vendor_cache = dir.path().join("vendor/cache");
+        std::fs::create_dir_all(&vendor_cache)?;
+        std::fs::write(
+            vendor_cache.join("rack-2.2.8.gem"),
+            tar::Builder::new(Vec::new()).into_inner()?,
+        )?;
+        let mut installer = GemInstaller {
+            install_base_dir: dir.path().join("install"),
+            cache_dir: dir.path().join("cache"),
+            http_options: HttpOptions::default(),
+            ruby_version: "3.3.0".to_string(),
+            platform: "x86_64-linux".to_string(),
+            bundle_path: None,
+            vendor_cache: None,
+            local: false,
+            continue_on_error: false,
+        };
+        installer.set_vendor_cache(&vendor_cache);
+        installer.set_local(true);
+
+        assert_eq!(
+            installer.cached_gem("rack", "2.2.8"),
+            Some(vendor_cache.join("rack-2.2.8.gem"))
+        );
+        assert_eq!(installer.cached_gem("thor", "1.3.2"), None);
+
+        for platform in ["x86_64-linux", "arm64-darwin"] {
+            std::fs::write(
+                vendor_cache.join(format!("nokogiri-1.16.0-{}.gem", platform)),
+                "",
+            )?;
+        }
+        let platform_gem = |platform| installer.cached_platform_gem("nokogiri", "1.16.0", platform);
+        assert_eq!(
+            platform_gem("arm64-darwin23"),
+            Some(vendor_cache.join("nokogiri-1.16.0-arm64-darwin.gem"))
+        );
+        assert_eq!(
+            platform_gem("x86_64-linux-gnu"),
+            Some(vendor_cache.join("nokogiri-1.16.0-x86_64-linux.gem"))
+        );
+        assert_eq!(platform_gem("aarch64-linux"), None);
+        assert_eq!(
+            InstallSummary::new(
+                &installer.install_gems(HashMap::from([resolved("rack", "2.2.8")]), true)?
+            ),
+            InstallSummary {
+                installed: 1,
+                already_installed: 0,
+            }
+        );
+
+        let gems = || {
+            HashMap::from([
+                resolved("rack", "2.2.8"),
+                resolved("thor", "1.3.2"),
+                resolved("zeitwerk", "2.6.0"),
+            ])
+        };
+        let outcomes = |outcomes: Vec<(String, super::Result<InstallOutcome>)>| {
+            outcomes
+                .into_iter()
+                .map(|(name, outcome)| match outcome {
+                    Ok(outcome) => format!("{} {:?}", name, outcome),
+                    Err(InstallerError::NotCached(gem)) => format!("{} not cached", gem),
+                    Err(err) => panic!("unexpected error for {}: {}", name, err),
+                })
+                .collect::<Vec<_>>()
+        };
+        // 最初に失敗した gem で止まる
+        assert_eq!(
+            outcomes(installer.install_gems(gems(), true)?),
+            vec!["rack Installed", "thor-1.3.2 not cached"]
+        );
+        installer.set_continue_on_error(true);
+        assert_eq!(
+            outcomes(installer.install_gems(gems(), true)?),
+            vec![
+                "rack Installed",
+                "thor-1.3.2 not cached",
+                "zeitwerk-2.6.0 not cached"
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ruby_runs_once() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        use std::sync::OnceLock;
+
+        // 呼ばれるたびに calls に 1 行足す ruby の代わり
+        let dir = tempfile::tempdir()?;
+        let ruby = dir.path().join("ruby");
+        let calls = dir.path().join("calls");
+        std::fs::write(
+            &ruby,
+            format!(
+                "#!/bin/sh\necho \"$*\" >> {}\necho 3.3.0\necho arm64-darwin23\n",
+                calls.display()
+            ),
+        )?;
+        std::fs::set_permissions(&ruby, std::fs::Permissions::from_mode(0o755))?;
+
+        let cell = OnceLock::new();
+        for _ in 0..3 {
+            assert_eq!(
+                RubyInfo::cached(&cell, &ruby)?,
+                &RubyInfo {
+                    version: "3.3.0".to_string(),
+                    platform: "arm64-darwin23".to_string(),
+                }
+            );
+        }
+        assert_eq!(
+            std::fs::read_to_string(&calls)?,
+            "-e puts RUBY_VERSION; puts RUBY_PLATFORM\n"
+        );
+
+        // 失敗は覚えず、次の呼び出しでもう一度試す
+        let cell = OnceLock::new();
+        assert!(RubyInfo::cached(&cell, &dir.path().join("missing")).is_err());
+        assert!(RubyInfo::cached(&cell, &ruby).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_atomically() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("rack-2.2.8.gem");
+
+        let result = write_atomically(&path, |file| {
+            file.write_all(b"partial")?;
+            Err(InstallerError::Other("connection reset".to_string()))
+        });
+        assert!(result.is_err());
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+
+        write_atomically(&path, |file| Ok(file.write_all(b"gem")?))?;
+        assert_eq!(std::fs::read(&path)?, b"gem");
+        Ok(())
+    }
+
+    #[test]
+    fn test_dependency_differences() {
+        let declared = |deps: &[(&str, &[&str])]| -> Vec<(String, Vec<String>)> {
+            deps.iter()
+                .map(|(name, reqs)| {
+                    (
+                        name.to_string(),
+                        reqs.iter().map(|r| r.to_string()).collect(),
+                    )
+                })
+                .collect()
+        };
+        let indexed = parse_dependencies("rack:>= 2.2.4&~> 2.0,thor:>= 0");
+
+        assert!(
+            dependency_differences(
+                &declared(&[("thor", &[">= 0"]), ("rack", &["~> 2.0", ">=2.2.4"])]),
+                &indexed
+            )
+            .is_empty()
+        );
+        // 依存関係が書き換えられた gem
+        assert_eq!(
+            dependency_differences(
+                &declared(&[("rack", &["~> 3.0"]), ("evil", &[">= 0"])]),
+                &indexed
+            ),
+            vec![
+                "evil: >= 0 in the gem, missing in the index",
+                "rack: ~> 3.0 in the gem, ~> 2.0, >= 2.2.4 in the index",
+                "thor: missing in the gem, >= 0 in the index",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_timeout() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/gems/slow-1.0.0.gem"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let installer = GemInstaller {
+            install_base_dir: dir.path().join("install"),
+            cache_dir: dir.path().join("cache"),
+            http_options: HttpOptions {
+                timeout: Duration::from_millis(200),
+                retries: 0,
+                ..HttpOptions::default()
+            },
+            ruby_version: "3.3.0".to_string(),
+            platform: "x86_64-linux".to_string(),
+            bundle_path: None,
+            vendor_cache: None,
+            local: false,
+            continue_on_error: false,
+        };
+        let output = dir.path().join("slow-1.0.0.gem");
+        let source = Url::parse(&server.uri())?;
+
+        let result = tokio::task::spawn_blocking(move || {
+            installer.download_gem(&source, "slow", "1.0.0", &output)
+        })
+        .await?;
+        match result {
+            Err(InstallerError::Http(err)) => assert!(err.is_timeout()),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_retries() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("flaky-1.0.0.gem");
+        let download = |server: &MockServer, retries| {
+            let installer = GemInstaller {
+                install_base_dir: dir.path().join("install"),
+                cache_dir: dir.path().join("cache"),
+                http_options: HttpOptions {
+                    retries,
+                    ..HttpOptions::default()
+                },
+                ruby_version: "3.3.0".to_string(),
+                platform: "x86_64-linux".to_string(),
+                bundle_path: None,
+                vendor_cache: None,
+                local: false,
+                continue_on_error: false,
+            };
+            let output = output.clone();
+            let source = Url::parse(&server.uri()).unwrap();
+            tokio::task::spawn_blocking(move || {
+                installer.download_gem(&source, "flaky", "1.0.0", &output)
+            })
+        };
+
+        let server = flaky_server("/gems/flaky-1.0.0.gem", b"gem").await;
+        assert!(download(&server, 1).await?.is_err());
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+
+        let server = flaky_server("/gems/flaky-1.0.0.gem", b"gem").await;
+        download(&server, 2).await??;
+        assert_eq!(std::fs::read(&output)?, b"gem");
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_from_resolved_source() -> anyhow::Result<()> {
+        let rubygems = MockServer::start().await;
+        let private = MockServer::start().await;
+        for server in [&rubygems, &private] {
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(b"gem".to_vec()))
+                .mount(server)
+                .await;
+        }
+        let dir = tempfile::tempdir()?;
+        let mut installer = GemInstaller {
+            install_base_dir: dir.path().join("install"),
+            cache_dir: dir.path().join("cache"),
+            http_options: HttpOptions::default(),
+            ruby_version: "3.3.0".to_string(),
+            platform: "x86_64-linux".to_string(),
+            bundle_path: None,
+            vendor_cache: None,
+            local: false,
+            continue_on_error: false,
+        };
+        installer.set_continue_on_error(true);
+        let gems = HashMap::from([
+            resolved_from(&rubygems.uri(), "rack", "3.0.9"),
+            resolved_from(&private.uri(), "internal-auth", "1.0.0"),
+        ]);
+        // 中身は gem ではないので展開で失敗するが、どこから取ったかは分かる
+        tokio::task::spawn_blocking(move || installer.install_gems(gems, false)).await??;
+
+        let paths = |requests: Vec<wiremock::Request>| {
+            requests
+                .iter()
+                .map(|request| request.url.path().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            paths(rubygems.received_requests().await.unwrap()),
+            vec!["/gems/rack-3.0.9.gem"]
+        );
+        assert_eq!(
+            paths(private.received_requests().await.unwrap()),
+            vec!["/gems/internal-auth-1.0.0.gem"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let installer = GemInstaller {
+            install_base_dir: dir.path().join("install"),
+            cache_dir: dir.path().join("cache"),
+            http_options: HttpOptions::default(),
+            ruby_version: "3.3.0".to_string(),
+            platform: "x86_64-linux".to_string(),
+            bundle_path: None,
+            vendor_cache: None,
+            local: false,
+            continue_on_error: false,
+        };
+        installer.create_dirs()?;
+        for full_name in ["rack-2.2.8", "rack-3.0.0", "thor-1.3.2"] {
+            std::fs::create_dir(installer.get_gems_dir().join(full_name))?;
+            std::fs::write(
+                installer
+                    .get_specifications_dir()
+                    .join(format!("{}.gemspec", full_name)),
+                "",
+            )?;
+            std::fs::write(
+                dir.path().join("cache").join(format!("{}.gem", full_name)),
+                "",
+            )?;
+        }
+        for full_name in ["nokogiri-1.16.0-x86_64-linux", "bundler-2.5.22"] {
+            std::fs::create_dir(installer.get_gems_dir().join(full_name))?;
+        }
+        let keep = HashSet::from(["rack-2.2.8".to_string(), "nokogiri-1.16.0".to_string()]);
+
+        assert_eq!(
+            installer.clean(&keep, true)?,
+            vec!["rack-3.0.0", "thor-1.3.2"]
+        );
+        assert!(installer.get_gems_dir().join("thor-1.3.2").exists());
+
+        assert_eq!(
+            installer.clean(&keep, false)?,
+            vec!["rack-3.0.0", "thor-1.3.2"]
+        );
+        assert!(installer.get_gems_dir().join("rack-2.2.8").exists());
+        assert!(!installer.get_gems_dir().join("rack-3.0.0").exists());
+        assert!(
+            !installer
+                .get_specifications_dir()
+                .join("thor-1.3.2.gemspec")
+                .exists()
+        );
+        assert!(
+            installer
+                .get_gems_dir()
+                .join("nokogiri-1.16.0-x86_64-linux")
+                .exists()
+        );
+        assert!(installer.get_gems_dir().join("bundler-2.5.22").exists());
+        // gem のキャッシュは他のバンドルと共有しているので消さない
+        assert!(dir.path().join("cache/rack-3.0.0.gem").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+//! A Bundler-compatible gem resolver and installer.
+//!
+//! Besides the `bundle` binary, the crate can be used to read the gem
+//! metadata a source serves: [`compact_index_client::CompactIndexClient`]
+//! speaks the compact index protocol of rubygems.org and caches what it
+//! downloads. The HTTP settings and Ruby version types its API takes are
+//! re-exported at the top level; the rest of the crate is the internals of
+//! the binary.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use bundle::{HttpOptions, compact_index_client::CompactIndexClient};
+//!
+//! # async fn run() -> bundle::compact_index_client::Result<()> {
+//! let client = CompactIndexClient::new(
+//!     "https://rubygems.org/",
+//!     Path::new("/tmp/bundle-cache"),
+//!     HttpOptions::default(),
+//! )
+//! .await?;
+//! for version in client.info("rack").await? {
+//!     println!("rack {} needs Ruby {:?}", version.version, version.required_ruby_version);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod compact_index_client;
+pub(crate) mod http;
+#[cfg(test)]
+mod test_support;
+pub(crate) mod version;
+
+pub use http::{HttpOptions, HttpVersion};
+pub use version::{RichReq, RubyVersion, parse_req, parse_req_any};
@@ -1,22 +1,46 @@
 mod cli;
 mod compact_index_client;
+mod config;
+mod diff;
 mod executor;
+mod failure;
 mod gemfilelock;
+mod gemspec;
+mod git;
+mod http;
 mod installer;
+mod multi_source;
+mod outdated;
+mod output;
+mod platform;
 mod resolver;
+mod source;
+#[cfg(test)]
+mod test_support;
 mod version;
 
-use compact_index_client::CompactIndexClient;
+use compact_index_client::{CompactIndexClient, GemVersion};
+use config::BundleConfig;
 use executor::Executor;
-use gemfilelock::write_lockfile;
-use installer::GemInstaller;
+use failure::Failure;
+use gemfilelock::{LockSource, lockfile_hash, read_lockfile, write_lockfile, write_lockfile_to};
+use gemspec::Gemspec;
+use git::GitReference;
+use http::HttpOptions;
+use installer::{GemInstaller, InstallSummary};
+use multi_source::MultiSourceClient;
+use output::status;
 use resolver::Resolver;
 use serde::Deserialize;
+use source::GemSource;
+use tracing::{debug, info, warn};
 use tracing_subscriber::fmt::format::FmtSpan;
-use version::{RichReq, RubyVersion, parse_req};
+use url::Url;
+use version::{RichReq, RubyVersion, parse_req, parse_req_any};
 // use resolver::Resolver;
 
-use pubgrub::{DependencyProvider, Ranges};
+use pubgrub::{DependencyProvider, Ranges, VersionSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::path::{Path, PathBuf};
@@ -26,66 +50,787 @@ use clap::Parser as _;
 #[derive(Deserialize, Debug)]
 struct Gemfile {
     dependencies: Vec<Gem>,
+    /// Global `source` remotes in declaration order.
+    #[serde(default)]
+    sources: Vec<String>,
+    #[serde(default)]
+    ruby: Option<RubyDirective>,
+}
+
+/// The `ruby '3.3.0', engine: 'jruby', engine_version: '9.4.0'` directive.
+#[derive(Deserialize, Debug)]
+struct RubyDirective {
+    /// The Ruby version requirements, e.g. `["3.3.0"]` or `["~> 3.2"]`.
+    #[serde(default)]
+    versions: Vec<String>,
+    engine: Option<String>,
+    #[serde(default)]
+    engine_versions: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct Gem {
     name: String,
     requirement: Option<String>,
+    /// From a `platforms :jruby do` block or a `platforms:` option.
+    #[serde(default)]
+    platforms: Vec<String>,
+    /// Whether every enclosing `install_if` condition held when the Gemfile was evaluated.
+    #[serde(default = "default_true")]
+    should_include: bool,
+    /// The `require:` option. `None` when omitted, empty for `require: false`.
+    #[serde(default)]
+    autorequire: Option<Vec<String>>,
+    /// The `path:` option, relative to the Gemfile.
+    #[serde(default)]
+    path: Option<String>,
+    /// The repository URL of a `git:` gem. Bundler expands `github: 'rails/rails'`
+    /// to `https://github.com/rails/rails.git` while evaluating the Gemfile, and
+    /// options defined with `git_source` the same way.
+    #[serde(default)]
+    git: Option<String>,
+    /// The `branch:` of a git gem.
+    #[serde(default)]
+    branch: Option<String>,
+    /// The `tag:` of a git gem.
+    #[serde(default)]
+    tag: Option<String>,
+    /// The `ref:` of a git gem, usually a commit SHA.
+    #[serde(default, rename = "ref")]
+    git_ref: Option<String>,
+    /// From `group :test do` blocks and the `group:` option; `default` when ungrouped.
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl Gem {
+    /// Whether `bundle install --only <groups>` installs this gem. Ungrouped gems always are.
+    fn in_groups(&self, only: &[String]) -> bool {
+        self.groups.is_empty()
+            || self
+                .groups
+                .iter()
+                .any(|group| group == "default" || only.contains(group))
+    }
+
+    /// What `Bundler.require` loads for this gem.
+    fn requires(&self) -> Vec<String> {
+        match &self.autorequire {
+            Some(paths) => paths.clone(),
+            None => vec![self.name.clone()],
+        }
+    }
+
+    /// The commit to check out for a `git:` gem. Like Bundler, `ref:` wins over
+    /// `tag:`, which wins over `branch:`.
+    fn git_reference(&self) -> Option<GitReference> {
+        self.git_ref
+            .clone()
+            .map(GitReference::Ref)
+            .or_else(|| self.tag.clone().map(GitReference::Tag))
+            .or_else(|| self.branch.clone().map(GitReference::Branch))
+    }
+
+    /// The requirement to resolve against. `gem 'x', '>= 1.0', '< 2.0'` arrives
+    /// as `>= 1.0, < 2.0` (`Gem::Requirement#to_s`), so every argument applies.
+    fn constraint(&self) -> Result<(RichReq, Vec<String>), String> {
+        parse_req(self.requirement.as_deref().unwrap_or("*"), ",")
+            .map_err(|err| format!("{} for gem {}", err, self.name))
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Folds repeated `gem` declarations into one, as Bundler does. Repeating a gem
+/// with the same requirement only warns; with a different one it is an error.
+fn merge_duplicate_gems(dependencies: Vec<Gem>) -> Result<Vec<Gem>, String> {
+    // Gem::Requirement#== と同じく、正規化して並べ替えた要件で比べる (">= 0" は要件なしと同じ)
+    let normalized = |gem: &Gem| -> Result<Vec<String>, String> {
+        let mut reqs = gem.constraint()?.1;
+        if gemfilelock::is_unconstrained(&reqs) {
+            reqs.clear();
+        }
+        reqs.sort();
+        Ok(reqs)
+    };
+    let mut merged: Vec<Gem> = Vec::with_capacity(dependencies.len());
+    for dep in dependencies {
+        let Some(existing) = merged.iter_mut().find(|g| g.name == dep.name) else {
+            merged.push(dep);
+            continue;
+        };
+        if normalized(existing)? != normalized(&dep)? {
+            let shown = |gem: &Gem| {
+                gem.requirement
+                    .clone()
+                    .unwrap_or_else(|| ">= 0".to_string())
+            };
+            return Err(format!(
+                "You cannot specify the same gem twice with different version requirements.\n\
+                 You specified: {} ({}) and {} ({})",
+                existing.name,
+                shown(existing),
+                dep.name,
+                shown(&dep)
+            ));
+        }
+        warn!(
+            "Your Gemfile lists the gem {} more than once. You should keep only one of them.",
+            dep.name
+        );
+        // 片方がグループなしなら、どの --only でもインストールされるようにする
+        for group in dep.groups {
+            if !existing.groups.contains(&group) {
+                existing.groups.push(group);
+            }
+        }
+    }
+    Ok(merged)
+}
+
+// Bundler の platforms (:ruby, :mri_31, :jruby, :windows, ...) が実行中の Ruby に当てはまるか
+fn platform_matches(platforms: &[String], engine: &str) -> bool {
+    if platforms.is_empty() {
+        return true;
+    }
+    let windows = cfg!(windows);
+    platforms.iter().any(|platform| {
+        // バージョン付き (ruby_31 など) はエンジン名だけを見る
+        let name = platform
+            .split_once('_')
+            .filter(|(_, version)| version.chars().all(|c| c.is_ascii_digit()))
+            .map_or(platform.as_str(), |(name, _)| name);
+        match name {
+            "ruby" | "mri" => engine == "ruby" && !windows,
+            "jruby" => engine == "jruby",
+            "truffleruby" => engine == "truffleruby",
+            "windows" | "mswin" | "mswin64" | "mingw" | "x64_mingw" => windows,
+            _ => false,
+        }
+    })
+}
+
+/// Groups the specs to lock by the source that provided them, for the
+/// lockfile. `resolved` holds the source each gem from an index was fetched
+/// from; a gem fetched from a mirror is listed under the remote it mirrors.
+fn group_by_source(
+    specs: &[(String, RubyVersion)],
+    path_gems: &HashMap<String, (LockSource, Gemspec)>,
+    resolved: &HashMap<String, (Url, GemVersion)>,
+    remotes: &HashMap<String, String>,
+) -> BTreeMap<LockSource, Vec<(String, RubyVersion)>> {
+    let mut lock_sources: BTreeMap<LockSource, Vec<(String, RubyVersion)>> = BTreeMap::new();
+    for (name, version) in specs {
+        if let Some((source, _)) = path_gems.get(name) {
+            lock_sources
+                .entry(source.clone())
+                .or_default()
+                .push((name.clone(), version.clone()));
+            continue;
+        }
+        let Some((source, _)) = resolved.get(name) else {
+            continue;
+        };
+        let source = source.to_string();
+        lock_sources
+            .entry(LockSource::Gem(
+                remotes.get(&source).cloned().unwrap_or(source),
+            ))
+            .or_default()
+            .push((name.clone(), version.clone()));
+    }
+    lock_sources
+}
+
+/// Evaluates the Gemfile at `path` with Bundler's own DSL, by running
+/// eval_gemfile.rb with the `ruby` on the PATH, and reads what it declares.
+fn parse_gemfile(path: &Path) -> Result<Gemfile, String> {
+    let output = std::process::Command::new("ruby")
+        .arg("-e")
+        .arg(include_str!("../eval_gemfile.rb"))
+        .arg(path)
+        .output()
+        .map_err(|err| format!("Could not run ruby to evaluate {}: {}", path.display(), err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Could not evaluate {}:\n{}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("Could not parse the evaluated Gemfile: {}", err))
+}
+
+// 実行中の Ruby のエンジンとそのバージョンを取得
+fn detect_ruby_engine() -> Option<(String, RubyVersion)> {
+    let output = std::process::Command::new("ruby")
+        .args(["-e", "puts RUBY_ENGINE; puts RUBY_ENGINE_VERSION"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let engine = lines.next()?.trim().to_string();
+    let version = RubyVersion::parse(lines.next()?.trim());
+    Some((engine, version))
+}
+
+/// The Ruby gems are resolved for: the Gemfile's `ruby` version when it names
+/// exactly one, otherwise the running CRuby.
+fn target_ruby_version(
+    directive: Option<&RubyDirective>,
+    running: Option<&(String, RubyVersion)>,
+) -> Option<RubyVersion> {
+    let pinned = directive.and_then(|directive| match directive.versions.as_slice() {
+        [version] => parse_req(version, ",")
+            .ok()?
+            .1
+            .first()?
+            .strip_prefix("= ")
+            .map(RubyVersion::parse),
+        _ => None,
+    });
+    // JRuby などの RUBY_ENGINE_VERSION は Ruby のバージョンではない
+    pinned.or_else(|| {
+        running
+            .filter(|(engine, _)| engine == "ruby")
+            .map(|(_, version)| version.clone())
+    })
+}
+
+/// Checks the running Ruby against the Gemfile's `ruby '3.3.0'` or `ruby '>= 2.7'`.
+fn check_ruby_version(directive: &RubyDirective, ruby_version: &RubyVersion) -> Result<(), String> {
+    if directive.versions.is_empty() {
+        return Ok(());
+    }
+    let (req, _) = parse_req(&directive.versions.join(","), ",")
+        .map_err(|err| format!("{} in the Gemfile's ruby directive", err))?;
+    // preview 版の Ruby でも範囲だけで判断する
+    if !req.range.contains(ruby_version) {
+        return Err(format!(
+            "Your Ruby version is {}, but your Gemfile specified {}",
+            ruby_version,
+            directive.versions.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn check_ruby_engine(
+    directive: &RubyDirective,
+    engine: &str,
+    engine_version: &RubyVersion,
+) -> anyhow::Result<()> {
+    let Some(expected) = &directive.engine else {
+        return Ok(());
+    };
+    if expected != engine {
+        anyhow::bail!(
+            "Your Ruby engine is {}, but your Gemfile specified {}",
+            engine,
+            expected
+        );
+    }
+    if !directive.engine_versions.is_empty() {
+        let (req, _) = parse_req(&directive.engine_versions.join(","), ",")?;
+        if !req.contains(engine_version) {
+            anyhow::bail!(
+                "Your {} version is {}, but your Gemfile specified {} {}",
+                engine,
+                engine_version,
+                expected,
+                directive.engine_versions.join(", ")
+            );
+        }
+    }
+    Ok(())
 }
 
-fn parse_gemfile() -> Gemfile {
-    let gemfile: Gemfile = serde_json::from_str(include_str!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/gemfile.json"
-    )))
-    .unwrap();
+async fn print_outdated(
+    client: &MultiSourceClient,
+    gemfile: &Gemfile,
+    lockfile: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let lockfile = read_lockfile(lockfile).await?;
+    let requirements: HashMap<String, String> = gemfile
+        .dependencies
+        .iter()
+        .filter_map(|dep| Some((dep.name.clone(), dep.requirement.clone()?)))
+        .collect();
 
-    // println!("gemfile: {:?}", gemfile);
+    let gems = outdated::outdated(client, &lockfile, &requirements).await?;
+    if gems.is_empty() {
+        status!("Bundle up to date!");
+        return Ok(());
+    }
 
-    // println!("rmagick: {}", gemfile.dependencies.iter().find(|dep| dep.name == "rmagick").unwrap().requirement.clone().unwrap());
+    let (held_back, updatable): (Vec<_>, Vec<_>) =
+        gems.into_iter().partition(|g| g.held_back_by.is_some());
+    if !updatable.is_empty() {
+        println!("Outdated gems included in the bundle:");
+        for gem in updatable {
+            println!("  {}", gem);
+        }
+    }
+    if !held_back.is_empty() {
+        println!("Newer versions held back by the Gemfile requirement:");
+        for gem in held_back {
+            println!("  {}", gem);
+        }
+    }
+    Ok(())
+}
+
+/// Bundler's closing line, followed by what this run installed and how long it took.
+fn install_summary(
+    dependencies: usize,
+    summary: &InstallSummary,
+    elapsed: std::time::Duration,
+) -> String {
+    let gems = summary.installed + summary.already_installed;
+    format!(
+        "Bundle complete! {} Gemfile {}, {} {} now installed.\n\
+         {} newly installed, {} already present, in {:.2}s.",
+        dependencies,
+        if dependencies == 1 {
+            "dependency"
+        } else {
+            "dependencies"
+        },
+        gems,
+        if gems == 1 { "gem" } else { "gems" },
+        summary.installed,
+        summary.already_installed,
+        elapsed.as_secs_f64()
+    )
+}
 
-    gemfile
+/// The command line's network settings, with the `User-Agent` and `BUNDLE_JOBS`
+/// from the config if set.
+fn http_options(cli: &cli::Cli, config: &BundleConfig) -> HttpOptions {
+    let mut options = cli.http_options();
+    if let Some(user_agent) = config.user_agent() {
+        options.user_agent = user_agent.to_string();
+    }
+    if let Some(jobs) = cli.jobs().or_else(|| config.jobs()) {
+        options.jobs = jobs;
+    }
+    options
+}
+
+fn new_installer(
+    cli: &cli::Cli,
+    config: &BundleConfig,
+    bundle_path: Option<&Path>,
+) -> Result<GemInstaller, Box<dyn Error>> {
+    let home_dir = match dirs::home_dir() {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+
+    // Bundlerのディレクトリ構造
+    let bundle_dir = home_dir.join(".bundle");
+
+    // Gemキャッシュディレクトリ
+    let gem_cache_dir = bundle_dir.join("cache");
+
+    // Bundlerのインストールパス
+    let install_dir = match env::var("GEM_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => home_dir.join(".gem"),
+    };
+
+    let mut installer = GemInstaller::new(&install_dir, &gem_cache_dir, http_options(cli, config))?;
+    if let Some(path) = bundle_path {
+        installer.set_bundle_path(path);
+    }
+    installer.set_vendor_cache(Path::new("vendor/cache"));
+    installer.set_local(cli.local());
+    Ok(installer)
+}
+
+/// Removes installed gems that `Gemfile.lock` no longer lists. Without a
+/// bundle path that would be every other gem of the system, so it needs `force`.
+async fn clean(
+    cli: &cli::Cli,
+    config: &BundleConfig,
+    dry_run: bool,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    if config.path().is_none() && !force {
+        return Err(
+            "Cleaning all the gems on your system is dangerous! If you're sure you \
+                    want to remove every system gem not in this bundle, run `bundle clean --force`."
+                .into(),
+        );
+    }
+    let lockfile = read_lockfile(cli.lockfile()).await?;
+    let keep: HashSet<String> = lockfile
+        .specs
+        .iter()
+        .map(|(name, version)| format!("{}-{}", name, version))
+        .collect();
+
+    let installer = new_installer(cli, config, config.path().as_deref())?;
+    for full_name in installer.clean(&keep, dry_run)? {
+        if dry_run {
+            status!("Would have removed {}", full_name);
+        } else {
+            status!("Removing {}", full_name);
+        }
+    }
+    Ok(())
+}
+
+/// Removes the cached index entries of gems that neither `Gemfile.lock` nor
+/// their dependencies reach.
+async fn prune_cache(
+    client: &MultiSourceClient,
+    lockfile: &Path,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let lockfile = read_lockfile(lockfile).await?;
+    // git や path の gem は index にないので、GEM の gem からたどる
+    let mut roots = lockfile.gem_specs;
+    roots.dedup();
+    // ロックしたバージョンが使わない依存も、次の解決で読むので残す
+    let keep: HashSet<String> = client
+        .resolve_dependencies(roots)
+        .await?
+        .into_keys()
+        .collect();
+    for file_name in client.prune_cache(&keep, dry_run).await? {
+        if dry_run {
+            status!("Would have removed the cached index of {}", file_name);
+        } else {
+            status!("Removing the cached index of {}", file_name);
+        }
+    }
+    Ok(())
+}
+
+/// The versions of `gem` the sources offer that meet `requirement`, oldest
+/// first. Empty for a gem no source knows.
+async fn gem_versions<S: GemSource + Sync>(
+    client: &MultiSourceClient<S>,
+    gem: &str,
+    requirement: Option<&RichReq>,
+) -> compact_index_client::Result<Vec<RubyVersion>> {
+    let mut versions: Vec<RubyVersion> = client
+        .info(gem)
+        .await?
+        .into_iter()
+        .map(|(_, v)| v.version)
+        .filter(|version| requirement.is_none_or(|req| req.contains(version)))
+        .collect();
+    versions.sort();
+    versions.dedup();
+    Ok(versions)
+}
+
+async fn print_versions(
+    client: &MultiSourceClient,
+    gem: &str,
+    requirement: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let requirement = requirement
+        .map(|req| parse_req_any(&req.split("||").collect::<Vec<_>>()))
+        .transpose()?;
+    let versions = gem_versions(client, gem, requirement.as_ref()).await?;
+    if versions.is_empty() {
+        if client.info(gem).await?.is_empty() {
+            return Err(format!("Could not find gem '{}' in any of the gem sources", gem).into());
+        }
+        status!("No versions of {} match the requirement", gem);
+    }
+    for version in versions {
+        println!("{}", version);
+    }
+    Ok(())
+}
+
+// bundle config: 設定の一覧、取得、保存
+fn config(key: Option<&str>, value: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let dir = Path::new(".");
+    let mut config = BundleConfig::load(dir)?;
+    match (key, value) {
+        (Some(key), Some(value)) => {
+            config.set(&BundleConfig::key(key), value);
+            config.save(dir)?;
+        }
+        (Some(key), None) => match config.get(&BundleConfig::key(key)) {
+            Some(value) => println!("{}", value),
+            None => status!("You have not configured a value for `{}`", key),
+        },
+        (None, _) => {
+            for (key, value) in config.settings() {
+                println!("{}: \"{}\"", key, value);
+            }
+        }
+    }
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() {
+    let cli = cli::Cli::parse();
+    if let Err(err) = run(&cli).await {
+        eprintln!("{}", failure::report(err.as_ref(), cli.trace()));
+        std::process::exit(failure::exit_code(err.as_ref()));
+    }
+}
+
+async fn run(cli: &cli::Cli) -> Result<(), Box<dyn Error>> {
+    use tracing::level_filters::LevelFilter;
     use tracing_subscriber::util::SubscriberInitExt;
     use tracing_subscriber::{EnvFilter, fmt, prelude::__tracing_subscriber_SubscriberExt};
 
+    let started = std::time::Instant::now();
+
     tracing_subscriber::registry()
         .with(
+            // ログは stderr に出し、stdout は lock --print などの出力に使う
             fmt::layer()
+                .with_writer(std::io::stderr)
                 .with_span_events(FmtSpan::CLOSE)
                 .event_format(tracing_subscriber::fmt::format().without_time()),
         )
-        .with(EnvFilter::from_default_env())
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::from_level(cli.log_level()).into())
+                .from_env_lossy(),
+        )
         .init();
+    output::set_quiet(cli.quiet());
 
-    let cli = cli::Cli::parse();
+    if let Some(cli::Command::Config { key, value }) = cli.command() {
+        return config(key.as_deref(), value.as_deref());
+    }
 
-    let gemfile = parse_gemfile();
+    let mut gemfile = parse_gemfile(Path::new("Gemfile")).map_err(Failure::Gemfile)?;
 
-    let gems = CompactIndexClient::new("https://rubygems.org/", Path::new(".newbundle"))
-        .await?
-        .resolve_dependencies(
-            gemfile
-                .dependencies
-                .iter()
-                .map(|dep| dep.name.clone())
-                .collect(),
-        )
-        .await?;
+    let running_ruby = detect_ruby_engine();
+    if let Some(directive) = &gemfile.ruby {
+        match &running_ruby {
+            Some((engine, engine_version)) => check_ruby_engine(directive, engine, engine_version)?,
+            None => warn!("Could not detect the running Ruby; skipping the engine check"),
+        }
+        if !directive.versions.is_empty() {
+            match GemInstaller::get_ruby_version() {
+                Ok(version) => {
+                    if let Err(message) =
+                        check_ruby_version(directive, &RubyVersion::parse(&version))
+                    {
+                        if cli.strict_ruby() {
+                            return Err(Failure::Gemfile(message).into());
+                        }
+                        warn!("{}", message);
+                    }
+                }
+                Err(_) => warn!("Could not detect the running Ruby; skipping the version check"),
+            }
+        }
+    }
+
+    // install_if や platforms で除外された gem は解決もインストールもしない
+    let engine = running_ruby
+        .as_ref()
+        .map_or("ruby", |(engine, _)| engine.as_str());
+    gemfile.dependencies.retain(|dep| {
+        let included = dep.should_include && platform_matches(&dep.platforms, engine);
+        if !included {
+            info!("Skipping {}, which is not used on this platform", dep.name);
+        }
+        included
+    });
+    gemfile.dependencies = merge_duplicate_gems(std::mem::take(&mut gemfile.dependencies))
+        .map_err(Failure::Gemfile)?;
+    for dep in &gemfile.dependencies {
+        dep.constraint().map_err(Failure::Gemfile)?;
+        if dep.requires().is_empty() {
+            info!(
+                "{} is not loaded by Bundler.require (require: false)",
+                dep.name
+            );
+        }
+    }
+
+    let sources = if gemfile.sources.is_empty() {
+        vec!["https://rubygems.org/".to_string()]
+    } else {
+        gemfile.sources.clone()
+    };
+    let mut config = BundleConfig::load(Path::new("."))?;
+    let mut clients = Vec::new();
+    // Gemfile.lock にはミラーではなく元の remote を書く
+    let mut remotes: HashMap<String, String> = HashMap::new();
+    for source in &sources {
+        let mut url = source.clone();
+        if let Ok(remote) = Url::parse(source)
+            && let Some(mirror) = config.mirror_for(&remote)
+        {
+            info!("Using {} as a mirror of {}", mirror, remote);
+            remotes.insert(mirror.to_string(), remote.to_string());
+            url = mirror.to_string();
+        }
+        let mut client =
+            CompactIndexClient::new(&url, Path::new(".newbundle"), http_options(cli, &config))
+                .await?;
+        client.set_fresh_within(cli.fresh_within());
+        client.set_offline(cli.local());
+        client.set_no_cache(cli.no_cache());
+        clients.push(client);
+    }
+    let mut client = MultiSourceClient::new(clients);
+    let graph_cache = Path::new(".newbundle").join("graph");
+    // --no-cache では保存した依存グラフも使わずに作り直す
+    if cli.no_cache() && graph_cache.exists() {
+        std::fs::remove_file(&graph_cache)?;
+    }
+    client.set_graph_cache(graph_cache);
+    client.set_max_versions(cli.max_versions());
+
+    if let Some(cli::Command::Outdated) = cli.command() {
+        return print_outdated(&client, &gemfile, cli.lockfile()).await;
+    }
+    if let Some(cli::Command::Versions { gem, requirement }) = cli.command() {
+        return print_versions(&client, gem, requirement.as_deref()).await;
+    }
+    if let Some(cli::Command::Clean { dry_run, force }) = cli.command() {
+        return clean(cli, &config, *dry_run, *force).await;
+    }
+    if let Some(cli::Command::Cache {
+        command: cli::CacheCommand::Prune { dry_run },
+    }) = cli.command()
+    {
+        return prune_cache(&client, cli.lockfile(), *dry_run).await;
+    }
+
+    // path: の gem はネットワークを使わず gemspec から読む。git: の gem はチェックアウトしてから同じように読む
+    let mut path_gems: HashMap<String, (LockSource, Gemspec)> = HashMap::new();
+    for dep in &gemfile.dependencies {
+        let (source, dir) = match (&dep.path, &dep.git) {
+            (Some(path), _) => (LockSource::Path(path.clone()), PathBuf::from(path)),
+            (None, Some(remote)) => {
+                let reference = dep.git_reference();
+                let dir = Path::new(".newbundle").join("git").join(&dep.name);
+                info!("Checking out {} from {}", dep.name, remote);
+                let revision = git::checkout(remote, reference.as_ref(), &dir).map_err(|err| {
+                    Failure::Gemfile(format!(
+                        "Could not check out {} from {}: {}",
+                        dep.name, remote, err
+                    ))
+                })?;
+                let source = LockSource::Git {
+                    remote: remote.clone(),
+                    revision,
+                    reference,
+                };
+                (source, dir)
+            }
+            (None, None) => continue,
+        };
+        let spec = Gemspec::load(&dir, &dep.name).map_err(|err| {
+            Failure::Gemfile(format!(
+                "Could not load the gemspec of {}: {:#}",
+                dep.name, err
+            ))
+        })?;
+        path_gems.insert(dep.name.clone(), (source, spec));
+    }
+    let mut root_gems: Vec<String> = gemfile
+        .dependencies
+        .iter()
+        .filter(|dep| !path_gems.contains_key(&dep.name))
+        .map(|dep| dep.name.clone())
+        .collect();
+    for (_, spec) in path_gems.values() {
+        for (name, _) in &spec.dependencies {
+            if !path_gems.contains_key(name) && !root_gems.contains(name) {
+                root_gems.push(name.clone());
+            }
+        }
+    }
+    let gems = client.resolve_dependencies(root_gems).await?;
+    // 綴りの違う gem は index の綴りで取ってきているので、Gemfile の名前もそれに合わせる
+    for dep in &mut gemfile.dependencies {
+        if gems.contains_key(&dep.name) || path_gems.contains_key(&dep.name) {
+            continue;
+        }
+        if let Some(name) = gems
+            .keys()
+            .find(|name| name.eq_ignore_ascii_case(&dep.name))
+        {
+            dep.name = name.clone();
+        }
+    }
 
     // println!("gems: {}", gems.len());
 
     let mut resolver = Resolver::new();
+    if let Some(ruby) = target_ruby_version(gemfile.ruby.as_ref(), running_ruby.as_ref()) {
+        resolver.set_ruby_version(ruby);
+    }
+    resolver.set_platforms(cli.platforms());
+    resolver.set_prerelease(matches!(
+        cli.command(),
+        Some(cli::Command::Install { pre: true, .. } | cli::Command::Lock { pre: true, .. })
+    ));
+
+    // 既存の Gemfile.lock のバージョンはなるべく維持する
+    let previous_lock = read_lockfile(cli.lockfile()).await.ok();
+    if let Some(lockfile) = &previous_lock {
+        for (gem, version) in &lockfile.specs {
+            resolver.lock(gem.clone(), version.clone());
+        }
+        for (gem, version) in resolver.unusable_locked() {
+            warn!(
+                "{} locks {} ({}), which none of the platforms {} can use; choosing another build",
+                cli.lockfile().display(),
+                gem,
+                version,
+                cli.platforms().join(", ")
+            );
+        }
+    }
 
-    for (gem, versions) in gems {
+    for (_, spec) in path_gems.values() {
+        let constraints = spec
+            .dependencies
+            .iter()
+            .map(|(name, requirements)| {
+                let (req, req_str) = parse_req(&requirements.join(","), ",").map_err(|err| {
+                    Failure::Gemfile(format!("{} for {} in {}.gemspec", err, name, spec.name))
+                })?;
+                Ok((name.clone(), req, req_str))
+            })
+            .collect::<Result<_, Failure>>()?;
+        resolver.add_dependencies(spec.name.clone(), spec.version.clone(), constraints);
+    }
+    // HashMap の順序に左右されないよう、gem 名の順に渡す
+    let mut sorted_gems: Vec<_> = gems.iter().collect();
+    sorted_gems.sort_by(|a, b| a.0.cmp(b.0));
+    for (gem, versions) in sorted_gems {
+        // path: の gem はリモートの同名 gem より優先する
+        if path_gems.contains_key(gem) {
+            continue;
+        }
         // if gem == "grpc-google-iam-v1" {
         //     println!("Ok grpc-google-iam-v1: {:?}", versions);
         // }
-        for v in versions.into_iter().rev() {
+        for (_, v) in versions.iter().rev() {
+            if !resolver.supports_ruby(v.required_ruby_version.as_ref()) {
+                debug!(
+                    "Skipping {} {}, which does not support this Ruby",
+                    gem, v.version
+                );
+                continue;
+            }
             // if gem == "grpc-google-iam-v1" {
             //     if v.version.to_string() == "1.11.0" {
             //         println!("Ok mainsssssssssssss: {:?}", v);
@@ -113,70 +858,210 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     )
                 })
                 .collect();
-            resolver.add_dependencies(gem.clone(), v.version, constraints);
+            resolver.add_dependencies(gem.clone(), v.version.clone(), constraints);
         }
     }
+    // --only でも解決と lockfile は全グループを対象にし、インストールだけを絞る
+    let only = match cli.command() {
+        Some(cli::Command::Install { only, .. }) if !only.is_empty() => only.clone(),
+        Some(cli::Command::Install { .. }) => config.only(),
+        _ => vec![],
+    };
+    let only_roots: Option<Vec<String>> = (!only.is_empty()).then(|| {
+        gemfile
+            .dependencies
+            .iter()
+            .filter(|gem| gem.in_groups(&only))
+            .map(|gem| gem.name.clone())
+            .collect()
+    });
+    let gemfile_dependencies = gemfile.dependencies.len();
     let root_pkg = "root".to_string();
     let root_ver = RubyVersion::new(0, 0, 0);
     let root_constraints: Vec<(String, RichReq, Vec<String>)> = gemfile
         .dependencies
         .into_iter()
         .map(|gem| {
-            // semver::VersionReq から VS へ
-            let (vs, req_str) = match gem.requirement {
-                Some(req) => parse_req(&req, ","), // :contentReference[oaicite:1]{index=1}
-                None => parse_req("*", ","),
-            };
+            let (vs, req_str) = gem
+                .constraint()
+                .expect("requirements are checked after reading the Gemfile");
             (gem.name, vs, req_str)
         })
         .collect();
+    let frozen = match cli.command() {
+        Some(cli::Command::Install { frozen, .. }) => *frozen || config.frozen(),
+        _ => false,
+    };
+    // --frozen では Gemfile.lock を書き換えられないので、Gemfile が変わっていたら止める
+    if frozen && let Some(lockfile) = &previous_lock {
+        let changes = lockfile.dependency_changes(&root_constraints);
+        if !changes.is_empty() {
+            return Err(Failure::Gemfile(format!(
+                "The Gemfile changed since Gemfile.lock was written, which --frozen does not allow:\n  {}",
+                changes.join("\n  ")
+            ))
+            .into());
+        }
+    }
     resolver.add_dependencies(root_pkg, root_ver, root_constraints);
+    info!("Resolver stats: {}", resolver.stats());
 
-    let solution = resolver.resolve().expect("dependency resolution failed");
-    let solution_vec: Vec<(String, RubyVersion)> = solution
+    // Ctrl-C で解決を止められるようにする
+    let (resolver, solution) = resolver
+        .resolve_until(async {
+            if tokio::signal::ctrl_c().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+        .await;
+    let solution = solution
+        .map_err(|err| Failure::Resolution(format!("Could not resolve dependencies: {}", err)))?;
+    // ハンドラを登録すると SIGINT で終了しなくなるので、以降は自分で終了する
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    if let Some(cli::Command::Why { gem }) = cli.command() {
+        let paths = resolver.paths_to(&solution, "root", gem);
+        if paths.is_empty() {
+            return Err(format!("Could not find gem '{}' in the bundle", gem).into());
+        }
+        for path in paths {
+            let chain: Vec<&str> = path[1..].iter().rev().map(String::as_str).collect();
+            println!("{}", chain.join(" <- "));
+        }
+        return Ok(());
+    }
+    // キャッシュの info が古いと yank されたバージョンを選んでしまい、インストールが 404 になる
+    let mut from_index: Vec<(String, RubyVersion)> = solution
         .iter()
-        .map(|(k, v)| (k.clone(), v.clone()))
+        .filter(|(name, _)| name.as_str() != "root" && !path_gems.contains_key(*name))
+        .map(|(name, version)| (name.clone(), version.clone()))
+        .collect();
+    from_index.sort();
+    for (name, version) in client.unlisted(&from_index).await? {
+        let message = format!(
+            "{} ({}) is no longer listed by its source and may have been yanked",
+            name, version
+        );
+        if frozen {
+            return Err(Failure::Yanked(message).into());
+        }
+        warn!("{}", message);
+    }
+    let print = matches!(cli.command(), Some(cli::Command::Lock { print: true, .. }));
+    let hash = matches!(cli.command(), Some(cli::Command::Lock { hash: true, .. }));
+    // --print / --hash では標準出力をその結果だけにする
+    if let Some(lockfile) = previous_lock.as_ref().filter(|_| !print && !hash) {
+        let solution: Vec<_> = solution
+            .iter()
+            .map(|(name, version)| (name.clone(), version.clone()))
+            .collect();
+        for change in diff::diff_solutions(&lockfile.specs, &solution) {
+            status!("{}", change);
+        }
+    }
+    // どのソースから取ったかも残し、lockfile の remote とダウンロード先の両方に使う
+    let resolved: HashMap<String, (Url, GemVersion)> = solution
+        .iter()
+        .filter(|(name, _)| name.as_str() != "root")
+        .filter_map(|(name, version)| {
+            gems.get(name)?
+                .iter()
+                .find(|(_, v)| &v.version == version)
+                .map(|resolved| (name.clone(), resolved.clone()))
+        })
+        .collect();
+    let lock_sources = group_by_source(
+        &resolver.lock_specs(&solution),
+        &path_gems,
+        &resolved,
+        &remotes,
+    );
+
+    let installable = only_roots.map(|roots| resolver.reachable(&solution, roots));
+    let resolved_gems: HashMap<String, (Url, GemVersion)> = resolved
+        .into_iter()
+        .filter(|(name, _)| installable.as_ref().is_none_or(|set| set.contains(name)))
         .collect();
 
     // resolver.dependency_provider.prioritize(package, range, package_conflicts_counts)
 
-    write_lockfile(solution_vec, resolver, Path::new("./Gemfile.new.lock")).await?;
+    let dry_run = matches!(
+        cli.command(),
+        Some(cli::Command::Install { dry_run: true, .. })
+    );
+
+    // --path は Bundler と同じく .bundle/config に覚えておく
+    if let Some(cli::Command::Install {
+        path: Some(path), ..
+    }) = cli.command()
+    {
+        config.set("BUNDLE_PATH", &path.to_string_lossy());
+        if !dry_run {
+            config.save(Path::new("."))?;
+        }
+    }
+    let standalone = matches!(
+        cli.command(),
+        Some(cli::Command::Install {
+            standalone: true,
+            ..
+        })
+    );
+    let bundle_path = config
+        .path()
+        .or_else(|| standalone.then(|| PathBuf::from("bundle")));
+    if hash {
+        println!(
+            "{}",
+            lockfile_hash(lock_sources.clone(), &cli.platforms(), &resolver).await?
+        );
+    }
+    if print {
+        write_lockfile_to(
+            lock_sources,
+            &cli.platforms(),
+            &resolver,
+            tokio::io::stdout(),
+        )
+        .await?;
+    } else if !dry_run
+        && !write_lockfile(lock_sources, &cli.platforms(), &resolver, cli.lockfile()).await?
+    {
+        info!("{} is already up to date", cli.lockfile().display());
+    }
 
     match &cli.command() {
-        Some(cli::Command::Install) => (),
+        Some(cli::Command::Install { .. }) => (),
         Some(cli::Command::Exec { args }) => {
-            Executor::new(args.clone()).exec()?;
+            // install と同じ場所から gem を読む
+            let gem_home = new_installer(cli, &config, bundle_path.as_deref())?.get_gems_base_dir();
+            Executor::new(args.clone(), gem_home).exec()?;
             return Ok(());
         }
-        Some(cli::Command::Lock) => {
+        Some(cli::Command::Lock { .. }) => {
             return Ok(());
         }
+        Some(
+            cli::Command::Outdated
+            | cli::Command::Clean { .. }
+            | cli::Command::Cache { .. }
+            | cli::Command::Versions { .. }
+            | cli::Command::Config { .. },
+        ) => {
+            unreachable!("handled before resolution")
+        }
+        Some(cli::Command::Why { .. }) => unreachable!("handled after resolution"),
         None => {}
     }
 
-    // Bundlerのディレクトリ構造
-    let bundle_dir = dirs::home_dir()
-        .unwrap_or_else(|| env::current_dir().unwrap())
-        .join(".bundle");
-
-    // Gemキャッシュディレクトリ
-    let gem_cache_dir = bundle_dir.join("cache");
-
-    // Bundlerのインストールパス
-    let install_dir = match env::var("GEM_HOME") {
-        Ok(dir) => PathBuf::from(dir),
-        Err(_) => dirs::home_dir()
-            .unwrap_or_else(|| env::current_dir().unwrap())
-            .join(".gem"),
-    };
-
-    let api_url = "https://rubygems.org/";
-
     // Gemfileを解析
-    println!("Parsing Gemfile...");
+    debug!("Parsing Gemfile...");
 
     // Compact Index Clientを初期化
-    println!("Initializing Compact Index Client...");
+    debug!("Initializing Compact Index Client...");
 
     // 依存関係を解決
     // // println!("Resolving dependencies...");
@@ -188,12 +1073,528 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // //     println!("  {} ({})", name, version.version);
     // // }
 
-    // // gemをインストール
-    // println!("Installing gems...");
-    // let installer = GemInstaller::new(&install_dir, &gem_cache_dir, api_url)?;
-    // installer.install_gems(resolved_gems)?;
+    // gemをインストール
+    info!("Installing gems...");
+    let continue_on_error = matches!(
+        cli.command(),
+        Some(cli::Command::Install {
+            continue_on_error: true,
+            ..
+        })
+    );
+    let mut installer = new_installer(cli, &config, bundle_path.as_deref())?;
+    installer.set_continue_on_error(continue_on_error);
+    let summary = tokio::task::spawn_blocking(move || {
+        let gems: Option<HashMap<String, GemVersion>> = (standalone && !dry_run).then(|| {
+            resolved_gems
+                .iter()
+                .map(|(name, (_, version))| (name.clone(), version.clone()))
+                .collect()
+        });
+        let outcomes = installer.install_gems(resolved_gems, dry_run)?;
+        let summary = InstallSummary::new(&outcomes);
+        let mut failures: Vec<(String, installer::InstallerError)> = outcomes
+            .into_iter()
+            .filter_map(|(name, outcome)| Some((name, outcome.err()?)))
+            .collect();
+        // 続けない場合は最初の失敗で止まっているので、そのエラーをそのまま返す
+        if !continue_on_error && let Some((_, err)) = failures.pop() {
+            return Err(err);
+        }
+        if !failures.is_empty() {
+            return Err(installer::InstallerError::Failed(
+                failures
+                    .iter()
+                    .map(|(name, err)| format!("{}: {}", name, err))
+                    .collect(),
+            ));
+        }
+        if let Some(gems) = gems {
+            let path = installer.write_standalone_setup(&gems)?;
+            status!("Wrote {}", path.display());
+        }
+        Ok(summary)
+    })
+    .await??;
 
-    println!("Bundle install completed successfully!");
+    if !dry_run {
+        status!(
+            "{}",
+            install_summary(gemfile_dependencies, &summary, started.elapsed())
+        );
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::Path};
+
+    use crate::{
+        Gem, Gemfile, RubyDirective, check_ruby_engine, check_ruby_version,
+        compact_index_client::CompactIndexClient,
+        gem_versions,
+        gemfilelock::write_lockfile_to,
+        git::GitReference,
+        group_by_source,
+        http::HttpOptions,
+        install_summary,
+        installer::InstallSummary,
+        merge_duplicate_gems,
+        multi_source::MultiSourceClient,
+        parse_gemfile, platform_matches,
+        resolver::Resolver,
+        source::InMemorySource,
+        target_ruby_version,
+        test_support::mock_compact_index,
+        version::{RubyVersion, parse_req},
+    };
+
+    #[tokio::test]
+    async fn test_gem_versions() -> anyhow::Result<()> {
+        let server = mock_compact_index("grpc").await;
+        let dir = tempfile::tempdir()?;
+        let client = MultiSourceClient::new(vec![
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?,
+        ]);
+        let versions = |versions: &[&str]| -> Vec<RubyVersion> {
+            versions.iter().map(|v| RubyVersion::parse(v)).collect()
+        };
+
+        assert_eq!(
+            gem_versions(&client, "google-protobuf", None).await?,
+            versions(&["3.25.3", "3.25.5", "4.30.2"])
+        );
+        let req = parse_req("~> 3.25", ",").unwrap().0;
+        assert_eq!(
+            gem_versions(&client, "google-protobuf", Some(&req)).await?,
+            versions(&["3.25.3", "3.25.5"])
+        );
+        assert!(gem_versions(&client, "no-such-gem", None).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lockfile_lists_gems_under_their_source() -> anyhow::Result<()> {
+        let rubygems = InMemorySource::new("https://rubygems.org/")
+            .add("rails", "7.1.3", "rack:>= 2")
+            .add("rack", "3.0.9", "");
+        let private = InMemorySource::new("https://gems.example.com/").add("rack", "3.0.10", "");
+        let mirror = InMemorySource::new("https://mirror.example.com/").add("thor", "1.3.2", "");
+        let client = MultiSourceClient::new(vec![rubygems, private, mirror]);
+        let gems = client
+            .resolve_dependencies(vec!["rails".to_string(), "thor".to_string()])
+            .await?;
+        let solution = HashMap::from(
+            [("rails", "7.1.3"), ("rack", "3.0.10"), ("thor", "1.3.2")]
+                .map(|(name, version)| (name.to_string(), RubyVersion::parse(version))),
+        );
+        let remotes = HashMap::from([(
+            "https://mirror.example.com/".to_string(),
+            "https://internal.example.com/".to_string(),
+        )]);
+
+        let resolved = solution
+            .iter()
+            .map(|(name, version)| {
+                let resolved = gems[name]
+                    .iter()
+                    .find(|(_, v)| &v.version == version)
+                    .unwrap();
+                (name.clone(), resolved.clone())
+            })
+            .collect();
+
+        let specs: Vec<_> = solution.clone().into_iter().collect();
+        let sources = group_by_source(&specs, &HashMap::new(), &resolved, &remotes);
+        let mut lockfile = Vec::new();
+        write_lockfile_to(
+            sources,
+            &["ruby".to_string()],
+            &Resolver::new(),
+            &mut lockfile,
+        )
+        .await?;
+        let lockfile = String::from_utf8(lockfile)?;
+        assert!(lockfile.starts_with(
+            "GEM\n  remote: https://gems.example.com/\n  specs:\n    rack (3.0.10)\n\n\
+             GEM\n  remote: https://internal.example.com/\n  specs:\n    thor (1.3.2)\n\n\
+             GEM\n  remote: https://rubygems.org/\n  specs:\n    rails (7.1.3)\n\n"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_ruby_engine() {
+        let directive = RubyDirective {
+            versions: vec![],
+            engine: Some("jruby".to_string()),
+            engine_versions: vec!["~> 9.4".to_string()],
+        };
+        assert!(check_ruby_engine(&directive, "jruby", &RubyVersion::parse("9.4.5.0")).is_ok());
+
+        let err = check_ruby_engine(&directive, "ruby", &RubyVersion::parse("3.1.4")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Your Ruby engine is ruby, but your Gemfile specified jruby"
+        );
+
+        assert!(check_ruby_engine(&directive, "jruby", &RubyVersion::parse("9.3.0.0")).is_err());
+    }
+
+    #[test]
+    fn test_install_summary() {
+        let summary = InstallSummary {
+            installed: 1,
+            already_installed: 2,
+        };
+        assert_eq!(
+            install_summary(1, &summary, std::time::Duration::from_millis(1500)),
+            "Bundle complete! 1 Gemfile dependency, 3 gems now installed.\n\
+             1 newly installed, 2 already present, in 1.50s."
+        );
+    }
+
+    #[test]
+    fn test_check_ruby_version() {
+        let directive = |versions: &[&str]| RubyDirective {
+            versions: versions.iter().map(|v| v.to_string()).collect(),
+            engine: None,
+            engine_versions: vec![],
+        };
+        let ruby = RubyVersion::parse("3.3.0");
+
+        assert_eq!(
+            check_ruby_version(&directive(&["2.7.2"]), &ruby).unwrap_err(),
+            "Your Ruby version is 3.3.0, but your Gemfile specified 2.7.2"
+        );
+        assert!(check_ruby_version(&directive(&["3.3.0"]), &ruby).is_ok());
+        assert!(check_ruby_version(&directive(&[">= 2.7"]), &ruby).is_ok());
+        assert!(check_ruby_version(&directive(&["~> 3.2", "< 3.3"]), &ruby).is_err());
+        assert!(
+            check_ruby_version(
+                &directive(&[">= 3.3"]),
+                &RubyVersion::parse("3.4.0.preview1")
+            )
+            .is_ok()
+        );
+        assert!(check_ruby_version(&directive(&[]), &ruby).is_ok());
+    }
+
+    #[test]
+    fn test_target_ruby_version() {
+        let directive = |versions: &[&str]| RubyDirective {
+            versions: versions.iter().map(|v| v.to_string()).collect(),
+            engine: None,
+            engine_versions: vec![],
+        };
+        let cruby = ("ruby".to_string(), RubyVersion::parse("3.2.2"));
+        let jruby = ("jruby".to_string(), RubyVersion::parse("9.4.5.0"));
+
+        assert_eq!(
+            target_ruby_version(Some(&directive(&["3.3.0"])), Some(&cruby)),
+            Some(RubyVersion::parse("3.3.0"))
+        );
+        assert_eq!(
+            target_ruby_version(Some(&directive(&["~> 3.1"])), Some(&cruby)),
+            Some(RubyVersion::parse("3.2.2"))
+        );
+        assert_eq!(
+            target_ruby_version(None, Some(&cruby)),
+            Some(RubyVersion::parse("3.2.2"))
+        );
+        assert_eq!(target_ruby_version(None, Some(&jruby)), None);
+        assert_eq!(target_ruby_version(None, None), None);
+    }
+
+    #[test]
+    fn test_platform_matches() {
+        let platforms = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert!(platform_matches(&[], "jruby"));
+        assert!(platform_matches(&platforms(&["jruby"]), "jruby"));
+        assert!(!platform_matches(&platforms(&["jruby"]), "ruby"));
+        assert!(platform_matches(
+            &platforms(&["jruby", "truffleruby"]),
+            "truffleruby"
+        ));
+        assert!(!platform_matches(&platforms(&["ruby"]), "jruby"));
+        assert!(!platform_matches(&platforms(&["mri_31"]), "jruby"));
+        assert!(!platform_matches(&platforms(&["unknown"]), "ruby"));
+        if !cfg!(windows) {
+            assert!(platform_matches(&platforms(&["ruby"]), "ruby"));
+            assert!(platform_matches(&platforms(&["mri_31"]), "ruby"));
+            assert!(!platform_matches(&platforms(&["x64_mingw"]), "ruby"));
+        }
+    }
+
+    #[test]
+    fn test_gem_requires() -> serde_json::Result<()> {
+        let gem: Gem = serde_json::from_str(r#"{"name": "rails", "requirement": "~> 7.0"}"#)?;
+        assert_eq!(gem.requires(), vec!["rails"]);
+
+        let gem: Gem = serde_json::from_str(
+            r#"{"name": "bootsnap", "requirement": ">= 0", "autorequire": []}"#,
+        )?;
+        assert!(gem.requires().is_empty());
+
+        let gem: Gem = serde_json::from_str(
+            r#"{"name": "rspec-rails", "requirement": ">= 0", "autorequire": ["rspec/rails"]}"#,
+        )?;
+        assert_eq!(gem.requires(), vec!["rspec/rails"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gem_with_version_and_options() -> serde_json::Result<()> {
+        // gem 'x', '~> 1.0', require: false
+        let gem: Gem = serde_json::from_str(
+            r#"{"name": "x", "requirement": "~> 1.0", "autorequire": [], "groups": ["default"]}"#,
+        )?;
+        assert_eq!(gem.requirement.as_deref(), Some("~> 1.0"));
+        assert!(gem.requires().is_empty());
+
+        // gem 'x', '>= 1.0', require: 'x/railtie'
+        let gem: Gem = serde_json::from_str(
+            r#"{"name": "x", "requirement": ">= 1.0", "autorequire": ["x/railtie"]}"#,
+        )?;
+        let (req, req_str) = gem.constraint().unwrap();
+        assert_eq!(req_str, vec![">= 1.0"]);
+        assert!(req.range.contains(&RubyVersion::parse("1.2")));
+        assert_eq!(gem.requires(), vec!["x/railtie"]);
+        Ok(())
+    }
+
+    /// Evaluates `tests/fixtures/gemfiles/<name>` with eval_gemfile.rb, or
+    /// returns `None` when there is no Ruby with Bundler to run it.
+    fn evaluate_fixture(name: &str) -> Option<Gemfile> {
+        let ruby = std::process::Command::new("ruby")
+            .args(["-e", "require 'bundler'"])
+            .output();
+        if !ruby.is_ok_and(|output| output.status.success()) {
+            eprintln!("Skipping: evaluating {} needs ruby with Bundler", name);
+            return None;
+        }
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join("gemfiles")
+            .join(name);
+        Some(parse_gemfile(&path).unwrap())
+    }
+
+    #[test]
+    fn test_gem_github_shorthand() {
+        let Some(gemfile) = evaluate_fixture("github/Gemfile") else {
+            return;
+        };
+        let rails = &gemfile.dependencies[0];
+        assert_eq!(rails.name, "rails");
+        assert_eq!(
+            rails.git.as_deref(),
+            Some("https://github.com/rails/rails.git")
+        );
+        assert_eq!(
+            rails.git_reference(),
+            Some(GitReference::Branch("main".to_string()))
+        );
+        assert_eq!(gemfile.dependencies[1].git, None);
+    }
+
+    #[test]
+    fn test_gem_git_reference() -> serde_json::Result<()> {
+        let gem: Gem = serde_json::from_str(
+            r#"{"name": "rails", "git": "https://github.com/rails/rails.git", "tag": "v7.1.0", "ref": "0123abc"}"#,
+        )?;
+        assert_eq!(
+            gem.git_reference(),
+            Some(GitReference::Ref("0123abc".to_string()))
+        );
+
+        let gem: Gem = serde_json::from_str(r#"{"name": "rake", "requirement": ">= 0"}"#)?;
+        assert_eq!(gem.git, None);
+        assert_eq!(gem.git_reference(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gemfile_with_git_source() -> serde_json::Result<()> {
+        // git_source(:gist) { |id| "https://gist.github.com/#{id}.git" } は Bundler が評価するので、
+        // gem 'snippet', gist: 'abc123' は git: の gem として出てくる
+        let gemfile: Gemfile = serde_json::from_str(
+            r#"{
+                "dependencies": [
+                    {"name": "rake", "requirement": ">= 0", "groups": ["default"]},
+                    {"name": "snippet", "requirement": ">= 0", "groups": ["default"],
+                     "git": "https://gist.github.com/abc123.git", "branch": null}
+                ],
+                "ruby": null,
+                "sources": ["https://rubygems.org/"]
+            }"#,
+        )?;
+        let gems: Vec<_> = gemfile
+            .dependencies
+            .iter()
+            .map(|gem| (gem.name.as_str(), gem.git.as_deref()))
+            .collect();
+        assert_eq!(
+            gems,
+            vec![
+                ("rake", None),
+                ("snippet", Some("https://gist.github.com/abc123.git"))
+            ]
+        );
+        assert_eq!(gemfile.dependencies[1].git_reference(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gemfile_with_eval_gemfile() {
+        let Some(gemfile) = evaluate_fixture("eval_gemfile/Gemfile") else {
+            return;
+        };
+        let gems: Vec<_> = gemfile
+            .dependencies
+            .iter()
+            .map(|gem| (gem.name.as_str(), gem.groups.clone()))
+            .collect();
+        assert_eq!(
+            gems,
+            vec![
+                ("rails", vec!["default".to_string()]),
+                ("rspec", vec!["test".to_string()])
+            ]
+        );
+        assert!(!gemfile.dependencies[1].in_groups(&["default".to_string()]));
+        assert_eq!(gemfile.sources, vec!["https://rubygems.org/".to_string()]);
+    }
+
+    #[test]
+    fn test_gem_constraint_with_several_requirements() -> serde_json::Result<()> {
+        // gem 'puma', '~> 6.4', '>= 6.4.2'
+        let gem: Gem =
+            serde_json::from_str(r#"{"name": "puma", "requirement": "~> 6.4, >= 6.4.2"}"#)?;
+        let (req, req_str) = gem.constraint().unwrap();
+        assert_eq!(req_str, vec!["~> 6.4", ">= 6.4.2"]);
+        assert!(req.range.contains(&RubyVersion::parse("6.5.0")));
+        assert!(!req.range.contains(&RubyVersion::parse("6.4.1")));
+        assert!(!req.range.contains(&RubyVersion::parse("7.0.0")));
+
+        let gem: Gem = serde_json::from_str(r#"{"name": "rake", "requirement": null}"#)?;
+        assert!(
+            gem.constraint()
+                .unwrap()
+                .0
+                .range
+                .contains(&RubyVersion::parse("13.0"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_gem_constraint_rejects_malformed_requirement() -> serde_json::Result<()> {
+        let gem: Gem = serde_json::from_str(r#"{"name": "rails", "requirement": "~~ 7.0"}"#)?;
+        assert_eq!(
+            gem.constraint().unwrap_err(),
+            r#"invalid version requirement "~~ 7.0" for gem rails"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_gem_in_groups() -> serde_json::Result<()> {
+        let gemfile: Gemfile = serde_json::from_str(
+            r#"{"dependencies": [
+                {"name": "rails", "requirement": null, "groups": ["default"]},
+                {"name": "pry", "requirement": null, "groups": ["development"]},
+                {"name": "rspec", "requirement": null, "groups": ["development", "test"]},
+                {"name": "capybara", "requirement": null, "groups": ["test"]},
+                {"name": "puma", "requirement": null, "groups": ["development"]},
+                {"name": "puma", "requirement": null, "groups": ["default"]}
+            ]}"#,
+        )?;
+        let merged = merge_duplicate_gems(gemfile.dependencies).unwrap();
+        let only = |groups: &[&str]| {
+            let groups: Vec<String> = groups.iter().map(|g| g.to_string()).collect();
+            merged
+                .iter()
+                .filter(|gem| gem.in_groups(&groups))
+                .map(|gem| gem.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(only(&["test"]), vec!["rails", "rspec", "capybara", "puma"]);
+        assert_eq!(
+            only(&["development"]),
+            vec!["rails", "pry", "rspec", "puma"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_duplicate_gems() -> serde_json::Result<()> {
+        let gemfile: Gemfile = serde_json::from_str(
+            r#"{"dependencies": [
+                {"name": "rails", "requirement": "~> 7.0", "groups": ["default"]},
+                {"name": "rspec", "requirement": null, "groups": ["test"]},
+                {"name": "rails", "requirement": "~>7.0", "groups": ["test"]},
+                {"name": "rspec", "requirement": ">= 0", "groups": ["development"]}
+            ]}"#,
+        )?;
+        let merged = merge_duplicate_gems(gemfile.dependencies).unwrap();
+        let merged: Vec<_> = merged
+            .iter()
+            .map(|gem| {
+                (
+                    gem.name.as_str(),
+                    gem.requirement.as_deref(),
+                    gem.groups.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            merged,
+            vec![("rails", Some("~> 7.0"), 2), ("rspec", None, 2)]
+        );
+
+        // 両立できる要件でも、違っていればエラー
+        let gemfile: Gemfile = serde_json::from_str(
+            r#"{"dependencies": [
+                {"name": "rails", "requirement": ">= 7.0"},
+                {"name": "rails", "requirement": "< 8"}
+            ]}"#,
+        )?;
+        let err = merge_duplicate_gems(gemfile.dependencies).unwrap_err();
+        assert!(
+            err.ends_with("You specified: rails (>= 7.0) and rails (< 8)"),
+            "{}",
+            err
+        );
+
+        let gemfile: Gemfile = serde_json::from_str(
+            r#"{"dependencies": [
+                {"name": "rspec", "requirement": null},
+                {"name": "rspec", "requirement": "~> 3.12"}
+            ]}"#,
+        )?;
+        let err = merge_duplicate_gems(gemfile.dependencies).unwrap_err();
+        assert!(
+            err.ends_with("You specified: rspec (>= 0) and rspec (~> 3.12)"),
+            "{}",
+            err
+        );
+
+        let gemfile: Gemfile = serde_json::from_str(
+            r#"{"dependencies": [
+                {"name": "rails", "requirement": "~> 6.1"},
+                {"name": "rails", "requirement": "~> 7.0"}
+            ]}"#,
+        )?;
+        let err = merge_duplicate_gems(gemfile.dependencies).unwrap_err();
+        assert!(
+            err.ends_with("You specified: rails (~> 6.1) and rails (~> 7.0)"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,704 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use futures::{StreamExt, stream::FuturesUnordered};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{Level, debug, instrument};
+use url::Url;
+
+use crate::{
+    compact_index_client::{CompactIndexClient, CompactIndexError, GemVersion, Result},
+    source::GemSource,
+    version::RubyVersion,
+};
+
+/// Looks gems up across every `source` of a Gemfile.
+///
+/// When more than one source serves the same version of a gem, the last-declared
+/// source wins, like Bundler's global sources.
+pub struct MultiSourceClient<S = CompactIndexClient> {
+    // Gemfile の宣言順
+    clients: Vec<S>,
+    graph_cache: Option<PathBuf>,
+    max_versions: Option<usize>,
+}
+
+/// Bumped whenever the layout of [`SavedGraph`] changes; older files are ignored.
+const GRAPH_FORMAT: u32 = 7;
+
+/// The graph `resolve_dependencies` fetched last time, saved so the next run
+/// only fetches gems whose index entry changed.
+#[derive(Serialize, Deserialize)]
+struct SavedGraph {
+    format: u32,
+    /// The sources the graph was fetched from, in order.
+    remotes: Vec<String>,
+    /// The `max_versions` the graph was trimmed to.
+    max_versions: Option<usize>,
+    gems: HashMap<String, SavedGem>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedGem {
+    /// The combined `versions_checksum` of every source when this was fetched.
+    checksum: String,
+    versions: Vec<(String, GemVersion)>,
+}
+
+impl SavedGraph {
+    fn load(path: &Path, remotes: &[String], max_versions: Option<usize>) -> Option<Self> {
+        let graph: SavedGraph = bincode::deserialize(&fs::read(path).ok()?).ok()?;
+        (graph.format == GRAPH_FORMAT
+            && graph.remotes == remotes
+            && graph.max_versions == max_versions)
+            .then_some(graph)
+    }
+
+    // キャッシュは速くするためだけのものなので、書けなくてもエラーにしない
+    fn save(&self, path: &Path) {
+        let result = bincode::serialize(self)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| fs::write(path, bytes).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            debug!("Could not write {}: {}", path.display(), err);
+        }
+    }
+}
+
+impl<S: GemSource + Sync> MultiSourceClient<S> {
+    pub fn new(clients: Vec<S>) -> Self {
+        Self {
+            clients,
+            graph_cache: None,
+            max_versions: None,
+        }
+    }
+
+    /// Keeps only the newest `max` versions of every gem in the graph
+    /// `resolve_dependencies` returns. Older versions, even a locked one, are
+    /// never considered, and gems only they depend on are not fetched.
+    pub fn set_max_versions(&mut self, max: Option<usize>) {
+        self.max_versions = max;
+    }
+
+    /// Saves the fetched graph to `path` and reuses the gems in it whose
+    /// checksum in every source is unchanged on the next `resolve_dependencies`.
+    pub fn set_graph_cache(&mut self, path: PathBuf) {
+        self.graph_cache = Some(path);
+    }
+
+    /// The checksums of `gem_name` in every source, joined, or `None` when no
+    /// source can tell.
+    async fn versions_checksum(&self, gem_name: &str) -> Result<Option<String>> {
+        let mut checksums = Vec::new();
+        for client in &self.clients {
+            checksums.push(client.versions_checksum(gem_name).await?);
+        }
+        if checksums.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+        Ok(Some(
+            checksums
+                .into_iter()
+                .map(|checksum| checksum.unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(","),
+        ))
+    }
+
+    /// The spelling of `gem_name` used by the first source that serves it
+    /// only in another casing.
+    async fn canonical_name(&self, gem_name: &str) -> Result<Option<String>> {
+        for client in &self.clients {
+            if let Some(name) = client.canonical_name(gem_name).await? {
+                return Ok(Some(name));
+            }
+        }
+        Ok(None)
+    }
+
+    /// All versions of `gem_name` served by any source, with the source each one
+    /// comes from, sorted by version.
+    #[instrument(level = Level::DEBUG, skip_all)]
+    pub async fn info(&self, gem_name: &str) -> Result<Vec<(Url, GemVersion)>> {
+        // 後に宣言されたソースで上書きする
+        let mut merged: BTreeMap<RubyVersion, (Url, GemVersion)> = BTreeMap::new();
+        for client in &self.clients {
+            for version in client.versions(gem_name).await? {
+                merged.insert(version.version.clone(), (client.remote().clone(), version));
+            }
+        }
+        Ok(merged.into_values().collect())
+    }
+
+    /// One permit from the limiter of every source, or `None` when one of
+    /// them is exhausted.
+    fn try_acquire(&self) -> Option<Vec<OwnedSemaphorePermit>> {
+        self.clients
+            .iter()
+            .filter_map(|client| client.limiter())
+            .map(|limiter| limiter.try_acquire_owned().ok())
+            .collect()
+    }
+
+    /// One permit from the limiter of every source, waiting for them.
+    async fn acquire(&self) -> Vec<OwnedSemaphorePermit> {
+        let mut permits = Vec::new();
+        for limiter in self.clients.iter().filter_map(|client| client.limiter()) {
+            permits.push(
+                limiter
+                    .acquire_owned()
+                    .await
+                    .expect("limiter is never closed"),
+            );
+        }
+        permits
+    }
+
+    /// The versions of `name`, from `saved` when no source changed them since.
+    async fn fetch(
+        &self,
+        name: &str,
+        saved: &HashMap<String, SavedGem>,
+    ) -> Result<(Vec<(Url, GemVersion)>, Option<String>)> {
+        let checksum = self.versions_checksum(name).await?;
+        if let Some(checksum) = &checksum
+            && let Some(gem) = saved.get(name)
+            && &gem.checksum == checksum
+        {
+            debug!("Using the saved versions of {}", name);
+            let versions = gem
+                .versions
+                .iter()
+                .map(|(url, v)| Ok((Url::parse(url)?, v.clone())))
+                .collect::<Result<_>>()?;
+            return Ok((versions, Some(checksum.clone())));
+        }
+        Ok((self.info(name).await?, checksum))
+    }
+
+    /// Fetches the dependency graph reachable from `root_gems`.
+    #[instrument(level = Level::INFO, skip_all)]
+    pub async fn resolve_dependencies(
+        &self,
+        root_gems: Vec<String>,
+    ) -> Result<HashMap<String, Vec<(Url, GemVersion)>>> {
+        for client in &self.clients {
+            client.refresh().await?;
+        }
+
+        let remotes: Vec<String> = self
+            .clients
+            .iter()
+            .map(|client| client.remote().to_string())
+            .collect();
+        let saved = self
+            .graph_cache
+            .as_deref()
+            .and_then(|path| SavedGraph::load(path, &remotes, self.max_versions))
+            .map(|graph| graph.gems)
+            .unwrap_or_default();
+        let saved = &saved;
+
+        let mut graph = HashMap::new();
+        let mut checksums = HashMap::new();
+        let mut visited: HashSet<String> = root_gems.iter().cloned().collect();
+        let mut queue: VecDeque<String> = root_gems.into();
+        let mut tasks = FuturesUnordered::new();
+        loop {
+            // 見つけた順に、どのソースにも空きがある限り取りに行く
+            while let Some(name) = queue.front() {
+                let permits = if tasks.is_empty() {
+                    self.acquire().await
+                } else {
+                    match self.try_acquire() {
+                        Some(permits) => permits,
+                        None => break,
+                    }
+                };
+                let name = name.clone();
+                queue.pop_front();
+                tasks.push(async move {
+                    let result = self.fetch(&name, saved).await;
+                    drop(permits);
+                    (name, result)
+                });
+            }
+            let Some((name, result)) = tasks.next().await else {
+                break;
+            };
+            let (mut versions, checksum) = result?;
+            if versions.is_empty() {
+                // "Rails" のように綴りの違う名前は、index の綴りで取り直す
+                let Some(canonical) = self.canonical_name(&name).await? else {
+                    return Err(CompactIndexError::GemNotFound(name));
+                };
+                if visited.insert(canonical.clone()) {
+                    queue.push_back(canonical);
+                }
+                continue;
+            }
+            // info は古い順に並んでいるので、末尾の新しいものだけを残す
+            if let Some(max) = self.max_versions {
+                versions.drain(..versions.len().saturating_sub(max));
+            }
+            for (_, v) in &versions {
+                for d in &v.dependencies {
+                    if !d.requirement.is_satisfiable() {
+                        debug!("Skipping unsatisfiable dependency {}", d.name);
+                        continue;
+                    }
+                    if visited.insert(d.name.clone()) {
+                        queue.push_back(d.name.clone());
+                    }
+                }
+            }
+            if let Some(checksum) = checksum {
+                checksums.insert(name.clone(), checksum);
+            }
+            graph.insert(name, versions);
+        }
+
+        if let Some(path) = &self.graph_cache {
+            let gems = checksums
+                .into_iter()
+                .map(|(name, checksum)| {
+                    let versions = graph[&name]
+                        .iter()
+                        .map(|(url, v)| (url.to_string(), v.clone()))
+                        .collect();
+                    (name, SavedGem { checksum, versions })
+                })
+                .collect();
+            SavedGraph {
+                format: GRAPH_FORMAT,
+                remotes,
+                max_versions: self.max_versions,
+                gems,
+            }
+            .save(path);
+        }
+        Ok(graph)
+    }
+}
+
+impl MultiSourceClient<CompactIndexClient> {
+    /// Every version of `gem_name` any source serves, oldest first.
+    pub async fn versions_for(&self, gem_name: &str) -> Result<Vec<RubyVersion>> {
+        let mut versions = BTreeSet::new();
+        for client in &self.clients {
+            versions.extend(client.versions_for(gem_name).await?);
+        }
+        Ok(versions.into_iter().collect())
+    }
+
+    /// The entries of `resolved` that no source lists in `/versions` any more,
+    /// e.g. because they were yanked after their info file was cached.
+    pub async fn unlisted(
+        &self,
+        resolved: &[(String, RubyVersion)],
+    ) -> Result<Vec<(String, RubyVersion)>> {
+        let names: Vec<String> = resolved.iter().map(|(name, _)| name.clone()).collect();
+        let mut listed: HashMap<String, HashSet<RubyVersion>> = HashMap::new();
+        for client in &self.clients {
+            for (name, versions) in client.versions(names.clone()).await? {
+                listed.entry(name).or_default().extend(versions);
+            }
+        }
+        Ok(resolved
+            .iter()
+            .filter(|(name, version)| !listed.get(name).is_some_and(|v| v.contains(version)))
+            .cloned()
+            .collect())
+    }
+
+    /// Removes the cached index entries of gems not in `keep` from every
+    /// source, and returns the cached gems removed, sorted.
+    pub async fn prune_cache(&self, keep: &HashSet<String>, dry_run: bool) -> Result<Vec<String>> {
+        let mut removed = BTreeSet::new();
+        for client in &self.clients {
+            removed.extend(client.prune_info(keep, dry_run).await?);
+        }
+        Ok(removed.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{method, path},
+    };
+
+    use crate::{
+        compact_index_client::{CompactIndexClient, CompactIndexError},
+        http::HttpOptions,
+        multi_source::{MultiSourceClient, SavedGraph},
+        source::InMemorySource,
+        test_support::mock_compact_index,
+        version::RubyVersion,
+    };
+
+    async fn mock_source(infos: &[(&str, &str)]) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string("created_at: 2024-01-01\n---\n"),
+            )
+            .mount(&server)
+            .await;
+        for (name, body) in infos {
+            Mock::given(method("GET"))
+                .and(path(format!("/info/{}", name)))
+                .respond_with(ResponseTemplate::new(200).set_body_string(*body))
+                .mount(&server)
+                .await;
+        }
+        server
+    }
+
+    #[tokio::test]
+    async fn test_last_source_wins() -> anyhow::Result<()> {
+        let first = mock_source(&[("a", "---\n1.0.0 |checksum:01\n2.0.0 |checksum:02\n")]).await;
+        let second = mock_source(&[
+            ("a", "---\n2.0.0 b:>= 1.0|checksum:12\n"),
+            ("b", "---\n1.0.0 |checksum:11\n"),
+        ])
+        .await;
+
+        let dir = tempfile::tempdir()?;
+        let mut clients = Vec::new();
+        for server in [&first, &second] {
+            clients.push(
+                CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?,
+            );
+        }
+        let client = MultiSourceClient::new(clients);
+        let graph = client.resolve_dependencies(vec!["a".to_string()]).await?;
+
+        let a: Vec<_> = graph["a"]
+            .iter()
+            .map(|(url, v)| (url.to_string(), v.version.to_string()))
+            .collect();
+        assert_eq!(
+            a,
+            vec![
+                (format!("{}/", first.uri()), "1.0.0".to_string()),
+                (format!("{}/", second.uri()), "2.0.0".to_string()),
+            ]
+        );
+        assert_eq!(graph["a"][1].1.dependencies[0].name, "b");
+
+        let b: Vec<_> = graph["b"].iter().map(|(url, _)| url.to_string()).collect();
+        assert_eq!(b, vec![format!("{}/", second.uri())]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune_cache() -> anyhow::Result<()> {
+        let server = mock_source(&[
+            ("a", "---\n1.0.0 b:>= 1.0|checksum:01\n"),
+            ("b", "---\n1.0.0 |checksum:11\n"),
+        ])
+        .await;
+        let dir = tempfile::tempdir()?;
+        let client = MultiSourceClient::new(vec![
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?,
+        ]);
+        let keep = client
+            .resolve_dependencies(vec!["a".to_string()])
+            .await?
+            .into_keys()
+            .collect();
+
+        // 以前の Gemfile で使っていた gem のキャッシュ
+        let cache_dir = std::fs::read_dir(dir.path().join("cache").join("compact_index"))?
+            .next()
+            .unwrap()?
+            .path();
+        for sub_dir in ["info", "info-etags", "info-parsed"] {
+            std::fs::write(cache_dir.join(sub_dir).join("old"), "")?;
+        }
+        let exists = |sub_dir: &str, name: &str| cache_dir.join(sub_dir).join(name).exists();
+
+        assert_eq!(client.prune_cache(&keep, true).await?, vec!["old"]);
+        assert!(exists("info", "old"));
+
+        assert_eq!(client.prune_cache(&keep, false).await?, vec!["old"]);
+        for sub_dir in ["info", "info-etags", "info-parsed"] {
+            assert!(!exists(sub_dir, "old"));
+        }
+        assert!(exists("info", "a"));
+        assert!(exists("info", "b"));
+        assert!(client.prune_cache(&keep, false).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_dependencies_missing_gem() {
+        let source = InMemorySource::new("https://rubygems.org/").add("a", "1.0.0", "b:>= 1");
+        let client = MultiSourceClient::new(vec![source]);
+        let err = client
+            .resolve_dependencies(vec!["a".to_string()])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CompactIndexError::GemNotFound(name) if name == "b"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_dependencies_skips_unsatisfiable() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string("created_at: 2024-01-01\n---\n"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/a"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("---\n1.0.0 b:< 0,c:>= 1.0|checksum:00\n"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/b"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("---\n1.0.0 |checksum:00\n"))
+            .expect(0)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/c"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("---\n1.0.0 |checksum:00\n"))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client = MultiSourceClient::new(vec![
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?,
+        ]);
+        let graph = client.resolve_dependencies(vec!["a".to_string()]).await?;
+
+        let mut gems: Vec<_> = graph.keys().cloned().collect();
+        gems.sort();
+        assert_eq!(gems, vec!["a", "c"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_dependencies_fixes_casing() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "created_at: 2024-01-01\n---\nrails 7.1.0 355c2cd936023ee7c6081a6b236c918a\nrack 3.0.0 909ca247db019bdacfecc9a4a9e978c3\n",
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/rails"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string("---\n7.1.0 rack:>= 2|checksum:00\n"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/info/rack"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("---\n3.0.0 |checksum:00\n"))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client = MultiSourceClient::new(vec![
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?,
+        ]);
+        let graph = client
+            .resolve_dependencies(vec!["Rails".to_string()])
+            .await?;
+
+        let mut gems: Vec<_> = graph.keys().cloned().collect();
+        gems.sort();
+        assert_eq!(gems, vec!["rack", "rails"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_versions() -> anyhow::Result<()> {
+        let source = || {
+            InMemorySource::new("https://gems.example.com/")
+                .add("rails", "6.1.7", "sprockets:>= 3")
+                .add("rails", "7.0.8", "")
+                .add("rails", "7.1.3", "rack:>= 2")
+                .add("sprockets", "4.2.1", "")
+                .add("rack", "3.0.9", "")
+        };
+        let graph = |max| async move {
+            let mut client = MultiSourceClient::new(vec![source()]);
+            client.set_max_versions(max);
+            let graph = client
+                .resolve_dependencies(vec!["rails".to_string()])
+                .await?;
+            let mut gems: Vec<_> = graph
+                .into_iter()
+                .map(|(name, versions)| {
+                    let versions: Vec<_> = versions
+                        .iter()
+                        .map(|(_, v)| v.version.to_string())
+                        .collect();
+                    (name, versions)
+                })
+                .collect();
+            gems.sort();
+            anyhow::Ok(gems)
+        };
+
+        assert_eq!(graph(None).await?.len(), 3);
+        assert_eq!(
+            graph(Some(2)).await?,
+            vec![
+                ("rack".to_string(), vec!["3.0.9".to_string()]),
+                (
+                    "rails".to_string(),
+                    vec!["7.0.8".to_string(), "7.1.3".to_string()]
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unlisted_finds_yanked_versions() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "created_at: 2024-01-01\n---\na 1.0.0,2.0.0 00\nb 1.0.0 00\na -2.0.0 01\n",
+            ))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let client = MultiSourceClient::new(vec![
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?,
+        ]);
+        let version = |name: &str, version: &str| (name.to_string(), RubyVersion::parse(version));
+        let unlisted = client
+            .unlisted(&[
+                version("a", "1.0.0"),
+                version("a", "2.0.0"),
+                version("b", "1.0.0"),
+                version("c", "1.0.0"),
+            ])
+            .await?;
+
+        assert_eq!(unlisted, vec![version("a", "2.0.0"), version("c", "1.0.0")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serial_jobs() -> anyhow::Result<()> {
+        // 実行ごとに新しいサーバーとキャッシュを使い、info を取った順も返す
+        let resolve = |jobs: Option<usize>| async move {
+            let server = mock_compact_index("grpc").await;
+            let cache = tempfile::tempdir()?;
+            let options = HttpOptions {
+                jobs: jobs.unwrap_or(HttpOptions::default().jobs),
+                ..HttpOptions::default()
+            };
+            let client = MultiSourceClient::new(vec![
+                CompactIndexClient::new(&server.uri(), cache.path(), options).await?,
+            ]);
+            let mut graph: Vec<_> = client
+                .resolve_dependencies(vec!["grpc-google-iam-v1".to_string()])
+                .await?
+                .into_iter()
+                .map(|(name, versions)| {
+                    let versions: Vec<_> = versions.into_iter().map(|(_, v)| v.version).collect();
+                    (name, versions)
+                })
+                .collect();
+            graph.sort();
+            let fetched: Vec<_> = server
+                .received_requests()
+                .await
+                .unwrap()
+                .iter()
+                .map(|request| request.url.path().to_string())
+                .filter(|path| path.starts_with("/info/"))
+                .collect();
+            anyhow::Ok((graph, fetched))
+        };
+
+        let (parallel, _) = resolve(None).await?;
+        let (serial, order) = resolve(Some(1)).await?;
+        assert_eq!(serial, parallel);
+        assert_eq!(
+            order,
+            vec![
+                "/info/grpc-google-iam-v1",
+                "/info/google-protobuf",
+                "/info/googleapis-common-protos",
+                "/info/grpc",
+                "/info/googleapis-common-protos-types",
+            ]
+        );
+        assert_eq!(resolve(Some(1)).await?.1, order);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_graph_cache() -> anyhow::Result<()> {
+        let server = mock_compact_index("grpc").await;
+        let dir = tempfile::tempdir()?;
+        let graph_path = dir.path().join("graph");
+        let remotes = vec![format!("{}/", server.uri())];
+        let resolve = || async {
+            // 実行ごとに info のキャッシュは捨て、グラフのキャッシュだけを残す
+            let cache = tempfile::tempdir()?;
+            let mut client = MultiSourceClient::new(vec![
+                CompactIndexClient::new(&server.uri(), cache.path(), HttpOptions::default())
+                    .await?,
+            ]);
+            client.set_graph_cache(graph_path.clone());
+            let graph = client
+                .resolve_dependencies(vec!["grpc".to_string()])
+                .await?;
+            let mut gems: Vec<_> = graph
+                .into_iter()
+                .map(|(name, versions)| (name, versions.len()))
+                .collect();
+            gems.sort();
+            anyhow::Ok(gems)
+        };
+        let info_requests = || async {
+            let requests = server.received_requests().await.unwrap();
+            requests
+                .iter()
+                .filter(|request| request.url.path().starts_with("/info/"))
+                .count()
+        };
+
+        let first = resolve().await?;
+        let fetched = first.len();
+        assert_eq!(info_requests().await, fetched);
+
+        let second = resolve().await?;
+        assert_eq!(second, first);
+        assert_eq!(info_requests().await, fetched);
+
+        // /versions の checksum が変わった gem だけを取り直す
+        let mut saved = SavedGraph::load(&graph_path, &remotes, None).unwrap();
+        saved.gems.get_mut("grpc").unwrap().checksum = "stale".to_string();
+        saved.save(&graph_path);
+        assert_eq!(resolve().await?, first);
+        assert_eq!(info_requests().await, fetched + 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+
+use futures::{StreamExt, stream};
+use pubgrub::VersionSet;
+
+use crate::{
+    compact_index_client::Result,
+    gemfilelock::{Lockfile, is_unconstrained},
+    multi_source::MultiSourceClient,
+    version::{RichReq, RubyVersion, parse_req},
+};
+
+#[derive(Debug, PartialEq)]
+pub struct OutdatedGem {
+    pub name: String,
+    pub installed: RubyVersion,
+    pub newest: RubyVersion,
+    /// The newest release the Gemfile requirement allows, e.g. `6.1.7.8` under
+    /// `~> 6.1` while `7.1.0` is the newest. Without a requirement it is `newest`.
+    pub newest_allowed: Option<RubyVersion>,
+    pub requested: Option<String>,
+    /// The upper bound of the Gemfile requirement that excludes the newest
+    /// release, e.g. `< 7`, when one does.
+    pub held_back_by: Option<String>,
+}
+
+impl std::fmt::Display for OutdatedGem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "* {} (newest {}", self.name, self.newest)?;
+        if let Some(allowed) = &self.newest_allowed
+            && allowed != &self.newest
+        {
+            write!(f, ", newest allowed {}", allowed)?;
+        }
+        write!(f, ", installed {}", self.installed)?;
+        if let Some(requested) = &self.requested {
+            write!(f, ", requested {}", requested)?;
+        }
+        if let Some(bound) = &self.held_back_by {
+            write!(f, ", held back by {}", bound)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Fetches the available versions of every locked gem and compares them.
+pub async fn outdated(
+    client: &MultiSourceClient,
+    lockfile: &Lockfile,
+    requirements: &HashMap<String, String>,
+) -> Result<Vec<OutdatedGem>> {
+    let names: HashSet<String> = lockfile.specs.iter().map(|(n, _)| n.clone()).collect();
+    let available = stream::iter(names)
+        .map(|name| async move {
+            let versions = client.versions_for(&name).await?;
+            Ok((name, versions))
+        })
+        .buffer_unordered(num_cpus::get())
+        .collect::<Vec<Result<(String, Vec<RubyVersion>)>>>()
+        .await
+        .into_iter()
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    Ok(find_outdated(&lockfile.specs, requirements, &available))
+}
+
+/// Lists locked gems with a newer stable release, sorted by name, with the
+/// newest release overall and the newest one the Gemfile requirement allows.
+pub fn find_outdated(
+    locked: &[(String, RubyVersion)],
+    requirements: &HashMap<String, String>,
+    available: &HashMap<String, Vec<RubyVersion>>,
+) -> Vec<OutdatedGem> {
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    for (name, installed) in locked {
+        // Platform variants of the same gem are listed once.
+        if !seen.insert(name) {
+            continue;
+        }
+        let releases = available
+            .get(name)
+            .into_iter()
+            .flatten()
+            // 同じバージョンのプラットフォーム違いを新しいものと数えない
+            .filter(|v| !v.is_prerelease() && !v.is_platform());
+        let Some(newest) = releases.clone().max() else {
+            continue;
+        };
+        if newest <= installed {
+            continue;
+        }
+        let parsed = requirements
+            .get(name)
+            .and_then(|req| parse_req(req, ",").ok().map(|parsed| (req, parsed)));
+        // Bundler と同じく、">= 0" のように何も制限しない要件は表示しない
+        let requested = parsed
+            .as_ref()
+            .filter(|(_, (_, reqs))| !is_unconstrained(reqs))
+            .map(|(req, _)| req.to_string());
+        let req = parsed.map(|(_, (req, _))| req);
+        let newest_allowed = match &req {
+            Some(req) => releases.filter(|v| req.contains(v)).max(),
+            None => Some(newest),
+        };
+        let held_back_by = req.and_then(|req| holding_bound(&req, newest));
+        result.push(OutdatedGem {
+            name: name.clone(),
+            installed: installed.clone(),
+            newest: newest.clone(),
+            newest_allowed: newest_allowed.cloned(),
+            requested,
+            held_back_by,
+        });
+    }
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    result
+}
+
+// newest を含まないとき、そのすぐ下で区間を閉じている上限
+fn holding_bound(req: &RichReq, newest: &RubyVersion) -> Option<String> {
+    if req.contains(newest) {
+        return None;
+    }
+    req.bounds()
+        .into_iter()
+        .filter_map(|(_, upper)| match upper {
+            Bound::Excluded(v) if &v <= newest => Some((format!("< {}", v), v)),
+            Bound::Included(v) if &v < newest => Some((format!("<= {}", v), v)),
+            _ => None,
+        })
+        .max_by(|a, b| a.1.cmp(&b.1))
+        .map(|(text, _)| text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{outdated::find_outdated, version::RubyVersion};
+
+    fn versions(vs: &[&str]) -> Vec<RubyVersion> {
+        vs.iter().map(|v| RubyVersion::parse(v)).collect()
+    }
+
+    #[test]
+    fn test_find_outdated() {
+        let locked = vec![
+            ("rails".to_string(), RubyVersion::parse("6.1.7")),
+            ("rack".to_string(), RubyVersion::parse("2.2.0")),
+            ("puma".to_string(), RubyVersion::parse("6.4.0")),
+        ];
+        let requirements = HashMap::from([
+            ("rails".to_string(), "~> 6.1".to_string()),
+            ("puma".to_string(), ">= 6.0".to_string()),
+            ("rack".to_string(), ">= 0".to_string()),
+        ]);
+        let available = HashMap::from([
+            (
+                "rails".to_string(),
+                versions(&["6.1.7", "6.1.7.8", "7.1.0", "7.2.0.rc1"]),
+            ),
+            ("rack".to_string(), versions(&["2.2.0", "2.2.8"])),
+            (
+                "puma".to_string(),
+                versions(&["6.3.0", "6.4.0", "6.4.0-java"]),
+            ),
+        ]);
+
+        let outdated = find_outdated(&locked, &requirements, &available);
+        assert_eq!(outdated.len(), 2);
+
+        assert_eq!(outdated[0].name, "rack");
+        assert_eq!(outdated[0].held_back_by, None);
+        assert_eq!(
+            outdated[0].to_string(),
+            "* rack (newest 2.2.8, installed 2.2.0)"
+        );
+
+        assert_eq!(outdated[1].name, "rails");
+        assert_eq!(outdated[1].held_back_by.as_deref(), Some("< 7"));
+        assert_eq!(
+            outdated[1].newest_allowed,
+            Some(RubyVersion::parse("6.1.7.8"))
+        );
+        assert_eq!(
+            outdated[1].to_string(),
+            "* rails (newest 7.1.0, newest allowed 6.1.7.8, installed 6.1.7, requested ~> 6.1, held back by < 7)"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// A gem platform split into its parts like `Gem::Platform`, e.g.
+/// `arm64-darwin-23` is cpu `arm64`, os `darwin` and version `23`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    cpu: Option<String>,
+    os: String,
+    version: Option<String>,
+}
+
+impl Platform {
+    pub fn parse(platform: &str) -> Self {
+        lazy_static! {
+            // Gem::Platform#initialize の case 式と同じ順に試す
+            static ref OSES: Vec<(Regex, &'static str)> = [
+                (r"aix-?(\d+)?", "aix"),
+                (r"cygwin", "cygwin"),
+                (r"darwin-?(\d+)?", "darwin"),
+                (r"^macruby$", "macruby"),
+                (r"freebsd-?(\d+)?", "freebsd"),
+                (r"^(?:java|jruby)$", "java"),
+                (r"^java-?([\d.]*)", "java"),
+                (r"^dalvik-?(\d+)?$", "dalvik"),
+                (r"^dotnet-?([\d.]*)", "dotnet"),
+                (r"linux-?(\w+)?", "linux"),
+                (r"mingw32", "mingw32"),
+                (r"mingw-?(\w+)?", "mingw"),
+                (r"netbsdelf", "netbsdelf"),
+                (r"openbsd-?(\d+\.\d+)?", "openbsd"),
+                (r"solaris-?(\d+\.\d+)?", "solaris"),
+                (r"wasi", "wasi"),
+            ]
+            .into_iter()
+            .map(|(pattern, os)| (Regex::new(pattern).unwrap(), os))
+            .collect();
+            static ref MSWIN: Regex = Regex::new(r"(mswin\d+)(?:_(\d+))?").unwrap();
+        }
+
+        let platform = platform.trim_end_matches('-');
+        let (cpu, os) = match platform.split_once('-') {
+            Some((cpu, os)) => (Some(cpu), os),
+            // java のような CPU のない古い書き方
+            None => (None, platform),
+        };
+        let mut cpu = cpu.map(|cpu| {
+            if cpu.len() == 4 && cpu.starts_with('i') && cpu.ends_with("86") {
+                "x86".to_string()
+            } else {
+                cpu.to_string()
+            }
+        });
+
+        let version = |captures: regex::Captures| {
+            captures
+                .get(1)
+                .map(|m| m.as_str().to_string())
+                .filter(|version| !version.is_empty())
+        };
+        let (os, version) = if let Some(captures) = MSWIN.captures(os) {
+            let name = captures[1].to_string();
+            if cpu.is_none() && name.ends_with("32") {
+                cpu = Some("x86".to_string());
+            }
+            (name, captures.get(2).map(|m| m.as_str().to_string()))
+        } else {
+            OSES.iter()
+                .find_map(|(pattern, name)| {
+                    let captures = pattern.captures(os)?;
+                    Some((name.to_string(), version(captures)))
+                })
+                .unwrap_or_else(|| ("unknown".to_string(), None))
+        };
+        Self { cpu, os, version }
+    }
+
+    /// Whether a gem built for `self` runs on `target`, like `Gem::Platform#===`.
+    pub fn matches(&self, target: &Platform) -> bool {
+        let universal = |platform: &Platform| platform.cpu.as_deref() == Some("universal");
+        let any_cpu = |platform: &Platform| platform.cpu.is_none() || universal(platform);
+        // universal-mingw32 は x64-mingw-ucrt でも使える
+        if (universal(self) || universal(target))
+            && self.os.starts_with("mingw")
+            && target.os.starts_with("mingw")
+        {
+            return true;
+        }
+
+        let cpu = any_cpu(self)
+            || any_cpu(target)
+            || self.cpu == target.cpu
+            || (self.cpu.as_deref() == Some("arm")
+                && target
+                    .cpu
+                    .as_deref()
+                    .is_some_and(|cpu| cpu.starts_with("armv")));
+        if !cpu || self.os != target.os {
+            return false;
+        }
+
+        if self.os == "linux" {
+            // gnu と eabi(hf) の違いは無視する。汎用の linux 向けは musl でも使える
+            let suffix = self.version.as_deref().unwrap_or("");
+            let musl = ["musl", "musleabi", "musleabihf"].map(|libc| format!("{}{}", libc, suffix));
+            self.normalized_linux_version() == target.normalized_linux_version()
+                || target.version.as_ref().is_some_and(|v| musl.contains(v))
+        } else {
+            self.version.is_none() || target.version.is_none() || self.version == target.version
+        }
+    }
+
+    fn normalized_linux_version(&self) -> Option<&str> {
+        let version = self.version.as_deref()?;
+        let version = version.strip_prefix("gnu").unwrap_or(version);
+        let version = version
+            .strip_suffix("eabihf")
+            .or_else(|| version.strip_suffix("eabi"))
+            .unwrap_or(version);
+        (!version.is_empty()).then_some(version)
+    }
+}
+
+/// Whether a gem for `gem_platform` (e.g. `universal-darwin`) can be used on
+/// `target_platform` (e.g. `arm64-darwin23`). `ruby` gems run everywhere,
+/// while a `ruby` target only takes `ruby` gems.
+pub fn platform_matches(gem_platform: &str, target_platform: &str) -> bool {
+    if gem_platform == "ruby" || gem_platform == target_platform {
+        return true;
+    }
+    if target_platform == "ruby" {
+        return false;
+    }
+    Platform::parse(gem_platform).matches(&Platform::parse(target_platform))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::platform::{Platform, platform_matches};
+
+    #[test]
+    fn test_parse() {
+        let parse = |platform| {
+            let Platform { cpu, os, version } = Platform::parse(platform);
+            (cpu, os, version)
+        };
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            parse("arm64-darwin23"),
+            (some("arm64"), "darwin".to_string(), some("23"))
+        );
+        assert_eq!(
+            parse("x86_64-linux-musl"),
+            (some("x86_64"), "linux".to_string(), some("musl"))
+        );
+        assert_eq!(
+            parse("i686-linux"),
+            (some("x86"), "linux".to_string(), None)
+        );
+        assert_eq!(parse("java"), (None, "java".to_string(), None));
+        assert_eq!(
+            parse("x64-mingw-ucrt"),
+            (some("x64"), "mingw".to_string(), some("ucrt"))
+        );
+        assert_eq!(parse("mswin32"), (some("x86"), "mswin32".to_string(), None));
+    }
+
+    #[test]
+    fn test_platform_matches() {
+        let cases = [
+            ("ruby", "arm64-darwin23", true),
+            ("ruby", "ruby", true),
+            ("x86_64-linux", "ruby", false),
+            ("arm64-darwin", "arm64-darwin23", true),
+            ("arm64-darwin-23", "arm64-darwin23", true),
+            ("arm64-darwin-22", "arm64-darwin23", false),
+            ("universal-darwin", "arm64-darwin23", true),
+            ("x86_64-darwin", "arm64-darwin23", false),
+            ("x86_64-linux", "x86_64-linux", true),
+            ("x86_64-linux", "x86_64-linux-gnu", true),
+            ("x86_64-linux-gnu", "x86_64-linux", true),
+            ("x86_64-linux", "x86_64-linux-musl", true),
+            ("x86_64-linux-musl", "x86_64-linux", false),
+            ("x86_64-linux-musl", "x86_64-linux-musl", true),
+            ("aarch64-linux", "x86_64-linux", false),
+            ("arm-linux", "armv7-linux", true),
+            ("arm-linux-gnueabihf", "armv7-linux-eabihf", true),
+            ("arm-linux-musleabihf", "armv7-linux-gnueabihf", false),
+            ("x86_64-linux", "x86_64-darwin", false),
+            ("universal-mingw32", "x64-mingw-ucrt", true),
+            ("x64-mingw-ucrt", "x64-mingw-ucrt", true),
+            ("x64-mingw32", "x64-mingw-ucrt", false),
+            ("java", "universal-java-17", true),
+            ("universal-java-11", "universal-java-17", false),
+        ];
+        for (gem, target, expected) in cases {
+            assert_eq!(
+                platform_matches(gem, target),
+                expected,
+                "{} on {}",
+                gem,
+                target
+            );
+        }
+    }
+}
@@ -1,9 +1,18 @@
-use std::collections::HashMap;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
 use pubgrub::{
-    Dependencies, DependencyConstraints, DependencyProvider, OfflineDependencyProvider, Ranges,
-    resolve,
+    DefaultStringReporter, Dependencies, DependencyConstraints, DependencyProvider,
+    OfflineDependencyProvider, PackageResolutionStatistics, PubGrubError, Ranges, Reporter,
+    VersionSet, resolve,
 };
+use thiserror::Error;
 use tracing::{Level, error, instrument};
 // use pubgrub::SemanticVersion;
 // use pubgrub::{Dependencies, DependencyProvider, OfflineDependencyProvider};
@@ -12,11 +21,213 @@ use tracing::{Level, error, instrument};
 // use std::fmt;
 // use thiserror::Error;
 
-use crate::version::{RichReq, RubyVersion};
+use crate::{
+    platform::platform_matches,
+    version::{RichReq, RubyVersion},
+};
+
+pub struct ResolverStats {
+    pub packages: usize,
+    pub versions: usize,
+    pub largest_package: Option<(String, usize)>,
+}
+
+impl std::fmt::Display for ResolverStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} packages, {} versions considered",
+            self.packages, self.versions
+        )?;
+        if let Some((name, count)) = &self.largest_package {
+            write!(f, " (most versions: {} with {})", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Solver steps after which resolution is assumed to be stuck.
+pub const DEFAULT_MAX_STEPS: usize = 1_000_000;
+
+#[derive(Error, Debug)]
+pub enum ResolveError {
+    #[error("{package} ({version}) depends on itself")]
+    SelfDependency {
+        package: String,
+        version: RubyVersion,
+    },
+
+    #[error(
+        "Resolution gave up after {steps} steps; it kept revisiting {}",
+        packages.join(", ")
+    )]
+    TooManySteps { steps: usize, packages: Vec<String> },
+
+    #[error("Resolution was cancelled")]
+    Cancelled,
+}
+
+/// Wraps the offline provider the way Bundler picks versions: a locked version
+/// is kept while it still satisfies the requirements, otherwise the newest one
+/// wins. Resolutions that never converge on bad index data are stopped instead
+/// of spinning forever.
+struct BundlerDependencyProvider<'a> {
+    inner: &'a OfflineDependencyProvider<String, RichReq>,
+    locked: &'a HashMap<String, Vec<RubyVersion>>,
+    platforms: &'a [String],
+    max_steps: usize,
+    steps: Cell<usize>,
+    cancelled: &'a AtomicBool,
+    // パッケージごとにバージョンを選び直した回数
+    choices: RefCell<HashMap<String, usize>>,
+}
+
+impl BundlerDependencyProvider<'_> {
+    /// The packages the solver chose versions for most often, i.e. the ones it
+    /// kept backtracking over.
+    fn most_revisited(&self) -> Vec<String> {
+        let mut choices: Vec<_> = self
+            .choices
+            .borrow()
+            .iter()
+            .filter(|(p, _)| p.as_str() != "root")
+            .map(|(p, n)| (p.clone(), *n))
+            .collect();
+        choices.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        choices.into_iter().take(5).map(|(p, _)| p).collect()
+    }
+}
+
+/// How well `version` suits `platforms`: `ruby` gems run on any of them, a
+/// platform gem that one of them can use ranks higher, and one built for
+/// exactly one of them ranks highest. `None` when no platform can use it.
+fn platform_rank(version: &RubyVersion, platforms: &[String]) -> Option<u8> {
+    let Some(platform) = version.platform() else {
+        return Some(0);
+    };
+    platforms
+        .iter()
+        .filter_map(|target| {
+            if platform == target {
+                Some(2)
+            } else if platform_matches(platform, target) {
+                Some(1)
+            } else {
+                None
+            }
+        })
+        .max()
+}
+
+impl DependencyProvider for BundlerDependencyProvider<'_> {
+    type P = String;
+    type V = RubyVersion;
+    type VS = RichReq;
+    type M = String;
+    type Err = ResolveError;
+    type Priority = <OfflineDependencyProvider<String, RichReq> as DependencyProvider>::Priority;
+
+    fn prioritize(
+        &self,
+        package: &String,
+        range: &RichReq,
+        package_statistics: &PackageResolutionStatistics,
+    ) -> Self::Priority {
+        self.inner.prioritize(package, range, package_statistics)
+    }
+
+    fn choose_version(
+        &self,
+        package: &String,
+        range: &RichReq,
+    ) -> Result<Option<RubyVersion>, ResolveError> {
+        *self
+            .choices
+            .borrow_mut()
+            .entry(package.clone())
+            .or_default() += 1;
+        // ロックされたバージョンは、どのプラットフォームでも使えないビルドなら同じバージョンの使えるビルドに替えて選ぶ
+        if let Some(locked) = self.locked.get(package)
+            && let Some(version) = self.inner.versions(package).and_then(|versions| {
+                versions
+                    .filter(|v| range.contains(v))
+                    .filter(|v| locked.iter().any(|l| l.cmp_ignoring_platform(v).is_eq()))
+                    .filter_map(|v| Some((v, platform_rank(v, self.platforms)?)))
+                    .max_by(|(a, a_rank), (b, b_rank)| {
+                        a.cmp_ignoring_platform(b)
+                            .then(locked.contains(a).cmp(&locked.contains(b)))
+                            .then(a_rank.cmp(b_rank))
+                            .then_with(|| a.cmp(b))
+                    })
+            })
+        {
+            return Ok(Some(version.0.clone()));
+        }
+        // 要求がプレリリースを許していなければ安定版だけを候補にし、その中の最新を選ぶ。
+        // 同じバージョンのプラットフォーム違いは、対象に合うものを ruby より優先する
+        let allow_pre = !range.pre_range.is_empty();
+        Ok(self.inner.versions(package).and_then(|versions| {
+            versions
+                .filter(|version| version.is_stable() || allow_pre)
+                .filter(|version| range.contains(version))
+                .filter_map(|version| Some((version, platform_rank(version, self.platforms)?)))
+                .max_by(|(a, a_rank), (b, b_rank)| {
+                    a.cmp_ignoring_platform(b)
+                        .then(a_rank.cmp(b_rank))
+                        .then_with(|| a.cmp(b))
+                })
+                .map(|(version, _)| version.clone())
+        }))
+    }
+
+    fn get_dependencies(
+        &self,
+        package: &String,
+        version: &RubyVersion,
+    ) -> Result<Dependencies<String, RichReq, String>, ResolveError> {
+        let Ok(dependencies) = self.inner.get_dependencies(package, version);
+        if let Dependencies::Available(deps) = &dependencies
+            && deps.contains_key(package)
+        {
+            return Err(ResolveError::SelfDependency {
+                package: package.clone(),
+                version: version.clone(),
+            });
+        }
+        Ok(dependencies)
+    }
+
+    fn should_cancel(&self) -> Result<(), ResolveError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(ResolveError::Cancelled);
+        }
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        if steps > self.max_steps {
+            return Err(ResolveError::TooManySteps {
+                steps: self.max_steps,
+                packages: self.most_revisited(),
+            });
+        }
+        Ok(())
+    }
+}
 
 pub struct Resolver {
     pub dependency_provider: OfflineDependencyProvider<String, RichReq>,
     lock_meta: HashMap<(String, RubyVersion), Vec<(String, Vec<String>)>>,
+    locked: HashMap<String, Vec<RubyVersion>>,
+    max_steps: usize,
+    ruby_version: Option<RubyVersion>,
+    platforms: Vec<String>,
+    // --pre: どの要求でもプレリリースを選べるようにする
+    prerelease: bool,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Resolver {
@@ -24,16 +235,158 @@ impl Resolver {
         Resolver {
             dependency_provider: OfflineDependencyProvider::new(),
             lock_meta: HashMap::new(),
+            locked: HashMap::new(),
+            max_steps: DEFAULT_MAX_STEPS,
+            ruby_version: None,
+            platforms: vec!["ruby".to_string()],
+            prerelease: false,
+        }
+    }
+
+    /// Prefers `version` of `gem`, e.g. from an existing lockfile, as long as it
+    /// still satisfies the requirements. Locking another version replaces it,
+    /// but the builds of one version for several platforms are all kept; a
+    /// build none of the platforms can use is swapped for a usable build of
+    /// the same version.
+    pub fn lock(&mut self, gem: String, version: RubyVersion) {
+        let builds = self.locked.entry(gem).or_default();
+        builds.retain(|build| build.cmp_ignoring_platform(&version).is_eq());
+        builds.push(version);
+    }
+
+    /// The locked builds none of the platforms being locked for can use,
+    /// sorted, e.g. `grpc 1.71.0-java` when locking for `x86_64-linux`.
+    pub fn unusable_locked(&self) -> Vec<(String, RubyVersion)> {
+        let mut unusable: Vec<_> = self
+            .locked
+            .iter()
+            .flat_map(|(gem, versions)| versions.iter().map(move |v| (gem.clone(), v.clone())))
+            .filter(|(_, version)| platform_rank(version, &self.platforms).is_none())
+            .collect();
+        unusable.sort();
+        unusable
+    }
+
+    /// Limits how many solver steps `resolve` may take before giving up.
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.max_steps = max_steps;
+    }
+
+    /// Sets the Ruby the bundle is for, which [`Resolver::supports_ruby`] checks against.
+    pub fn set_ruby_version(&mut self, version: RubyVersion) {
+        self.ruby_version = Some(version);
+    }
+
+    /// Sets the platforms the lockfile is for, `ruby` by default. Among
+    /// platform gems of the same version, the one built for one of them is
+    /// chosen over the `ruby` gem; gems none of them can use are never chosen.
+    pub fn set_platforms(&mut self, platforms: Vec<String>) {
+        self.platforms = platforms;
+    }
+
+    /// Lets every requirement added afterwards match prereleases, like
+    /// `gem install --pre`. Versions outside a requirement's range, such as
+    /// those excluded by an exact pin, are still never chosen.
+    pub fn set_prerelease(&mut self, prerelease: bool) {
+        self.prerelease = prerelease;
+    }
+
+    /// Whether a gem version requiring `required` Ruby can be used. Any version
+    /// can when no Ruby is set or the gem names no requirement.
+    pub fn supports_ruby(&self, required: Option<&RichReq>) -> bool {
+        match (&self.ruby_version, required) {
+            // preview 版の Ruby でも要求の範囲だけで判断する
+            (Some(ruby), Some(required)) => required.range.contains(ruby),
+            _ => true,
         }
     }
 
     #[instrument(level = Level::INFO, skip_all)]
     pub fn resolve(&self) -> anyhow::Result<HashMap<String, RubyVersion>> {
+        self.resolve_with(&AtomicBool::new(false))
+    }
+
+    /// Resolves on a blocking thread until `cancel` completes. The resolver is
+    /// handed back with the result so it can still be used, e.g. to write the
+    /// lockfile. A cancelled resolution fails with [`ResolveError::Cancelled`].
+    pub async fn resolve_until(
+        self,
+        cancel: impl Future<Output = ()>,
+    ) -> (Self, anyhow::Result<HashMap<String, RubyVersion>>) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = cancelled.clone();
+        let mut task = tokio::task::spawn_blocking(move || {
+            let result = self.resolve_with(&flag);
+            (self, result)
+        });
+        let joined = tokio::select! {
+            joined = &mut task => joined,
+            _ = cancel => {
+                // pubgrub は毎ステップ should_cancel を呼ぶので、すぐに止まる
+                cancelled.store(true, Ordering::Relaxed);
+                task.await
+            }
+        };
+        match joined {
+            Ok(joined) => joined,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+
+    fn resolve_with(&self, cancelled: &AtomicBool) -> anyhow::Result<HashMap<String, RubyVersion>> {
         let root_pkg = "root".to_string();
         let root_ver = RubyVersion::new(0, 0, 0);
-        Ok(resolve(&self.dependency_provider, root_pkg, root_ver)?
-            .into_iter()
-            .collect())
+        let provider = BundlerDependencyProvider {
+            inner: &self.dependency_provider,
+            locked: &self.locked,
+            platforms: &self.platforms,
+            max_steps: self.max_steps,
+            steps: Cell::new(0),
+            cancelled,
+            choices: RefCell::new(HashMap::new()),
+        };
+        match resolve(&provider, root_pkg, root_ver) {
+            Ok(solution) => Ok(solution.into_iter().collect()),
+            Err(PubGrubError::NoSolution(tree)) => {
+                anyhow::bail!(
+                    "There is no solution:\n{}",
+                    DefaultStringReporter::report(&tree)
+                )
+            }
+            Err(PubGrubError::ErrorRetrievingDependencies { source, .. })
+            | Err(PubGrubError::ErrorChoosingVersion { source, .. })
+            | Err(PubGrubError::ErrorInShouldCancel(source)) => Err(source.into()),
+        }
+    }
+
+    /// Summarizes how much data was loaded into the provider.
+    /// The `root` package is not counted.
+    pub fn stats(&self) -> ResolverStats {
+        let mut stats = ResolverStats {
+            packages: 0,
+            versions: 0,
+            largest_package: None,
+        };
+        for package in self.dependency_provider.packages() {
+            if package == "root" {
+                continue;
+            }
+            let count = self
+                .dependency_provider
+                .versions(package)
+                .map(|v| v.count())
+                .unwrap_or(0);
+            stats.packages += 1;
+            stats.versions += count;
+            if stats
+                .largest_package
+                .as_ref()
+                .is_none_or(|(name, max)| count > *max || (count == *max && package < name))
+            {
+                stats.largest_package = Some((package.clone(), count));
+            }
+        }
+        stats
     }
 
     #[instrument(level = Level::DEBUG, skip_all)]
@@ -64,16 +417,124 @@ impl Resolver {
         self.lock_meta.get(&(package.clone(), version.clone()))
     }
 
+    /// The specs to lock for `solution`, sorted: every package once, except
+    /// that a version built for several platforms is listed once per platform
+    /// being locked for, with the build that suits that platform best, as
+    /// Bundler does. `root` is left out.
+    pub fn lock_specs(
+        &self,
+        solution: &HashMap<String, RubyVersion>,
+    ) -> Vec<(String, RubyVersion)> {
+        let mut specs = BTreeSet::new();
+        for (name, version) in solution {
+            if name == "root" {
+                continue;
+            }
+            let variants: Vec<&RubyVersion> = self
+                .dependency_provider
+                .versions(name)
+                .into_iter()
+                .flatten()
+                .filter(|v| v.cmp_ignoring_platform(version).is_eq())
+                .collect();
+            let builds: BTreeSet<&RubyVersion> = self
+                .platforms
+                .iter()
+                .filter_map(|target| {
+                    variants
+                        .iter()
+                        .filter_map(|v| Some((*v, platform_rank(v, std::slice::from_ref(target))?)))
+                        .max_by(|(a, a_rank), (b, b_rank)| {
+                            a_rank.cmp(b_rank).then_with(|| a.cmp(b))
+                        })
+                        .map(|(v, _)| v)
+                })
+                .collect();
+            if builds.is_empty() {
+                specs.insert((name.clone(), version.clone()));
+            }
+            specs.extend(builds.into_iter().map(|v| (name.clone(), v.clone())));
+        }
+        specs.into_iter().collect()
+    }
+
+    /// The packages in `solution` that `roots` need, directly or through other
+    /// packages, including `roots` themselves.
+    pub fn reachable(
+        &self,
+        solution: &HashMap<String, RubyVersion>,
+        roots: impl IntoIterator<Item = String>,
+    ) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<String> = roots.into_iter().collect();
+        while let Some(name) = stack.pop() {
+            let Some(version) = solution.get(&name) else {
+                continue;
+            };
+            if let Some(deps) = self.get_dependencies_str(&name, version) {
+                stack.extend(
+                    deps.iter()
+                        .map(|(dep, _)| dep.clone())
+                        .filter(|dep| !seen.contains(dep)),
+                );
+            }
+            seen.insert(name);
+        }
+        seen
+    }
+
+    /// Every dependency chain in `solution` from `root` to `gem`, each listed
+    /// from `root` on. A chain never visits a package twice, so a cycle ends
+    /// the chain instead of repeating forever.
+    pub fn paths_to(
+        &self,
+        solution: &HashMap<String, RubyVersion>,
+        root: &str,
+        gem: &str,
+    ) -> Vec<Vec<String>> {
+        let mut paths = vec![];
+        let mut stack = vec![vec![root.to_string()]];
+        while let Some(path) = stack.pop() {
+            let name = path.last().expect("paths are never empty");
+            if name == gem {
+                paths.push(path);
+                continue;
+            }
+            let Some(deps) = solution
+                .get(name)
+                .and_then(|version| self.get_dependencies_str(name, version))
+            else {
+                continue;
+            };
+            for (dep, _) in deps {
+                if !path.contains(dep) {
+                    let mut next = path.clone();
+                    next.push(dep.clone());
+                    stack.push(next);
+                }
+            }
+        }
+        paths.sort();
+        paths
+    }
+
     pub fn add_dependencies(
         &mut self,
         gem: String,
         version: RubyVersion,
         constraints: Vec<(String, RichReq, Vec<String>)>,
     ) {
+        let prerelease = self.prerelease;
         self.dependency_provider.add_dependencies(
             gem.clone(),
             version.clone(),
-            constraints.iter().map(|c| (c.0.clone(), c.1.clone())),
+            constraints.iter().map(|c| {
+                let mut req = c.1.clone();
+                if prerelease {
+                    req.pre_range = req.pre_range.union(&req.range);
+                }
+                (c.0.clone(), req)
+            }),
         );
         self.lock_meta.entry((gem, version)).or_insert(
             constraints
@@ -397,17 +858,13 @@ impl Resolver {
 
 #[cfg(test)]
 mod tests {
-    use std::{cmp::Reverse, convert::Infallible, path::Path};
-
-    use pubgrub::{
-        Dependencies, DependencyProvider, OfflineDependencyProvider, PackageResolutionStatistics,
-        VersionSet, resolve,
-    };
-
     use crate::{
         compact_index_client::CompactIndexClient,
-        parse_gemfile,
-        resolver::Resolver,
+        http::HttpOptions,
+        multi_source::MultiSourceClient,
+        resolver::{ResolveError, Resolver},
+        source::{GemSource, InMemorySource},
+        test_support::mock_compact_index,
         version::{self, RichReq, RubyVersion, parse_req},
     };
 
@@ -417,35 +874,35 @@ mod tests {
     //     p.add_dependencies(
     //         "a".to_string(),
     //         RubyVersion::parse("1.11.0"),
-    //         vec![("c".to_string(), parse_req("~> 1.7.0", ",").0)],
+    //         vec![("c".to_string(), parse_req("~> 1.7.0", ",").unwrap().0)],
     //     );
     //     p.add_dependencies(
     //         "a".to_string(),
     //         RubyVersion::parse("1.10.0"),
-    //         vec![("c".to_string(), parse_req("~> 1.4", ",").0)],
+    //         vec![("c".to_string(), parse_req("~> 1.4", ",").unwrap().0)],
     //     );
     //     p.add_dependencies(
     //         "c".to_string(),
     //         RubyVersion::parse("1.7.0"),
-    //         vec![("d".to_string(), parse_req("~> 1.7", ",").0)],
+    //         vec![("d".to_string(), parse_req("~> 1.7", ",").unwrap().0)],
     //     );
     //     p.add_dependencies(
     //         "c".to_string(),
     //         RubyVersion::parse("1.8.0"),
-    //         vec![("d".to_string(), parse_req("~> 1.20", ",").0)],
+    //         vec![("d".to_string(), parse_req("~> 1.20", ",").unwrap().0)],
     //     );
     //     p.add_dependencies("d".to_string(), RubyVersion::parse("1.20.0"), vec![]);
     //     p.add_dependencies(
     //         "e".to_string(),
     //         RubyVersion::parse("1.0.0"),
-    //         vec![("d".to_string(), parse_req("~> 1.15", ",").0)],
+    //         vec![("d".to_string(), parse_req("~> 1.15", ",").unwrap().0)],
     //     );
     //     p.add_dependencies(
     //         "root".to_string(),
     //         RubyVersion::new(0, 0, 0),
     //         vec![
-    //             ("a".to_string(), parse_req("~> 1.1", ",").0),
-    //             ("e".to_string(), parse_req(">= 0.20.0,< 2.a", ",").0),
+    //             ("a".to_string(), parse_req("~> 1.1", ",").unwrap().0),
+    //             ("e".to_string(), parse_req(">= 0.20.0,< 2.a", ",").unwrap().0),
     //         ],
     //     );
     //     let solutions = resolve(&p, "root".to_string(), RubyVersion::new(0, 0, 0)).unwrap();
@@ -456,7 +913,7 @@ mod tests {
     //     );
     //     assert_eq!(
     //         Some(RubyVersion::new(1, 10, 0)),
-    //         p.choose_version(&"a".to_string(), &parse_req("~> 1.1", ",").0)
+    //         p.choose_version(&"a".to_string(), &parse_req("~> 1.1", ",").unwrap().0)
     //             .unwrap()
     //     );
     //     assert_eq!(solutions.get("a"), Some(&RubyVersion::new(1, 10, 0)));
@@ -464,85 +921,27 @@ mod tests {
 
     #[test]
     fn bundler_like_resolution() {
-        struct BundlerOfflineDependencyProvider {
-            dependency_provider: OfflineDependencyProvider<String, RichReq>,
-        }
+        struct Provider(Resolver);
 
-        impl BundlerOfflineDependencyProvider {
+        impl Provider {
             fn add_dependencies(
                 &mut self,
                 package: String,
                 version: RubyVersion,
                 dependencies: Vec<(String, RichReq)>,
             ) {
-                self.dependency_provider
-                    .add_dependencies(package, version, dependencies);
+                self.0.add_dependencies(
+                    package,
+                    version,
+                    dependencies
+                        .into_iter()
+                        .map(|(name, req)| (name, req, vec![]))
+                        .collect(),
+                );
             }
         }
 
-        impl DependencyProvider for BundlerOfflineDependencyProvider {
-            type P = String;
-            type V = <RichReq as VersionSet>::V;
-            type VS = RichReq;
-            type M = String;
-
-            type Err = Infallible;
-
-            #[inline]
-            fn choose_version(
-                &self,
-                package: &Self::P,
-                range: &Self::VS,
-            ) -> Result<Option<Self::V>, Infallible> {
-                let mut versions = self
-                    .dependency_provider
-                    .versions(package)
-                    .map(|v| v.filter(|v| range.contains(v)).collect::<Vec<_>>())
-                    .unwrap_or_default();
-                versions.sort_by(|a, b| b.cmp(a));
-                if let Some(v) = versions.first() {
-                    if package == "grpc-google-iam-v1" {
-                        println!(
-                            "package: {}, choose_version: {}, versions: {:?}",
-                            package, v, versions
-                        );
-                    }
-                    Ok(Some((*v).clone()))
-                } else {
-                    Ok(None)
-                }
-                // Ok(
-                //     .and_then(|versions| {
-                //         versions.keys().rev().find(|v| range.contains(v)).cloned()
-                //     }))
-            }
-
-            type Priority = (u32, Reverse<usize>);
-
-            #[inline]
-            fn prioritize(
-                &self,
-                package: &Self::P,
-                range: &Self::VS,
-                package_statistics: &PackageResolutionStatistics,
-            ) -> Self::Priority {
-                self.dependency_provider
-                    .prioritize(package, range, package_statistics)
-            }
-
-            #[inline]
-            fn get_dependencies(
-                &self,
-                package: &Self::P,
-                version: &Self::V,
-            ) -> Result<Dependencies<Self::P, Self::VS, Self::M>, Infallible> {
-                self.dependency_provider.get_dependencies(package, version)
-            }
-        }
-
-        let mut p = BundlerOfflineDependencyProvider {
-            dependency_provider: OfflineDependencyProvider::new(),
-        };
+        let mut p = Provider(Resolver::new());
 
         /* ------ grpc-google-iam-v1 ------ */
         // 1.10.0
@@ -550,8 +949,14 @@ mod tests {
             "google-cloud-artifact_registry-v1".into(),
             RubyVersion::parse("0.11.0"),
             vec![
-                ("grpc-google-iam-v1".into(), parse_req("~> 1.1", ",").0),
-                ("gapic-common".into(), parse_req(">= 0.20.0, < 2.a", ",").0),
+                (
+                    "grpc-google-iam-v1".into(),
+                    parse_req("~> 1.1", ",").unwrap().0,
+                ),
+                (
+                    "gapic-common".into(),
+                    parse_req(">= 0.20.0, < 2.a", ",").unwrap().0,
+                ),
             ],
         );
 
@@ -561,11 +966,11 @@ mod tests {
             vec![
                 (
                     "googleapis-common-protos-types".into(),
-                    parse_req("~> 1.15", ",").0,
+                    parse_req("~> 1.15", ",").unwrap().0,
                 ),
                 (
                     "googleapis-common-protos".into(),
-                    parse_req("~> 1.6", ",").0,
+                    parse_req("~> 1.6", ",").unwrap().0,
                 ),
             ],
         );
@@ -576,12 +981,15 @@ mod tests {
             "grpc-google-iam-v1".into(),
             RubyVersion::parse("1.10.0"),
             vec![
-                ("google-protobuf".into(), parse_req(">= 3.18, < 5.a", ",").0),
+                (
+                    "google-protobuf".into(),
+                    parse_req(">= 3.18, < 5.a", ",").unwrap().0,
+                ),
                 (
                     "googleapis-common-protos".into(),
-                    parse_req("~> 1.4", ",").0,
+                    parse_req("~> 1.4", ",").unwrap().0,
                 ),
-                ("grpc".into(), parse_req("~> 1.41", ",").0),
+                ("grpc".into(), parse_req("~> 1.41", ",").unwrap().0),
             ],
         );
 
@@ -589,12 +997,15 @@ mod tests {
             "grpc-google-iam-v1".into(),
             RubyVersion::parse("1.8.0"),
             vec![
-                ("google-protobuf".into(), parse_req(">= 3.18, < 5.a", ",").0),
+                (
+                    "google-protobuf".into(),
+                    parse_req(">= 3.18, < 5.a", ",").unwrap().0,
+                ),
                 (
                     "googleapis-common-protos".into(),
-                    parse_req("~> 1.4", ",").0,
+                    parse_req("~> 1.4", ",").unwrap().0,
                 ),
-                ("grpc".into(), parse_req("~> 1.41", ",").0),
+                ("grpc".into(), parse_req("~> 1.41", ",").unwrap().0),
             ],
         );
 
@@ -602,12 +1013,15 @@ mod tests {
             "grpc-google-iam-v1".into(),
             RubyVersion::parse("1.11.0"),
             vec![
-                ("google-protobuf".into(), parse_req(">= 3.18, < 5.a", ",").0),
+                (
+                    "google-protobuf".into(),
+                    parse_req(">= 3.18, < 5.a", ",").unwrap().0,
+                ),
                 (
                     "googleapis-common-protos".into(),
-                    parse_req("~> 1.5.0", ",").0,
+                    parse_req("~> 1.5.0", ",").unwrap().0,
                 ),
-                ("grpc".into(), parse_req("~> 1.41", ",").0),
+                ("grpc".into(), parse_req("~> 1.41", ",").unwrap().0),
             ],
         );
 
@@ -616,24 +1030,30 @@ mod tests {
             "googleapis-common-protos".into(),
             RubyVersion::parse("1.7.0"),
             vec![
-                ("google-protobuf".into(), parse_req(">= 3.18, < 5.a", ",").0),
+                (
+                    "google-protobuf".into(),
+                    parse_req(">= 3.18, < 5.a", ",").unwrap().0,
+                ),
                 (
                     "googleapis-common-protos-types".into(),
-                    parse_req("~> 1.7", ",").0,
+                    parse_req("~> 1.7", ",").unwrap().0,
                 ),
-                ("grpc".into(), parse_req("~> 1.41", ",").0),
+                ("grpc".into(), parse_req("~> 1.41", ",").unwrap().0),
             ],
         );
         p.add_dependencies(
             "googleapis-common-protos".into(),
             RubyVersion::parse("1.8.0"),
             vec![
-                ("google-protobuf".into(), parse_req(">= 3.18, < 5.a", ",").0),
+                (
+                    "google-protobuf".into(),
+                    parse_req(">= 3.18, < 5.a", ",").unwrap().0,
+                ),
                 (
                     "googleapis-common-protos-types".into(),
-                    parse_req("~> 1.20", ",").0,
+                    parse_req("~> 1.20", ",").unwrap().0,
                 ),
-                ("grpc".into(), parse_req("~> 1.41", ",").0),
+                ("grpc".into(), parse_req("~> 1.41", ",").unwrap().0),
             ],
         );
 
@@ -655,11 +1075,11 @@ mod tests {
             RubyVersion::parse("0.0.0"),
             vec![(
                 "google-cloud-artifact_registry-v1".into(),
-                parse_req("~> 0.11.0", ",").0,
+                parse_req("~> 0.11.0", ",").unwrap().0,
             )],
         );
 
-        let sol = resolve(&p, "root".into(), RubyVersion::parse("0.0.0")).unwrap();
+        let sol = p.0.resolve().unwrap();
 
         assert_eq!(
             sol.get("grpc-google-iam-v1"),
@@ -671,110 +1091,608 @@ mod tests {
         );
     }
 
-    #[tokio::test]
-    async fn test_resolve_deps() -> anyhow::Result<()> {
-        let gemfile = parse_gemfile();
-        let gems = CompactIndexClient::new("https://rubygems.org/", Path::new(".newbundle"))
-            .await?
-            .resolve_dependencies(
-                gemfile
-                    .dependencies
-                    .iter()
-                    .map(|dep| dep.name.clone())
-                    .collect(),
+    #[test]
+    fn test_stats() {
+        let mut resolver = Resolver::new();
+        resolver.add_dependencies("a".into(), RubyVersion::parse("1.0.0"), vec![]);
+        resolver.add_dependencies("a".into(), RubyVersion::parse("1.1.0"), vec![]);
+        resolver.add_dependencies("b".into(), RubyVersion::parse("2.0.0"), vec![]);
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![(
+                "a".into(),
+                parse_req("~> 1.0", ",").unwrap().0,
+                vec!["~> 1.0".into()],
+            )],
+        );
+
+        let stats = resolver.stats();
+        assert_eq!(stats.packages, 2);
+        assert_eq!(stats.versions, 3);
+        assert_eq!(stats.largest_package, Some(("a".to_string(), 2)));
+    }
+
+    #[test]
+    fn test_reachable() {
+        let mut resolver = Resolver::new();
+        let dep = |name: &str| (name.to_string(), parse_req(">= 0", ",").unwrap().0, vec![]);
+        resolver.add_dependencies("a".into(), RubyVersion::parse("1.0"), vec![dep("c")]);
+        resolver.add_dependencies("b".into(), RubyVersion::parse("1.0"), vec![dep("c")]);
+        resolver.add_dependencies("c".into(), RubyVersion::parse("1.0"), vec![dep("a")]);
+        resolver.add_dependencies("d".into(), RubyVersion::parse("1.0"), vec![]);
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![dep("a"), dep("b"), dep("d")],
+        );
+        let solution = resolver.resolve().unwrap();
+
+        let mut reachable: Vec<_> = resolver
+            .reachable(&solution, ["a".to_string()])
+            .into_iter()
+            .collect();
+        reachable.sort();
+        assert_eq!(reachable, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_paths_to() {
+        let mut resolver = Resolver::new();
+        let dep = |name: &str| (name.to_string(), parse_req(">= 0", ",").unwrap().0, vec![]);
+        let gem = |resolver: &mut Resolver, name: &str, deps: &[&str]| {
+            resolver.add_dependencies(
+                name.into(),
+                RubyVersion::parse("1.0"),
+                deps.iter().map(|d| dep(d)).collect(),
             )
-            .await?;
+        };
+        gem(&mut resolver, "rails", &["actionview"]);
+        gem(&mut resolver, "actionview", &["sanitizer"]);
+        gem(&mut resolver, "sanitizer", &["loofah"]);
+        gem(&mut resolver, "loofah", &["nokogiri"]);
+        // nokogiri -> loofah の循環はたどらない
+        gem(&mut resolver, "nokogiri", &["loofah"]);
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![dep("rails"), dep("sanitizer")],
+        );
+        let solution = resolver.resolve().unwrap();
+
+        assert_eq!(
+            resolver.paths_to(&solution, "root", "nokogiri"),
+            vec![
+                vec![
+                    "root",
+                    "rails",
+                    "actionview",
+                    "sanitizer",
+                    "loofah",
+                    "nokogiri"
+                ],
+                vec!["root", "sanitizer", "loofah", "nokogiri"],
+            ]
+        );
+        assert_eq!(
+            resolver.paths_to(&solution, "root", "rails"),
+            vec![vec!["root", "rails"]]
+        );
+        assert!(resolver.paths_to(&solution, "root", "thor").is_empty());
+    }
 
+    #[test]
+    fn test_prefers_locked_version() {
         let mut resolver = Resolver::new();
+        for version in ["1.0.0", "1.1.0", "2.0.0"] {
+            resolver.add_dependencies("a".into(), RubyVersion::parse(version), vec![]);
+        }
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![(
+                "a".into(),
+                parse_req(">= 1.0", ",").unwrap().0,
+                vec![">= 1.0".into()],
+            )],
+        );
+
+        resolver.lock("a".into(), RubyVersion::parse("1.1.0"));
+        assert_eq!(
+            resolver.resolve().unwrap()["a"],
+            RubyVersion::parse("1.1.0")
+        );
+
+        // ロックされたバージョンが存在しなければ最新を選ぶ
+        resolver.lock("a".into(), RubyVersion::parse("1.2.0"));
+        assert_eq!(
+            resolver.resolve().unwrap()["a"],
+            RubyVersion::parse("2.0.0")
+        );
+    }
 
+    #[test]
+    fn test_prerelease() {
+        let resolve = |prerelease: bool| {
+            let mut resolver = Resolver::new();
+            resolver.set_prerelease(prerelease);
+            let dep = |name: &str, req: &str| (name.into(), parse_req(req, ",").unwrap().0, vec![]);
+            for version in ["7.1.0", "7.2.0.rc1"] {
+                resolver.add_dependencies(
+                    "rails".into(),
+                    RubyVersion::parse(version),
+                    vec![dep("rack", ">= 2")],
+                );
+            }
+            for version in ["2.2.8", "3.0.0.beta1"] {
+                resolver.add_dependencies("rack".into(), RubyVersion::parse(version), vec![]);
+            }
+            for version in ["1.0.0", "1.1.0.rc1"] {
+                resolver.add_dependencies("pinned".into(), RubyVersion::parse(version), vec![]);
+            }
+            for version in ["7.1.0", "8.0.0.rc1"] {
+                resolver.add_dependencies("tilt".into(), RubyVersion::parse(version), vec![]);
+            }
+            resolver.add_dependencies(
+                "root".into(),
+                RubyVersion::new(0, 0, 0),
+                vec![
+                    dep("rails", ">= 7.0"),
+                    dep("pinned", "= 1.0.0"),
+                    dep("tilt", "~> 7.0"),
+                ],
+            );
+            let mut solution: Vec<_> = resolver
+                .resolve()
+                .unwrap()
+                .into_iter()
+                .filter(|(name, _)| name != "root")
+                .map(|(name, version)| format!("{} {}", name, version))
+                .collect();
+            solution.sort();
+            solution
+        };
+
+        assert_eq!(
+            resolve(false),
+            vec!["pinned 1.0.0", "rack 2.2.8", "rails 7.1.0", "tilt 7.1.0"]
+        );
+        // ~> 7.0 は 8 のプレリリースを含まない
+        assert_eq!(
+            resolve(true),
+            vec![
+                "pinned 1.0.0",
+                "rack 3.0.0.beta1",
+                "rails 7.2.0.rc1",
+                "tilt 7.1.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stable_wins_by_default() {
+        let mut resolver = Resolver::new();
+        for version in ["1.0.0", "1.1.0", "2.0.0.beta1", "2.0.0.rc1"] {
+            resolver.add_dependencies("a".into(), RubyVersion::parse(version), vec![]);
+        }
+        let (req, req_str) = parse_req(">= 1.0", ",").unwrap();
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![("a".into(), req, req_str)],
+        );
+        assert_eq!(
+            resolver.resolve().unwrap()["a"],
+            RubyVersion::parse("1.1.0")
+        );
+    }
+
+    #[test]
+    fn test_platform_variants() {
+        // 解決で選んだものと、lockfile に書く grpc の spec
+        let resolve = |platforms: &[&str], locked: Option<&str>| {
+            let mut resolver = Resolver::new();
+            for version in [
+                "1.70.0-x86_64-linux",
+                "1.71.0-x86_64-linux-gnu",
+                "1.71.0",
+                "1.71.0-x86_64-linux",
+                "1.71.0-arm64-darwin",
+                "1.71.0-java",
+            ] {
+                resolver.add_dependencies("grpc".into(), RubyVersion::parse(version), vec![]);
+            }
+            let (req, req_str) = parse_req(">= 1.0", ",").unwrap();
+            resolver.add_dependencies(
+                "root".into(),
+                RubyVersion::new(0, 0, 0),
+                vec![("grpc".into(), req, req_str)],
+            );
+            resolver.set_platforms(platforms.iter().map(|p| p.to_string()).collect());
+            if let Some(locked) = locked {
+                resolver.lock("grpc".into(), RubyVersion::parse(locked));
+            }
+            let solution = resolver.resolve().unwrap();
+            let specs: Vec<String> = resolver
+                .lock_specs(&solution)
+                .into_iter()
+                .map(|(_, version)| version.to_string())
+                .collect();
+            (solution["grpc"].to_string(), specs)
+        };
+        let single = |platforms: &[&str]| {
+            let (chosen, specs) = resolve(platforms, None);
+            assert_eq!(specs, vec![chosen.clone()]);
+            chosen
+        };
+        // ruby 向けのロックではプラットフォーム付きの gem を選ばない
+        assert_eq!(single(&["ruby"]), "1.71.0");
+        assert_eq!(single(&["x86_64-linux"]), "1.71.0-x86_64-linux");
+        assert_eq!(single(&["x86_64-linux-gnu"]), "1.71.0-x86_64-linux-gnu");
+        assert_eq!(single(&["arm64-darwin23"]), "1.71.0-arm64-darwin");
+        assert_eq!(single(&["x86_64-darwin"]), "1.71.0");
+        // プラットフォームごとに 1 つずつロックする。何度解決しても同じものを選ぶ
+        for _ in 0..10 {
+            assert_eq!(
+                resolve(&["arm64-darwin", "x86_64-linux"], None).1,
+                vec!["1.71.0-arm64-darwin", "1.71.0-x86_64-linux"]
+            );
+        }
+        assert_eq!(
+            resolve(&["ruby", "x86_64-linux"], None).1,
+            vec!["1.71.0", "1.71.0-x86_64-linux"]
+        );
+        // どの対象でも使えないロック済みのものは選び直す
+        assert_eq!(
+            resolve(&["x86_64-linux"], Some("1.71.0-java")),
+            (
+                "1.71.0-x86_64-linux".to_string(),
+                vec!["1.71.0-x86_64-linux".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn test_locked_platform_variant() {
+        let mut resolver = Resolver::new();
+        for version in [
+            "1.71.0",
+            "1.71.0-x86_64-linux",
+            "1.71.0-java",
+            "1.72.0",
+            "1.72.0-x86_64-linux",
+        ] {
+            resolver.add_dependencies("grpc".into(), RubyVersion::parse(version), vec![]);
+        }
+        let (req, req_str) = parse_req(">= 1.0", ",").unwrap();
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![("grpc".into(), req, req_str)],
+        );
+        resolver.set_platforms(vec!["x86_64-linux".into()]);
+        // lockfile が java 向けのビルドだけを持っていても、バージョンは保ったまま選び直す
+        resolver.lock("grpc".into(), RubyVersion::parse("1.71.0-java"));
+
+        assert_eq!(
+            resolver.unusable_locked(),
+            vec![("grpc".to_string(), RubyVersion::parse("1.71.0-java"))]
+        );
+        assert_eq!(
+            resolver.resolve().unwrap()["grpc"],
+            RubyVersion::parse("1.71.0-x86_64-linux")
+        );
+
+        // ロックされた使えるビルドは、同じバージョンの他のビルドより優先する
+        resolver.lock("grpc".into(), RubyVersion::parse("1.71.0"));
+        assert_eq!(resolver.unusable_locked().len(), 1);
+        resolver.set_platforms(vec!["ruby".into(), "x86_64-linux".into()]);
+        assert_eq!(
+            resolver.resolve().unwrap()["grpc"],
+            RubyVersion::parse("1.71.0")
+        );
+    }
+
+    #[test]
+    fn test_pinned_prerelease() {
+        let mut resolver = Resolver::new();
+        for version in ["0.9.0", "1.0.0.beta", "1.0.0"] {
+            resolver.add_dependencies("a".into(), RubyVersion::parse(version), vec![]);
+        }
+        let (req, req_str) = parse_req("= 1.0.0.beta", ",").unwrap();
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![("a".into(), req, req_str)],
+        );
+        assert_eq!(
+            resolver.resolve().unwrap()["a"],
+            RubyVersion::parse("1.0.0.beta")
+        );
+    }
+
+    #[test]
+    fn test_skips_prerelease_unless_requested() {
+        let mut resolver = Resolver::new();
+        for version in ["1.0.0", "1.1.0.rc1"] {
+            resolver.add_dependencies("a".into(), RubyVersion::parse(version), vec![]);
+        }
+        for (version, req) in [("1.0.0", ">= 0"), ("2.0.0", "*")] {
+            resolver.add_dependencies(
+                "b".into(),
+                RubyVersion::parse(version),
+                vec![("a".into(), parse_req(req, ",").unwrap().0, vec![req.into()])],
+            );
+        }
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![("b".into(), parse_req("*", ",").unwrap().0, vec![])],
+        );
+        let solution = resolver.resolve().unwrap();
+        assert_eq!(solution["a"], RubyVersion::parse("1.0.0"));
+
+        // 要求がプレリリースを名指ししていれば選べる
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![(
+                "a".into(),
+                parse_req(">= 1.1.0.rc1", ",").unwrap().0,
+                vec![">= 1.1.0.rc1".into()],
+            )],
+        );
+        assert_eq!(
+            resolver.resolve().unwrap()["a"],
+            RubyVersion::parse("1.1.0.rc1")
+        );
+    }
+
+    #[test]
+    fn test_resolve_self_dependency() {
+        let mut resolver = Resolver::new();
+        resolver.add_dependencies(
+            "a".into(),
+            RubyVersion::parse("1.0.0"),
+            vec![(
+                "a".into(),
+                parse_req(">= 1.0", ",").unwrap().0,
+                vec![">= 1.0".into()],
+            )],
+        );
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![(
+                "a".into(),
+                parse_req(">= 0", ",").unwrap().0,
+                vec![">= 0".into()],
+            )],
+        );
+
+        let err = resolver.resolve().unwrap_err();
+        assert_eq!(err.to_string(), "a (1.0.0) depends on itself");
+    }
+
+    #[test]
+    fn test_resolve_gives_up_on_cycle() {
+        // a の各バージョンが b の別バージョンを要求し、b は a を要求し返す
+        let mut resolver = Resolver::new();
+        for i in 1..=20 {
+            let version = RubyVersion::parse(&format!("{}.0", i));
+            let req = format!("= {}.0", 21 - i);
+            resolver.add_dependencies(
+                "a".into(),
+                version.clone(),
+                vec![(
+                    "b".into(),
+                    parse_req(&req, ",").unwrap().0,
+                    vec![req.clone()],
+                )],
+            );
+            let req = format!("= {}.0", i + 1);
+            resolver.add_dependencies(
+                "b".into(),
+                version,
+                vec![(
+                    "a".into(),
+                    parse_req(&req, ",").unwrap().0,
+                    vec![req.clone()],
+                )],
+            );
+        }
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![(
+                "a".into(),
+                parse_req(">= 0", ",").unwrap().0,
+                vec![">= 0".into()],
+            )],
+        );
+        resolver.set_max_steps(10);
+
+        let err = resolver.resolve().unwrap_err().to_string();
+        assert!(
+            err.starts_with("Resolution gave up after 10 steps"),
+            "{}",
+            err
+        );
+        assert!(err.ends_with("it kept revisiting a, b"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_until_cancelled() {
+        // 300 個の gem がそれぞれ 50 バージョンずつ連なる
+        let mut resolver = Resolver::new();
+        for i in 0..300 {
+            for v in 1..=50 {
+                let deps = if i < 299 {
+                    let req = format!(">= {}.0", v);
+                    vec![(
+                        format!("g{}", i + 1),
+                        parse_req(&req, ",").unwrap().0,
+                        vec![req.clone()],
+                    )]
+                } else {
+                    vec![]
+                };
+                resolver.add_dependencies(
+                    format!("g{}", i),
+                    RubyVersion::parse(&format!("{}.0", v)),
+                    deps,
+                );
+            }
+        }
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            vec![(
+                "g0".into(),
+                parse_req(">= 0", ",").unwrap().0,
+                vec![">= 0".into()],
+            )],
+        );
+
+        let (resolver, result) = resolver.resolve_until(async {}).await;
+        let err = result.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<ResolveError>(),
+                Some(ResolveError::Cancelled)
+            ),
+            "{}",
+            err
+        );
+
+        let (_, result) = resolver.resolve_until(std::future::pending()).await;
+        assert_eq!(result.unwrap()["g299"], RubyVersion::parse("50.0"));
+    }
+
+    /// Resolves `gemfile` against the gems `client` serves, without `root`.
+    async fn resolve_gemfile<S: GemSource + Sync>(
+        client: &MultiSourceClient<S>,
+        gemfile: &[(&str, &str)],
+        ruby: Option<&str>,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let gems = client
+            .resolve_dependencies(gemfile.iter().map(|(name, _)| name.to_string()).collect())
+            .await?;
+
+        let mut resolver = Resolver::new();
+        if let Some(ruby) = ruby {
+            resolver.set_ruby_version(RubyVersion::parse(ruby));
+        }
         for (gem, versions) in gems {
-            for v in versions.into_iter().rev() {
-                if gem == "googleapis-common-protos-types" && v.version.to_string() != "1.20.0" {
-                    continue;
-                }
-                if gem == "google-protobuf" && v.version.to_string() != "4.30.2" {
+            for (_, v) in versions {
+                if !resolver.supports_ruby(v.required_ruby_version.as_ref()) {
                     continue;
                 }
-                if gem == "grpc" && v.version.to_string() != "1.71.0" {
-                    continue;
-                }
-                if gem == "googleapis-common-protos" {
-                    if v.version.to_string() == "1.8.0" || v.version.to_string() == "1.7.0" {
-                    } else {
-                        continue;
-                    }
-                }
-                if gem == "grpc-google-iam-v1" {
-                    if v.version.to_string() == "1.9.0"
-                        || v.version.to_string() == "1.8.0"
-                        || v.version.to_string() == "1.7.0"
-                        || v.version.to_string() == "0.6.11"
-                        || v.version.to_string() == "1.0.0"
-                    // || v.version.to_string() == "1.1.0"
-                    || v.version.to_string() == "1.1.1"
-                    {
-                        continue;
-                    } else {
-                    }
-                }
-                let constraints: Vec<(String, RichReq, Vec<String>)> = v
+                let constraints = v
                     .dependencies
-                    .iter()
-                    .map(|dep| {
-                        (
-                            dep.name.clone(),
-                            dep.requirement.clone(),
-                            dep.requirement_str.clone(),
-                        )
-                    })
+                    .into_iter()
+                    .map(|dep| (dep.name, dep.requirement, dep.requirement_str))
                     .collect();
                 resolver.add_dependencies(gem.clone(), v.version, constraints);
             }
         }
-        let root_pkg = "root".to_string();
-        let root_ver = RubyVersion::new(0, 0, 0);
-        let root_constraints: Vec<(String, RichReq, Vec<String>)> = gemfile
-            .dependencies
+        resolver.add_dependencies(
+            "root".into(),
+            RubyVersion::new(0, 0, 0),
+            gemfile
+                .iter()
+                .map(|(name, req)| {
+                    let (vs, req_str) = parse_req(req, ",").unwrap();
+                    (name.to_string(), vs, req_str)
+                })
+                .collect(),
+        );
+
+        let solution = resolver.resolve()?;
+        let mut solution: Vec<_> = solution
             .into_iter()
-            .filter(|dep| {
-                dep.name != "gapic-common"
-                    && dep.name != "google-cloud-errors"
-                    && dep.name != "google-cloud-location"
-            })
-            .map(|gem| {
-                let (vs, req_str) = match gem.requirement {
-                    Some(req) => parse_req(&req, ","), // :contentReference[oaicite:1]{index=1}
-                    None => parse_req("*", ","),
-                };
-                (gem.name, vs, req_str)
-            })
+            .filter(|(name, _)| name != "root")
+            .map(|(name, version)| (name, version.to_string()))
             .collect();
-        resolver.add_dependencies(root_pkg, root_ver, root_constraints);
+        solution.sort();
+        Ok(solution)
+    }
 
-        let versions: Vec<_> = resolver
-            .dependency_provider
-            .versions(&"grpc-google-iam-v1".to_string())
-            .unwrap()
-            .collect();
+    #[tokio::test]
+    async fn test_resolve_deps() -> anyhow::Result<()> {
+        let server = mock_compact_index("grpc").await;
+        let dir = tempfile::tempdir()?;
+        let client = MultiSourceClient::new(vec![
+            CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?,
+        ]);
+        let gemfile = [
+            ("grpc", "= 1.63.0"),
+            ("google-protobuf", "~> 3.25, >= 3.25.3"),
+            ("grpc-google-iam-v1", ">= 0"),
+        ];
 
-        // assert_eq!(
-        //     versions,
-        //     vec![
-        //         &RubyVersion::parse("1.11.0"),
-        //         &RubyVersion::parse("1.10.0"),
-        //         &RubyVersion::parse("1.9.0"),
-        //         &RubyVersion::parse("1.1.1"),
-        //         &RubyVersion::parse("1.1.0"),
-        //         &RubyVersion::parse("1.0.0"),
-        //         &RubyVersion::parse("0.6.8"),
-        //     ]
-        // );
-
-        let solution = resolver.resolve().expect("dependency resolution failed");
         assert_eq!(
-            solution.get("grpc-google-iam-v1"),
-            Some(&RubyVersion::parse("1.11.0"))
+            resolve_gemfile(&client, &gemfile, None).await?,
+            [
+                ("google-protobuf", "3.25.5"),
+                ("googleapis-common-protos", "1.7.0"),
+                ("googleapis-common-protos-types", "1.20.0"),
+                ("grpc", "1.63.0"),
+                ("grpc-google-iam-v1", "1.11.0"),
+            ]
+            .map(|(name, version)| (name.to_string(), version.to_string()))
         );
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_resolution_is_deterministic() -> anyhow::Result<()> {
+        let server = mock_compact_index("grpc").await;
+        let gemfile = [("grpc", ">= 0"), ("grpc-google-iam-v1", ">= 0")];
+
+        let mut solutions = Vec::new();
+        for _ in 0..2 {
+            // キャッシュも使い回さず、毎回まっさらな状態から解決する
+            let dir = tempfile::tempdir()?;
+            let client = MultiSourceClient::new(vec![
+                CompactIndexClient::new(&server.uri(), dir.path(), HttpOptions::default()).await?,
+            ]);
+            solutions.push(resolve_gemfile(&client, &gemfile, None).await?);
+        }
+        assert_eq!(solutions[0], solutions[1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_in_memory_source() -> anyhow::Result<()> {
+        // rails 7.1 は rack 3 を要求するが、rack は 2 系に固定されている
+        let source = InMemorySource::new("https://gems.example.com/")
+            .add("rails", "7.0.8", "rack:~> 2.2")
+            .add("rails", "7.1.3", "rack:>= 3.0")
+            .add("rack", "2.2.8", "")
+            .add("rack", "3.0.9", "");
+        let client = MultiSourceClient::new(vec![source]);
+
+        assert_eq!(
+            resolve_gemfile(&client, &[("rails", ">= 7.0"), ("rack", "< 3")], None).await?,
+            [("rack", "2.2.8"), ("rails", "7.0.8")]
+                .map(|(name, version)| (name.to_string(), version.to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skips_versions_for_another_ruby() -> anyhow::Result<()> {
+        let source = InMemorySource::new("https://gems.example.com/")
+            .add_line("nokogiri", "1.15.6 |ruby:>= 2.7&< 3.3.dev")
+            .add_line("nokogiri", "1.16.0 |checksum:abc,ruby:>= 3.0")
+            .add_line("nokogiri", "1.17.0 |ruby:>= 3.1");
+        let client = MultiSourceClient::new(vec![source]);
+        let resolve = |ruby| resolve_gemfile(&client, &[("nokogiri", ">= 1.15")], ruby);
+
+        assert_eq!(resolve(Some("3.0.6")).await?[0].1, "1.16.0");
+        assert_eq!(resolve(Some("2.7.8")).await?[0].1, "1.15.6");
+        assert_eq!(resolve(None).await?[0].1, "1.17.0");
+        Ok(())
+    }
 }
@@ -0,0 +1,123 @@
+use std::{future::Future, sync::Arc};
+
+use tokio::sync::Semaphore;
+use url::Url;
+
+use crate::compact_index_client::{CompactIndexClient, GemVersion, Result};
+
+/// Somewhere gem metadata can be looked up, such as a compact index.
+///
+/// Resolution only needs the versions of a gem and their dependencies, so it
+/// does not care whether they come over HTTP or from memory.
+pub trait GemSource {
+    /// The remote written to the lockfile for gems from this source.
+    fn remote(&self) -> &Url;
+
+    /// Brings cached metadata up to date before any `versions` call.
+    fn refresh(&self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Every version of `gem` this source serves, empty when it serves none.
+    fn versions(&self, gem: &str) -> impl Future<Output = Result<Vec<GemVersion>>> + Send;
+
+    /// A fingerprint of what `versions(gem)` currently returns, if the source can
+    /// tell without fetching it. It changes whenever the versions do.
+    fn versions_checksum(&self, _gem: &str) -> impl Future<Output = Result<Option<String>>> + Send {
+        async { Ok(None) }
+    }
+
+    /// How this source spells `gem` when it serves it only in another casing,
+    /// e.g. `rails` for `Rails`.
+    fn canonical_name(&self, _gem: &str) -> impl Future<Output = Result<Option<String>>> + Send {
+        async { Ok(None) }
+    }
+
+    /// Bounds how many gems are fetched from this source at a time, if at all.
+    fn limiter(&self) -> Option<Arc<Semaphore>> {
+        None
+    }
+}
+
+impl GemSource for CompactIndexClient {
+    fn remote(&self) -> &Url {
+        self.base_url()
+    }
+
+    async fn refresh(&self) -> Result<()> {
+        self.ensure_versions_fresh().await
+    }
+
+    async fn versions(&self, gem: &str) -> Result<Vec<GemVersion>> {
+        self.info(gem).await
+    }
+
+    async fn versions_checksum(&self, gem: &str) -> Result<Option<String>> {
+        self.info_checksum(gem).await
+    }
+
+    async fn canonical_name(&self, gem: &str) -> Result<Option<String>> {
+        Ok(CompactIndexClient::versions(self, vec![gem.to_string()])
+            .await?
+            .into_keys()
+            .find(|name| name != gem))
+    }
+
+    fn limiter(&self) -> Option<Arc<Semaphore>> {
+        Some(CompactIndexClient::limiter(self).clone())
+    }
+}
+
+/// A source whose gems are listed up front, for tests that should not need an
+/// HTTP server.
+#[cfg(test)]
+pub struct InMemorySource {
+    remote: Url,
+    gems: std::collections::HashMap<String, Vec<GemVersion>>,
+}
+
+#[cfg(test)]
+impl InMemorySource {
+    pub fn new(remote: &str) -> Self {
+        Self {
+            remote: Url::parse(remote).unwrap(),
+            gems: Default::default(),
+        }
+    }
+
+    /// Adds a version of `name`. `dependencies` uses the info file format,
+    /// e.g. `rack:>= 2.0&< 3,thor:~> 1.2`.
+    pub fn add(mut self, name: &str, version: &str, dependencies: &str) -> Self {
+        self.gems
+            .entry(name.to_string())
+            .or_default()
+            .push(GemVersion {
+                name: name.to_string(),
+                version: crate::version::RubyVersion::parse(version),
+                checksum: None,
+                dependencies: crate::compact_index_client::parse_dependencies(dependencies),
+                required_ruby_version: None,
+            });
+        self
+    }
+
+    /// Adds a version of `name` from a line in the info file format, e.g.
+    /// `1.0.0 rack:>= 2.0|ruby:>= 2.7`.
+    pub fn add_line(mut self, name: &str, line: &str) -> Self {
+        if let Some(version) = crate::compact_index_client::parse_info_line(name, line) {
+            self.gems.entry(name.to_string()).or_default().push(version);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+impl GemSource for InMemorySource {
+    fn remote(&self) -> &Url {
+        &self.remote
+    }
+
+    async fn versions(&self, gem: &str) -> Result<Vec<GemVersion>> {
+        Ok(self.gems.get(gem).cloned().unwrap_or_default())
+    }
+}
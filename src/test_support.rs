@@ -0,0 +1,54 @@
+//! Helpers shared by tests that talk to a compact index.
+
+use std::{fs, path::PathBuf};
+
+use wiremock::{
+    Mock, MockServer, ResponseTemplate,
+    matchers::{method, path},
+};
+
+/// Serves `tests/fixtures/<name>` as a compact index: its `versions` file at
+/// `/versions` and every file in `info/` at `/info/<file>`.
+pub async fn mock_compact_index(name: &str) -> MockServer {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name);
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/versions"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_bytes(
+                fs::read(dir.join("versions")).expect("fixture has a versions file"),
+            ),
+        )
+        .mount(&server)
+        .await;
+    for entry in fs::read_dir(dir.join("info")).expect("fixture has an info directory") {
+        let file = entry.unwrap().path();
+        let gem = file.file_name().unwrap().to_string_lossy().to_string();
+        Mock::given(method("GET"))
+            .and(path(format!("/info/{}", gem)))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(fs::read(&file).unwrap()))
+            .mount(&server)
+            .await;
+    }
+    server
+}
+
+/// A server that answers `url_path` with 503 twice, then with `body`.
+pub async fn flaky_server(url_path: &str, body: &[u8]) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(url_path))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(url_path))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(body.to_vec()))
+        .mount(&server)
+        .await;
+    server
+}
@@ -1,19 +1,85 @@
+use std::ops::Bound;
+
+use lazy_static::lazy_static;
 use pubgrub::{Ranges, VersionSet};
+use regex::Regex;
 use semver::Version as SemVersion;
-use tracing::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, warn};
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct RichReq {
+    #[serde(with = "ranges_serde")]
     pub range: Ranges<RubyVersion>,
-    pub allow_pre: bool,
+    /// The prereleases allowed. RubyGems only takes them where the
+    /// requirement names one, so `range` alone decides only for releases.
+    #[serde(with = "ranges_serde")]
+    pub pre_range: Ranges<RubyVersion>,
+}
+
+// pubgrub の serde 実装は deserialize_any を使うので bincode で読めない。
+// 区間の列としてそのまま書き出す
+mod ranges_serde {
+    use std::ops::Bound;
+
+    use pubgrub::Ranges;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::RubyVersion;
+
+    pub fn serialize<S: Serializer>(
+        ranges: &Ranges<RubyVersion>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(ranges.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Ranges<RubyVersion>, D::Error> {
+        let segments = Vec::<(Bound<RubyVersion>, Bound<RubyVersion>)>::deserialize(deserializer)?;
+        Ok(segments.into_iter().collect())
+    }
+}
+
+impl RichReq {
+    /// Whether any version could ever satisfy this requirement.
+    /// Ruby versions are never negative, so e.g. `< 0` is unsatisfiable.
+    pub fn is_satisfiable(&self) -> bool {
+        !self.pre_range.is_empty()
+            || !self
+                .range
+                .intersection(&Ranges::higher_than(RubyVersion::new(0, 0, 0)))
+                .is_empty()
+    }
+
+    /// The disjoint intervals this requirement allows, lowest first, as
+    /// `(lower, upper)` bounds. `~> 6.1` is the single interval `[6.1, 7)`;
+    /// `!= 2.0` is `(.., 2.0)` and `(2.0, ..)`.
+    pub fn bounds(&self) -> Vec<(Bound<RubyVersion>, Bound<RubyVersion>)> {
+        self.range
+            .iter()
+            .map(|(lower, upper)| {
+                // ~> の上限 7.A は 7 のプレリリースも除くための印なので、7 として返す
+                let upper = match upper {
+                    Bound::Excluded(v) if *v == v.release().lowest_prerelease() => {
+                        Bound::Excluded(v.release())
+                    }
+                    upper => upper.clone(),
+                };
+                (lower.clone(), upper)
+            })
+            .collect()
+    }
 }
 
 impl std::fmt::Display for RichReq {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut text = String::new();
         text.push_str(&self.range.to_string());
-        if self.allow_pre {
-            text.push_str(" (allow pre-release)");
+        if !self.pre_range.is_empty() {
+            text.push_str(&format!(" (allow pre-release {})", self.pre_range));
         }
         write!(f, "{}", text)
     }
@@ -25,34 +91,41 @@ impl VersionSet for RichReq {
     fn empty() -> Self {
         RichReq {
             range: Ranges::empty(),
-            allow_pre: false,
+            pre_range: Ranges::empty(),
         }
     }
 
     fn singleton(v: Self::V) -> Self {
+        let pre_range = if v.is_prerelease() {
+            Ranges::singleton(v.clone())
+        } else {
+            Ranges::empty()
+        };
         Self {
-            range: Ranges::singleton(v.clone()),
-            allow_pre: v.is_prerelease(),
+            range: Ranges::singleton(v),
+            pre_range,
         }
     }
 
+    // リリースとプレリリースは別々の集合として扱う。
+    // フラグ一つだと、和集合で片方の範囲の許可がもう片方に漏れる
     fn complement(&self) -> Self {
         Self {
             range: self.range.complement(),
-            allow_pre: self.allow_pre,
+            pre_range: self.pre_range.complement(),
         }
     }
 
     fn intersection(&self, other: &Self) -> Self {
         Self {
             range: self.range.intersection(&other.range),
-            allow_pre: self.allow_pre && other.allow_pre,
+            pre_range: self.pre_range.intersection(&other.pre_range),
         }
     }
 
     fn contains(&self, v: &Self::V) -> bool {
-        if v.is_prerelease() && !self.allow_pre {
-            return false;
+        if v.is_prerelease() {
+            return self.pre_range.contains(v);
         }
         self.range.contains(v)
     }
@@ -60,31 +133,32 @@ impl VersionSet for RichReq {
     fn full() -> Self {
         RichReq {
             range: Ranges::full(),
-            allow_pre: true,
+            pre_range: Ranges::empty(),
         }
     }
 
     fn union(&self, other: &Self) -> Self {
         Self {
             range: self.range.union(&other.range),
-            allow_pre: self.allow_pre || other.allow_pre,
+            pre_range: self.pre_range.union(&other.pre_range),
         }
     }
 
     fn is_disjoint(&self, other: &Self) -> bool {
         Ranges::is_disjoint(&self.range, &other.range)
+            && Ranges::is_disjoint(&self.pre_range, &other.pre_range)
     }
 
     fn subset_of(&self, other: &Self) -> bool {
         Ranges::subset_of(&self.range, &other.range)
+            && Ranges::subset_of(&self.pre_range, &other.pre_range)
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Segment {
     Numeric(u64),
     Text(String),
-    Prerelease(String),
 }
 
 impl PartialOrd for Segment {
@@ -93,17 +167,114 @@ impl PartialOrd for Segment {
         Some(match (self, other) {
             (Numeric(a), Numeric(b)) => a.cmp(b),
             (Numeric(_), Text(_)) => std::cmp::Ordering::Greater,
-            (Numeric(_), Prerelease(_)) => std::cmp::Ordering::Greater,
             (Text(_), Numeric(_)) => std::cmp::Ordering::Less,
             (Text(a), Text(b)) => a.cmp(b),
-            (Text(a), Prerelease(b)) => a.cmp(b),
-            (Prerelease(_), Numeric(_)) => std::cmp::Ordering::Less,
-            (Prerelease(a), Text(b)) => a.cmp(b),
-            (Prerelease(a), Prerelease(b)) => a.cmp(b),
         })
     }
 }
 
+/// The platform after the `-` of a platform gem's version, e.g. `x86_64-linux`
+/// in `1.16.0-x86_64-linux`. It is not part of the version number: it makes a
+/// version neither a prerelease nor newer, and only breaks ties between equal
+/// versions (see the `Ord` impl of [`RubyVersion`]).
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct Platform(pub String);
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A segment of a version as `Gem::Version` compares it, borrowed from the
+/// version. Text sorts before numbers, like [`Segment`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum CanonicalSegment<'a> {
+    Text(&'a str),
+    Numeric(u64),
+}
+
+/// The segments of a version with every text segment split into runs of
+/// digits and non-digits.
+struct Runs<'a> {
+    segments: std::slice::Iter<'a, Segment>,
+    // 分割中の文字の区切りの残り
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Runs<'a> {
+    type Item = CanonicalSegment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(c) = self.rest.chars().next() {
+                let digit = c.is_ascii_digit();
+                let end = self
+                    .rest
+                    .find(|c: char| c.is_ascii_digit() != digit)
+                    .unwrap_or(self.rest.len());
+                let (run, tail) = self.rest.split_at(end);
+                self.rest = tail;
+                return Some(match run.parse() {
+                    Ok(n) if digit => CanonicalSegment::Numeric(n),
+                    _ => CanonicalSegment::Text(run),
+                });
+            }
+            match self.segments.next()? {
+                Segment::Numeric(n) => return Some(CanonicalSegment::Numeric(*n)),
+                Segment::Text(text) => self.rest = text,
+            }
+        }
+    }
+}
+
+/// [`Runs`] without the zeros that end the release part, the prerelease part
+/// or the version, so `1.0` yields the same segments as `1.0.0`.
+struct CanonicalSegments<'a> {
+    runs: Runs<'a>,
+    // 数字の前で残すことにした 0 の残りの数と、その後の数字
+    zeros: usize,
+    pending: Option<CanonicalSegment<'a>>,
+    // 文字列が出てきた後、つまりプレリリース部分にいるか
+    prerelease: bool,
+}
+
+impl<'a> Iterator for CanonicalSegments<'a> {
+    type Item = CanonicalSegment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.zeros > 0 {
+            self.zeros -= 1;
+            return Some(CanonicalSegment::Numeric(0));
+        }
+        if let Some(segment) = self.pending.take() {
+            return Some(segment);
+        }
+        let segment = self.runs.next()?;
+        if segment != CanonicalSegment::Numeric(0) {
+            self.prerelease |= matches!(segment, CanonicalSegment::Text(_));
+            return Some(segment);
+        }
+        // 0 の並びは、後に 0 以外の数字が続くときと、プレリリース部分で後に文字列が続くときだけ残す。
+        // リリース部分の末尾の 0 は、プレリリースが続いても落とす (1.0.a == 1.a)
+        let mut zeros = 1;
+        loop {
+            match self.runs.next() {
+                Some(CanonicalSegment::Numeric(0)) => zeros += 1,
+                Some(next) if matches!(next, CanonicalSegment::Numeric(_)) || self.prerelease => {
+                    self.zeros = zeros - 1;
+                    self.pending = Some(next);
+                    return Some(CanonicalSegment::Numeric(0));
+                }
+                next => {
+                    self.prerelease |= matches!(next, Some(CanonicalSegment::Text(_)));
+                    return next;
+                }
+            }
+        }
+    }
+}
+
 impl Ord for Segment {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.partial_cmp(other).unwrap()
@@ -112,17 +283,12 @@ impl Ord for Segment {
 
 impl PartialOrd for RubyVersion {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        use std::cmp::Ordering;
-        let max_len = usize::max(self.segments.len(), other.segments.len());
-        for i in 0..max_len {
-            let a = self.segments.get(i).unwrap_or(&Segment::Numeric(0));
-            let b = other.segments.get(i).unwrap_or(&Segment::Numeric(0));
-            let ord = a.cmp(b);
-            if ord != Ordering::Equal {
-                return Some(ord);
-            }
-        }
-        Some(std::cmp::Ordering::Equal)
+        // 同じバージョンのプラットフォーム違いも区別して、Eq と矛盾しない全順序にする
+        // (ruby が先、残りはプラットフォーム名の順)
+        Some(
+            self.cmp_ignoring_platform(other)
+                .then_with(|| self.platform_segment.cmp(&other.platform_segment)),
+        )
     }
 }
 
@@ -132,10 +298,29 @@ impl Ord for RubyVersion {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+// Gem::Version と同じく 1.0 と 1.0.0 は同じバージョン
+impl PartialEq for RubyVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.canonical_segments().eq(other.canonical_segments())
+            && self.platform_segment == other.platform_segment
+    }
+}
+
+impl Eq for RubyVersion {}
+
+impl std::hash::Hash for RubyVersion {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        for segment in self.canonical_segments() {
+            segment.hash(state);
+        }
+        self.platform_segment.hash(state);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RubyVersion {
     pub segments: Vec<Segment>,
-    platform_segment: Option<Segment>,
+    platform_segment: Option<Platform>,
 }
 
 impl std::fmt::Display for RubyVersion {
@@ -155,12 +340,11 @@ impl std::fmt::Display for RubyVersion {
                     }
                     text.push_str(s)
                 }
-                Segment::Prerelease(_) => (),
             }
         }
-        if let Some(Segment::Prerelease(platform)) = &self.platform_segment {
-            text.push_str("-");
-            text.push_str(&platform)
+        if let Some(platform) = &self.platform_segment {
+            text.push('-');
+            text.push_str(&platform.0)
         }
         write!(f, "{}", text)
     }
@@ -193,22 +377,76 @@ impl RubyVersion {
         self.segments.iter().any(|s| matches!(s, Segment::Text(_)))
     }
 
+    /// A release, i.e. not a prerelease. Platform gems like `1.0-java` are stable.
+    pub fn is_stable(&self) -> bool {
+        !self.is_prerelease()
+    }
+
     pub fn is_platform(&self) -> bool {
         self.platform_segment.is_some()
     }
 
-    pub fn bump(&self) -> Self {
-        let raw = self.to_string();
-        let mut segments: Vec<String> = raw.split('.').map(|s| s.to_string()).collect();
+    /// The platform of a platform gem, e.g. `x86_64-linux` for `1.16.0-x86_64-linux`.
+    pub fn platform(&self) -> Option<&str> {
+        self.platform_segment
+            .as_ref()
+            .map(|platform| platform.0.as_str())
+    }
+
+    /// Compares the version numbers only, so `1.0-java` and `1.0` are equal.
+    pub fn cmp_ignoring_platform(&self, other: &Self) -> std::cmp::Ordering {
+        use std::cmp::Ordering;
+        let (mut lhs, mut rhs) = (self.canonical_segments(), other.canonical_segments());
+        loop {
+            let ord = match (lhs.next(), rhs.next()) {
+                (None, None) => return Ordering::Equal,
+                (a, b) => a
+                    .unwrap_or(CanonicalSegment::Numeric(0))
+                    .cmp(&b.unwrap_or(CanonicalSegment::Numeric(0))),
+            };
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+    }
 
-        // Step 1-2: remove trailing non-numeric segments (prerelease identifiers)
-        while segments
-            .last()
-            .map(|s| !s.chars().all(|c| c.is_ascii_digit()))
-            .unwrap_or(false)
-        {
-            segments.pop();
+    /// The version without its prerelease part, like `Gem::Version#release`:
+    /// everything from the first non-numeric segment on is dropped (`1.2.3.rc1` => `1.2.3`).
+    pub fn release(&self) -> Self {
+        let segments = self
+            .segments
+            .iter()
+            .take_while(|s| matches!(s, Segment::Numeric(_)))
+            .cloned()
+            .collect();
+        RubyVersion {
+            segments,
+            platform_segment: self.platform_segment.clone(),
         }
+    }
+
+    /// Segments the way `Gem::Version` compares them: a text part like `pre10`
+    /// is split into `pre` and `10`, so `1.0.0.pre10` sorts like `1.0.0.pre.10`,
+    /// and trailing zeros of the release and prerelease parts are dropped, so
+    /// `1.0` equals `1.0.0`.
+    fn canonical_segments(&self) -> CanonicalSegments<'_> {
+        CanonicalSegments {
+            runs: Runs {
+                segments: self.segments.iter(),
+                rest: "",
+            },
+            zeros: 0,
+            pending: None,
+            prerelease: false,
+        }
+    }
+
+    /// The next version `~>` stops at, like `Gem::Version#bump`: `1.2.3` bumps
+    /// to `1.3`. `None` when the segment to increment is already the largest
+    /// one, as for a segment too large for a `u64`, since nothing is above it.
+    pub fn bump(&self) -> Option<Self> {
+        // Step 1-2: remove prerelease identifiers
+        let mut segments = self.release().segments;
 
         // Step 3: drop one more segment if we still have ≥2 (matching Ruby behaviour)
         if segments.len() > 1 {
@@ -216,23 +454,34 @@ impl RubyVersion {
         }
 
         // Step 4: increment last numeric segment, or default to 1
-        if let Some(last) = segments.pop() {
-            let next_num = last.parse::<u64>().unwrap_or(0) + 1;
-            segments.push(next_num.to_string());
-        } else {
-            segments.push("1".to_string());
+        match segments.last_mut() {
+            Some(Segment::Numeric(n)) => *n = n.checked_add(1)?,
+            _ => segments.push(Segment::Numeric(1)),
         }
 
-        // Step 5: join back & parse
-        let bumped = segments.join(".");
-        RubyVersion::parse(&bumped)
+        Some(RubyVersion {
+            segments,
+            platform_segment: None,
+        })
+    }
+
+    /// The lowest prerelease of this version, e.g. `8.A` for `8`: it sorts
+    /// below every prerelease of `8` RubyGems accepts, such as `8.0.0.rc1` or
+    /// `8.a`, since text segments only hold letters and `A` is the smallest.
+    fn lowest_prerelease(&self) -> Self {
+        let mut segments = self.release().segments;
+        segments.push(Segment::Text("A".to_string()));
+        RubyVersion {
+            segments,
+            platform_segment: None,
+        }
     }
 
     pub fn parse(text: &str) -> Self {
         let text = text.split('+').next().unwrap();
-        let mut main_and_pre = text.splitn(2, '-');
-        let main = main_and_pre.next().unwrap();
-        let pre = main_and_pre.next();
+        let mut main_and_platform = text.splitn(2, '-');
+        let main = main_and_platform.next().unwrap();
+        let platform = main_and_platform.next();
         let mut segments = Vec::new();
         for part in main.split('.') {
             let mut digits = String::new();
@@ -245,7 +494,12 @@ impl RubyVersion {
                 }
             }
             if !digits.is_empty() {
-                let n = digits.parse().unwrap_or(0);
+                // 先頭の 0 は数値として無視する (01 == 1)。u64 に収まらない値は
+                // 0 にすると順序が壊れるので、どの数値より大きい u64::MAX に丸める
+                let n = digits.parse().unwrap_or_else(|_| {
+                    warn!("Version segment {} of {} is too large", digits, text);
+                    u64::MAX
+                });
                 segments.push(Segment::Numeric(n));
             }
             if !letters.is_empty() {
@@ -255,7 +509,7 @@ impl RubyVersion {
 
         RubyVersion {
             segments,
-            platform_segment: pre.map(|pre| Segment::Prerelease(pre.to_string())),
+            platform_segment: platform.map(|platform| Platform(platform.to_string())),
         }
     }
 }
@@ -272,99 +526,142 @@ fn parse_semver(text: &str) -> anyhow::Result<SemVersion> {
         .map_err(|e| anyhow::anyhow!("Failed to parse semver string: {}. Error: {}", text, e))
 }
 
-pub fn parse_req(text: &str, separator: &str) -> (RichReq, Vec<String>) {
-    let mut set = RichReq::full();
+lazy_static! {
+    // Gem::Version::VERSION_PATTERN に、プラットフォーム名の "_" を加えたもの
+    static ref VERSION_PATTERN: Regex =
+        Regex::new(r"^[0-9]+(\.[0-9a-zA-Z]+)*(-[0-9A-Za-z_-]+(\.[0-9A-Za-z_-]+)*)?$").unwrap();
+}
+
+/// A requirement with an unknown operator, or a version RubyGems would not accept.
+#[derive(Debug, Error)]
+#[error("invalid version requirement {0:?}")]
+pub struct InvalidRequirement(pub String);
+
+pub fn parse_req(
+    text: &str,
+    separator: &str,
+) -> Result<(RichReq, Vec<String>), InvalidRequirement> {
+    let mut range = Ranges::full();
+    let mut names_pre = false;
     let mut req_str = vec![];
 
     if text.trim() == "*" {
-        return (set, req_str);
+        return Ok((RichReq::full(), req_str));
     }
     debug!("Parsing version requirement: {}", text);
     for part in text.split(separator) {
         let s = part.trim();
-        req_str.push(s.to_string());
         if s == "*" {
+            req_str.push(s.to_string());
             continue;
         }
 
-        let (op, ver_str) = if s.starts_with("~>") {
-            ("~>", s.trim_start_matches("~>").trim())
-        } else if s.starts_with('^') {
-            ("^", s.trim_start_matches('^').trim())
-        } else if s.starts_with(">=") {
-            (">=", s.trim_start_matches(">=").trim())
-        } else if s.starts_with("<=") {
-            ("<=", s.trim_start_matches("<=").trim())
-        } else if s.starts_with('>') {
-            (">", s.trim_start_matches('>').trim())
-        } else if s.starts_with('<') {
-            ("<", s.trim_start_matches('<').trim())
-        } else if s.starts_with("!=") {
-            ("!=", s.trim_start_matches("!=").trim())
-        } else {
-            ("=", s.trim_start_matches('=').trim())
-        };
+        // 演算子は一つだけ取り除く ("<<2" を "< 2" と読まないように)
+        let (op, ver_str) = ["~>", "^", ">=", "<=", ">", "<", "!=", "="]
+            .into_iter()
+            .find_map(|op| s.strip_prefix(op).map(|rest| (op, rest.trim())))
+            .unwrap_or(("=", s));
+        // "~~ 6.0" や "=> 1" のような未知の演算子は、ここでバージョンとして弾かれる
+        if !VERSION_PATTERN.is_match(ver_str) {
+            return Err(InvalidRequirement(part.trim().to_string()));
+        }
+        // Gem::Requirement と同じく "op version" の形に揃え、重複は除く
+        let normalized = format!("{} {}", op, ver_str);
+        if !req_str.contains(&normalized) {
+            req_str.push(normalized);
+        }
         let rv = RubyVersion::parse(ver_str);
 
-        let rng = match op {
-            "=" => Ranges::singleton(rv.clone()),
-            ">" => Ranges::strictly_higher_than(rv.clone()),
-            ">=" => Ranges::higher_than(rv.clone()),
-            "<" => Ranges::strictly_lower_than(rv.clone()),
-            "<=" => Ranges::lower_than(rv.clone()),
-            "!=" => {
-                let lower = Ranges::strictly_lower_than(rv.clone());
-                let upper = Ranges::strictly_higher_than(rv.clone());
-                lower.union(&upper)
-            }
-            "~>" => {
-                // pessimistic operator: >= rv, < next breaking version
-                let mut next = rv.clone();
-                if next.segments.len() > 2 {
-                    next = next.bump();
-                } else {
-                    if let Segment::Numeric(maj) = &mut next.segments[0] {
-                        *maj += 1;
+        // 完全一致は RichReq::singleton に任せ、プレリリースの扱いをそちらと揃える
+        let part = if op == "=" {
+            RichReq::singleton(rv)
+        } else {
+            let rng = match op {
+                ">" => Ranges::strictly_higher_than(rv.clone()),
+                ">=" => Ranges::higher_than(rv.clone()),
+                "<" => Ranges::strictly_lower_than(rv.clone()),
+                "<=" => Ranges::lower_than(rv.clone()),
+                "!=" => {
+                    let lower = Ranges::strictly_lower_than(rv.clone());
+                    let upper = Ranges::strictly_higher_than(rv.clone());
+                    lower.union(&upper)
+                }
+                "~>" => {
+                    // pessimistic operator: >= rv, < next breaking version.
+                    // RubyGems compares v.release < bump, so prereleases of bump are out too
+                    match rv.bump() {
+                        Some(bump) => Ranges::between(rv.clone(), bump.lowest_prerelease()),
+                        None => Ranges::higher_than(rv.clone()),
                     }
-                    // keep only the major segment
-                    next.segments.truncate(1);
                 }
-                Ranges::between(rv.clone(), next)
-            }
-            "^" => {
-                // caret semver: ^x.y.z => < next breaking change
-                let mut next = rv.clone();
-                match next.segments.get_mut(0) {
-                    Some(Segment::Numeric(maj)) if *maj > 0 => *maj += 1,
-                    _ => {
+                "^" => {
+                    // caret semver: ^x.y.z => < next breaking change
+                    let mut next = rv.clone();
+                    // 足せないほど大きい値なら、それより上のバージョンはないので上限も付けない
+                    let increment = |n: &mut u64| n.checked_add(1).map(|m| *n = m).is_some();
+                    let bounded = match next.segments.get_mut(0) {
+                        Some(Segment::Numeric(maj)) if *maj > 0 => increment(maj),
                         // major=0: bump minor
-                        if next.segments.len() > 1 {
-                            if let Segment::Numeric(min) = &mut next.segments[1] {
-                                *min += 1;
-                            }
-                        }
+                        _ => match next.segments.get_mut(1) {
+                            Some(Segment::Numeric(min)) => increment(min),
+                            _ => true,
+                        },
+                    };
+                    if bounded {
+                        Ranges::intersection(
+                            &Ranges::higher_than(rv.clone()),
+                            &Ranges::strictly_lower_than(next),
+                        )
+                    } else {
+                        Ranges::higher_than(rv.clone())
                     }
                 }
-                Ranges::intersection(
-                    &Ranges::higher_than(rv.clone()),
-                    &Ranges::strictly_lower_than(next),
-                )
+                _ => Ranges::full(),
+            };
+            let pre_range = if rv.is_prerelease() {
+                rng.clone()
+            } else {
+                Ranges::empty()
+            };
+            RichReq {
+                range: rng,
+                pre_range,
             }
-            _ => Ranges::full(),
         };
-        debug!("Parsed range: {:?}", rng);
-        set = set.intersection(&RichReq {
-            range: rng,
-            allow_pre: op == "=" && rv.is_prerelease(),
-        });
+        debug!("Parsed range: {:?}", part.range);
+        range = range.intersection(&part.range);
+        // RubyGems allows prereleases once any part of the requirement names one
+        names_pre |= !part.pre_range.is_empty();
     }
-    (set, req_str)
+    let pre_range = if names_pre {
+        range.clone()
+    } else {
+        Ranges::empty()
+    };
+    Ok((RichReq { range, pre_range }, req_str))
+}
+
+/// A requirement met by any one of `alternatives`, each of which is a
+/// comma-separated requirement as given to [`parse_req`]. For example
+/// `parse_req_any(&[">= 1.0", "<= 0.5"])` excludes only the versions in between.
+///
+/// Prereleases are allowed only within the alternatives that name one. No alternatives
+/// gives a requirement nothing satisfies.
+pub fn parse_req_any(alternatives: &[&str]) -> Result<RichReq, InvalidRequirement> {
+    alternatives
+        .iter()
+        .try_fold(RichReq::empty(), |any, alternative| {
+            let (req, _) = parse_req(alternative, ",")?;
+            Ok(any.union(&req))
+        })
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::version::{RubyVersion, Segment, parse_req};
-    use pubgrub::Ranges;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    use crate::version::{Platform, RichReq, RubyVersion, Segment, parse_req, parse_req_any};
+    use pubgrub::{Ranges, VersionSet};
 
     #[test]
     fn test_ruby_parse() {
@@ -390,7 +687,7 @@ mod tests {
         assert_eq!(rv.segments[2], Segment::Numeric(7));
         assert_eq!(
             rv.platform_segment,
-            Some(Segment::Prerelease("aarch64-linux-gnu".to_string()))
+            Some(Platform("aarch64-linux-gnu".to_string()))
         );
         assert_eq!(rv.to_string(), "1.18.7-aarch64-linux-gnu");
 
@@ -402,7 +699,7 @@ mod tests {
         assert_eq!(rv.segments[3], Segment::Text("rc1".to_string()));
         assert_eq!(
             rv.platform_segment,
-            Some(Segment::Prerelease("x86-linux-gnu".to_string()))
+            Some(Platform("x86-linux-gnu".to_string()))
         );
         assert_eq!(rv.to_string(), "2.15.0.rc1-x86-linux-gnu")
     }
@@ -413,7 +710,7 @@ mod tests {
 
     #[test]
     fn gt_operator() {
-        let r: Ranges<RubyVersion> = parse_req(">3.0", ",").0.range;
+        let r: Ranges<RubyVersion> = parse_req(">3.0", ",").unwrap().0.range;
         assert!(!r.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(3),
@@ -442,7 +739,7 @@ mod tests {
 
     #[test]
     fn ge_operator() {
-        let r: Ranges<RubyVersion> = parse_req(">=1.2.3", ",").0.range;
+        let r: Ranges<RubyVersion> = parse_req(">=1.2.3", ",").unwrap().0.range;
         assert!(r.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(1),
@@ -471,7 +768,7 @@ mod tests {
 
     #[test]
     fn lt_le_operators() {
-        let lt: Ranges<RubyVersion> = parse_req("<2.0", ",").0.range;
+        let lt: Ranges<RubyVersion> = parse_req("<2.0", ",").unwrap().0.range;
         assert!(!lt.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(2),
@@ -489,7 +786,7 @@ mod tests {
             platform_segment: None
         }));
 
-        let le: Ranges<RubyVersion> = parse_req("<=2.0", ",").0.range;
+        let le: Ranges<RubyVersion> = parse_req("<=2.0", ",").unwrap().0.range;
         assert!(le.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(2),
@@ -510,7 +807,7 @@ mod tests {
 
     #[test]
     fn eq_operator() {
-        let r: Ranges<RubyVersion> = parse_req("=1.4.5", ",").0.range;
+        let r: Ranges<RubyVersion> = parse_req("=1.4.5", ",").unwrap().0.range;
         assert!(r.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(1),
@@ -531,7 +828,7 @@ mod tests {
 
     #[test]
     fn wildcard() {
-        let r: Ranges<RubyVersion> = parse_req("*", ",").0.range;
+        let r: Ranges<RubyVersion> = parse_req("*", ",").unwrap().0.range;
         assert!(r.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(0),
@@ -552,7 +849,7 @@ mod tests {
 
     #[test]
     fn pessimistic_operator() {
-        let r: Ranges<RubyVersion> = parse_req("~>1.5", ",").0.range;
+        let r: Ranges<RubyVersion> = parse_req("~>1.5", ",").unwrap().0.range;
         assert!(r.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(1),
@@ -581,7 +878,7 @@ mod tests {
 
     #[test]
     fn aaa() {
-        let r: Ranges<RubyVersion> = parse_req("~> 1.1", ",").0.range;
+        let r: Ranges<RubyVersion> = parse_req("~> 1.1", ",").unwrap().0.range;
         let a = RubyVersion::parse("1.10.0");
         let b = RubyVersion::parse("1.11.0");
         assert!(r.contains(&a));
@@ -591,37 +888,37 @@ mod tests {
 
     #[test]
     fn bbb() {
-        let r: Ranges<RubyVersion> = parse_req("~> 1.6", ",").0.range;
+        let r: Ranges<RubyVersion> = parse_req("~> 1.6", ",").unwrap().0.range;
         let a = RubyVersion::parse("1.8.0");
         assert!(r.contains(&a));
     }
 
-    // #[test]
-    // fn pessimistic_operator_invalid_semver() {
-    //     let r: Ranges<RubyVersion> = parse_req("~>0.0.6.beta.2", ",");
-    //     assert!(r.contains(&RubyVersion {
-    //         segments: vec![
-    //             Segment::Numeric(0),
-    //             Segment::Numeric(0),
-    //             Segment::Numeric(6),
-    //             Segment::Text("beta".to_string()),
-    //             Segment::Numeric(2)
-    //         ],
-    //         platform_segment: None
-    //     }));
-    //     assert!(!r.contains(&RubyVersion {
-    //         segments: vec![
-    //             Segment::Numeric(0),
-    //             Segment::Numeric(0),
-    //             Segment::Numeric(7)
-    //         ],
-    //         platform_segment: None
-    //     }));
-    // }
+    #[test]
+    fn pessimistic_operator_invalid_semver() {
+        // RubyGems drops the prerelease part before bumping, so the upper bound is 0.1
+        let r = parse_req("~>0.0.6.beta.2", ",").unwrap().0;
+        assert!(r.contains(&RubyVersion {
+            segments: vec![
+                Segment::Numeric(0),
+                Segment::Numeric(0),
+                Segment::Numeric(6),
+                Segment::Text("beta".to_string()),
+                Segment::Numeric(2)
+            ],
+            platform_segment: None
+        }));
+        assert!(!r.contains(&RubyVersion::parse("0.0.6.beta.1")));
+        assert!(r.contains(&RubyVersion::parse("0.0.6")));
+        assert!(r.contains(&RubyVersion::parse("0.0.7")));
+        assert!(!r.contains(&RubyVersion {
+            segments: vec![Segment::Numeric(0), Segment::Numeric(1)],
+            platform_segment: None
+        }));
+    }
 
     #[test]
     fn not_equal_operator() {
-        let r: Ranges<RubyVersion> = parse_req("!=2.1.3", ",").0.range;
+        let r: Ranges<RubyVersion> = parse_req("!=2.1.3", ",").unwrap().0.range;
         assert!(!r.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(2),
@@ -650,7 +947,7 @@ mod tests {
 
     #[test]
     fn multiple_version_req() {
-        let r: Ranges<RubyVersion> = parse_req(">2.0&<=3.0", "&").0.range;
+        let r: Ranges<RubyVersion> = parse_req(">2.0&<=3.0", "&").unwrap().0.range;
         assert!(r.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(2),
@@ -687,7 +984,7 @@ mod tests {
 
     #[test]
     fn multiple_version_req_with_comma() {
-        let r: Ranges<RubyVersion> = parse_req(">=2.0,<3.0", ",").0.range;
+        let r: Ranges<RubyVersion> = parse_req(">=2.0,<3.0", ",").unwrap().0.range;
         assert!(r.contains(&RubyVersion {
             segments: vec![
                 Segment::Numeric(2),
@@ -730,19 +1027,401 @@ mod tests {
         }));
     }
 
+    #[test]
+    fn test_req_str_normalized() {
+        assert_eq!(
+            parse_req(">=1.0,  <2.0", ",").unwrap().1,
+            vec![">= 1.0", "< 2.0"]
+        );
+        assert_eq!(parse_req("7.0.8.7", ",").unwrap().1, vec!["= 7.0.8.7"]);
+        assert_eq!(parse_req("= 1.2&=1.2", "&").unwrap().1, vec!["= 1.2"]);
+        assert_eq!(parse_req("~>  2.0", ",").unwrap().1, vec!["~> 2.0"]);
+    }
+
+    #[test]
+    fn test_prerelease_only_when_named() {
+        let rc = RubyVersion::parse("1.1.0.rc1");
+        assert!(!RichReq::full().contains(&rc));
+        assert!(!parse_req("*", ",").unwrap().0.contains(&rc));
+        assert!(!parse_req(">= 0", ",").unwrap().0.contains(&rc));
+        assert!(
+            parse_req(">= 1.0, < 1.1.0.rc2", ",")
+                .unwrap()
+                .0
+                .contains(&rc)
+        );
+        assert!(parse_req("= 1.1.0.rc1", ",").unwrap().0.contains(&rc));
+        assert_eq!(
+            parse_req("= 1.1.0.rc1", ",").unwrap().0,
+            RichReq::singleton(rc.clone())
+        );
+        assert_eq!(
+            parse_req("1.0", ",").unwrap().0,
+            RichReq::singleton(RubyVersion::parse("1.0"))
+        );
+    }
+
+    #[test]
+    fn test_prerelease_per_range() {
+        let v = RubyVersion::parse;
+        let req = |text| parse_req(text, ",").unwrap().0;
+
+        // = 2.0.0.rc1 が許すプレリリースは 2.0.0.rc1 だけで、>= 1.0 の側には広がらない
+        let union = req(">= 1.0").union(&req("= 2.0.0.rc1"));
+        assert!(union.contains(&v("2.0.0.rc1")));
+        assert!(union.contains(&v("1.5.0")));
+        assert!(!union.contains(&v("1.5.0.rc1")));
+        assert!(!union.contains(&v("2.0.0.rc2")));
+
+        let union = req("< 1.0").union(&req(">= 2.0.a"));
+        assert!(union.contains(&v("2.1.rc1")));
+        assert!(!union.contains(&v("0.9.rc1")));
+
+        // 補集合と共通部分も、プレリリースの許可を範囲ごとに保つ
+        let pre = req(">= 1.0.rc1, < 2");
+        let complement = pre.complement();
+        for version in [
+            "0.9", "0.9.rc1", "1.0.rc1", "1.5", "1.5.rc1", "2.0.rc1", "2.1",
+        ] {
+            assert_ne!(
+                pre.contains(&v(version)),
+                complement.contains(&v(version)),
+                "{}",
+                version
+            );
+        }
+        let intersection = pre.intersection(&req(">= 1.2"));
+        assert!(intersection.contains(&v("1.2")));
+        assert!(!intersection.contains(&v("1.3.rc1")));
+        assert!(pre.intersection(&req("< 1.2.rc1")).contains(&v("1.1.rc1")));
+        assert!(RichReq::singleton(v("1.1.rc1")).subset_of(&pre));
+        assert!(!RichReq::singleton(v("0.9.rc1")).subset_of(&union));
+    }
+
+    #[test]
+    fn test_prerelease_ordering() {
+        let ordered = [
+            "1.0.0.pre.1",
+            "1.0.0.pre.2",
+            "1.0.0.pre.10",
+            "1.0.0.rc1",
+            "1.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(
+                RubyVersion::parse(pair[0]) < RubyVersion::parse(pair[1]),
+                "{} < {}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert!(RubyVersion::parse("1.0.0.pre10") > RubyVersion::parse("1.0.0.pre2"));
+        assert_eq!(
+            RubyVersion::parse("1.0.0.pre2").cmp(&RubyVersion::parse("1.0.0.pre.2")),
+            std::cmp::Ordering::Equal
+        );
+        assert_eq!(RubyVersion::parse("1.0.0.pre2").to_string(), "1.0.0.pre2");
+    }
+
+    #[test]
+    fn test_trailing_zeros_are_equal() {
+        use std::collections::HashMap;
+
+        assert_eq!(RubyVersion::parse("1.0"), RubyVersion::parse("1.0.0"));
+        assert_eq!(RubyVersion::parse("1"), RubyVersion::parse("1.0.0.0"));
+        assert_eq!(RubyVersion::parse("1.0.a"), RubyVersion::parse("1.a.0"));
+        // プレリリース部分の途中の 0 は残す
+        assert_ne!(RubyVersion::parse("1.a.0.b"), RubyVersion::parse("1.a.b"));
+        assert_eq!(
+            RubyVersion::parse("1.0.a.0.b.0"),
+            RubyVersion::parse("1.a.0.b")
+        );
+        assert!(RubyVersion::parse("1.a.b") < RubyVersion::parse("1.a.0.b"));
+        assert_eq!(RubyVersion::parse("1.0.0.rc1"), RubyVersion::parse("1.rc1"));
+        assert_ne!(RubyVersion::parse("1.0"), RubyVersion::parse("1.0.1"));
+        assert_ne!(
+            RubyVersion::parse("1.0-java"),
+            RubyVersion::parse("1.0.0-x86_64-linux")
+        );
+
+        let mut versions = HashMap::new();
+        versions.insert(RubyVersion::parse("1.0"), "short");
+        versions.insert(RubyVersion::parse("1.0.0"), "long");
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[&RubyVersion::parse("1.0")], "long");
+
+        // 表示は元の書き方のまま
+        assert_eq!(RubyVersion::parse("1.0").to_string(), "1.0");
+    }
+
+    #[test]
+    fn test_platform_tie_break() {
+        use std::collections::BTreeSet;
+
+        let versions: BTreeSet<_> = ["1.0-x86_64-linux", "1.0.0", "1.0-java", "0.9-java"]
+            .into_iter()
+            .map(RubyVersion::parse)
+            .collect();
+        let versions: Vec<_> = versions.iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            versions,
+            vec!["0.9-java", "1.0.0", "1.0-java", "1.0-x86_64-linux"]
+        );
+        assert_eq!(
+            RubyVersion::parse("1.0").cmp(&RubyVersion::parse("1.0.0")),
+            std::cmp::Ordering::Equal
+        );
+        assert_eq!(
+            RubyVersion::parse("1.0-java").cmp_ignoring_platform(&RubyVersion::parse("1.0.0")),
+            std::cmp::Ordering::Equal
+        );
+        assert_eq!(RubyVersion::parse("1.0-java").platform(), Some("java"));
+        assert_eq!(RubyVersion::parse("1.0").platform(), None);
+    }
+
+    #[test]
+    fn test_platform_is_not_prerelease() {
+        let v = RubyVersion::parse("1.16.0-x86_64-linux");
+        assert!(v.is_platform());
+        assert!(!v.is_prerelease());
+        assert!(v.is_stable());
+        assert!(!RubyVersion::parse("1.16.0.rc1-java").is_stable());
+        assert_eq!(v.segments.len(), 3);
+        assert_eq!(v.to_string(), "1.16.0-x86_64-linux");
+        // プラットフォーム名が数値より大きく扱われることはない
+        assert!(v < RubyVersion::parse("1.16.1"));
+        assert!(RubyVersion::parse("1.16.0.rc1-java") < RubyVersion::parse("1.16.0"));
+        assert!(RubyVersion::parse("1.16.0.rc1-java").is_prerelease());
+        assert!(
+            !parse_req(">= 1.16", ",")
+                .unwrap()
+                .0
+                .contains(&RubyVersion::parse("1.16.0.rc1-java"))
+        );
+        assert!(parse_req(">= 1.16", ",").unwrap().0.contains(&v));
+        assert_eq!(
+            RubyVersion::parse("1.0-universal-darwin")
+                .release()
+                .to_string(),
+            "1.0-universal-darwin"
+        );
+        assert_eq!(Platform("java".to_string()).to_string(), "java");
+    }
+
+    #[test]
+    fn test_leading_zeros_and_overflow() {
+        assert_eq!(RubyVersion::parse("1.00.3"), RubyVersion::parse("1.0.3"));
+        assert!(RubyVersion::parse("1.00.3") < RubyVersion::parse("1.1"));
+
+        let huge = RubyVersion::parse("1.123456789012345678901234567890");
+        assert!(!huge.is_prerelease());
+        assert!(huge > RubyVersion::parse("1.18446744073709551614"));
+        assert!(huge < RubyVersion::parse("2"));
+    }
+
     #[test]
     fn test_bump() {
         let rv = RubyVersion::parse("1.2.3");
-        let bumped = rv.bump();
+        let bumped = rv.bump().unwrap();
         assert_eq!(bumped.to_string(), "1.3");
 
         let rv = RubyVersion::parse("0.9.11");
-        let bumped = rv.bump();
+        let bumped = rv.bump().unwrap();
         assert_eq!(bumped.to_string(), "0.10");
 
         let rv = RubyVersion::parse("3.0.0.rc12");
-        let bumped = rv.bump();
+        let bumped = rv.bump().unwrap();
         assert_eq!(bumped.to_string(), "3.1");
+
+        // u64 に収まらない値は u64::MAX に丸めているので、その次はない
+        assert!(RubyVersion::parse("99999999999999999999").bump().is_none());
+        assert!(
+            RubyVersion::parse("1.99999999999999999999.0")
+                .bump()
+                .is_none()
+        );
+        assert_eq!(
+            RubyVersion::parse("1.99999999999999999999").bump(),
+            Some(RubyVersion::parse("2"))
+        );
+    }
+
+    #[test]
+    fn test_oversize_segment_requirements() {
+        let v = RubyVersion::parse;
+        let (req, _) = parse_req("~> 99999999999999999999", ",").unwrap();
+        assert!(req.contains(&v("99999999999999999999")));
+        assert!(req.contains(&v("99999999999999999999.1")));
+        assert!(!req.contains(&v("9999999999")));
+
+        let (req, _) = parse_req("~> 1.99999999999999999999", ",").unwrap();
+        assert!(req.contains(&v("1.99999999999999999999")));
+        assert!(!req.contains(&v("1.5")));
+
+        let (req, _) = parse_req("^99999999999999999999", ",").unwrap();
+        assert!(req.contains(&v("99999999999999999999.3")));
+        let (req, _) = parse_req("^0.99999999999999999999", ",").unwrap();
+        assert!(req.contains(&v("0.99999999999999999999")));
+        assert!(!req.contains(&v("0.1")));
+    }
+
+    #[test]
+    fn test_bump_platform() {
+        let bump = |v: &str| RubyVersion::parse(v).bump().unwrap().to_string();
+        assert_eq!(bump("1.2.3-x86_64-linux"), "1.3");
+        assert_eq!(bump("1.16.0-arm64-darwin"), "1.17");
+        assert_eq!(bump("3.0.0.rc12-java"), "3.1");
+        assert_eq!(bump("2-x64-mingw-ucrt"), "3");
+
+        let (req, _) = parse_req("~> 1.2.3-x86_64-linux", ",").unwrap();
+        assert!(req.contains(&RubyVersion::parse("1.2.9")));
+        assert!(!req.contains(&RubyVersion::parse("1.3.0")));
+    }
+
+    #[test]
+    fn test_pessimistic_single_segment() {
+        // ~> N は >= N, < N+1。N = 0 でも同じ
+        for (req, bound, inside, outside) in [
+            ("~> 0", "1", ["0", "0.9.9"], ["1", "1.0.0.rc1"]),
+            ("~> 1", "2", ["1", "1.99"], ["0.9", "2.a"]),
+            ("~> 2", "3", ["2.0.0", "2.10"], ["1.9", "3"]),
+        ] {
+            let (rich, _) = parse_req(req, ",").unwrap();
+            let rich = RichReq {
+                pre_range: rich.range.clone(),
+                ..rich
+            };
+            let lower = bound.parse::<u64>().unwrap() - 1;
+            assert_eq!(
+                rich.range,
+                Ranges::between(
+                    RubyVersion::new(lower, 0, 0),
+                    RubyVersion::parse(&format!("{}.A", bound))
+                ),
+                "{}",
+                req
+            );
+            for v in inside {
+                assert!(
+                    rich.contains(&RubyVersion::parse(v)),
+                    "{} should allow {}",
+                    req,
+                    v
+                );
+            }
+            for v in outside {
+                assert!(
+                    !rich.contains(&RubyVersion::parse(v)),
+                    "{} should reject {}",
+                    req,
+                    v
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_satisfiable() {
+        assert!(parse_req(">= 0", ",").unwrap().0.is_satisfiable());
+        assert!(parse_req("~> 1.0", ",").unwrap().0.is_satisfiable());
+        assert!(!parse_req("< 0", ",").unwrap().0.is_satisfiable());
+        assert!(!parse_req(">= 2.0, < 1.0", ",").unwrap().0.is_satisfiable());
+    }
+
+    #[test]
+    fn test_bounds() {
+        let bounds = |text: &str| parse_req(text, ",").unwrap().0.bounds();
+        let v = RubyVersion::parse;
+
+        assert_eq!(
+            bounds("~> 6.1"),
+            vec![(Included(v("6.1")), Excluded(v("7")))]
+        );
+        assert_eq!(
+            bounds("> 1.0, <= 2.0"),
+            vec![(Excluded(v("1.0")), Included(v("2.0")))]
+        );
+        assert_eq!(
+            bounds("!= 2.0"),
+            vec![
+                (Unbounded, Excluded(v("2.0"))),
+                (Excluded(v("2.0")), Unbounded)
+            ]
+        );
+        assert_eq!(
+            bounds("= 1.2"),
+            vec![(Included(v("1.2")), Included(v("1.2")))]
+        );
+        assert_eq!(bounds(">= 1"), vec![(Included(v("1")), Unbounded)]);
+        assert_eq!(bounds("*"), vec![(Unbounded, Unbounded)]);
+        assert!(bounds("< 1, > 2").is_empty());
+    }
+
+    #[test]
+    fn test_parse_req_any() {
+        let v = RubyVersion::parse;
+        let req = parse_req_any(&[">= 1.0", "<= 0.5"]).unwrap();
+        assert!(req.contains(&v("1.2")));
+        assert!(req.contains(&v("0.5")));
+        assert!(req.contains(&v("0.3.1")));
+        assert!(!req.contains(&v("0.7")));
+        assert!(!req.contains(&v("1.1.rc1")));
+
+        // 各選択肢の中は AND
+        let req = parse_req_any(&["~> 2.0, != 2.3.0", "= 1.5"]).unwrap();
+        assert!(req.contains(&v("2.4")));
+        assert!(!req.contains(&v("2.3.0")));
+        assert!(req.contains(&v("1.5")));
+        assert!(!req.contains(&v("1.6")));
+
+        // プレリリースを許すのは、それを書いた選択肢の範囲だけ
+        let req = parse_req_any(&[">= 2.0.beta", "< 1.0"]).unwrap();
+        assert!(req.contains(&v("2.0.beta2")));
+        assert!(req.contains(&v("0.9")));
+        assert!(!req.contains(&v("0.9.rc1")));
+
+        assert!(!parse_req_any(&[]).unwrap().is_satisfiable());
+        assert!(parse_req_any(&[">= 1.0", "~~ 2"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_req_rejects_malformed() {
+        for text in [
+            "~~ 6.0",
+            "=> 1.0",
+            ">= ",
+            "",
+            "1..2",
+            ">= abc",
+            "~> 1.0 beta",
+            ">= 1.0, <<2",
+        ] {
+            let err = parse_req(text, ",").unwrap_err();
+            assert!(
+                err.to_string().starts_with("invalid version requirement"),
+                "{text}"
+            );
+        }
+        // 正しい要素があっても、一つでも壊れていればエラー
+        assert_eq!(parse_req(">= 1.0, ~= 2.0", ",").unwrap_err().0, "~= 2.0");
+        assert!(parse_req("= 1.0.0.pre.1", ",").is_ok());
+        assert!(parse_req(">= 1.2-x86_64-linux", ",").is_ok());
+    }
+
+    #[test]
+    fn test_release() {
+        assert_eq!(
+            RubyVersion::parse("1.2.3.rc1").release().to_string(),
+            "1.2.3"
+        );
+        assert_eq!(RubyVersion::parse("1.2.0.a").release().to_string(), "1.2.0");
+        assert_eq!(
+            RubyVersion::parse("1.0.0.pre.2").release().to_string(),
+            "1.0.0"
+        );
+        assert_eq!(RubyVersion::parse("1.a.2").release().to_string(), "1");
+        assert_eq!(RubyVersion::parse("1.2.3").release().to_string(), "1.2.3");
+        assert!(!RubyVersion::parse("2.0.0.beta1").release().is_prerelease());
     }
 
     #[test]